@@ -0,0 +1,354 @@
+//! The agent, which holds onto keys derived from passwords so that they aren't derived again
+//!
+//! Deriving the key from a password is deliberately slow. The agent keeps derived keys (refer to
+//! `FileContent::derived_key`) in locked memory for a limited time, handing them out to other
+//! invocations of passman over a Unix socket. Only processes belonging to the same user may use
+//! it -- this is checked on both ends of every connection.
+//!
+//! The protocol is line-based, with each request answered by a single line:
+//!
+//!  * `GET <id>...` is answered with `KEY <id> <key>` for the first key the agent has, or `NONE`
+//!  * `PUT <id> <key>` stores the key, and is answered with `OK`
+//!  * `LOCK` forgets all keys, and is answered with `OK`
+//!
+//! Keys are base64-encoded. Invalid requests are answered with `ERR <message>`.
+
+use passman::secret::{self, LockedKey, SecretString};
+use passman::version::FileContent;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// Returns the path of the agent's socket
+///
+/// This is `$PASSMAN_AGENT_SOCK` if it's set. Otherwise, the socket is placed in a directory only
+/// accessible by the current user: under `$XDG_RUNTIME_DIR` if that's set, or in /tmp.
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os("PASSMAN_AGENT_SOCK") {
+        return path.into();
+    }
+
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("passman"),
+        None => PathBuf::from(format!("/tmp/passman-{}", current_uid())),
+    };
+    dir.join("agent.sock")
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions, and can't fail
+    unsafe { libc::getuid() }
+}
+
+/// Returns the uid of the process on the other end of the socket
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `cred` and `len` are valid for writes, and `len` gives the size of `cred`
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match res {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Returns the uid of the process on the other end of the socket
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+
+    // SAFETY: `uid` and `gid` are valid for writes
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// A connection to the agent
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Connection {
+    /// Connects to the agent, returning `None` if it isn't running or doesn't belong to us
+    fn open() -> Option<Self> {
+        let stream = UnixStream::connect(socket_path()).ok()?;
+        // Someone else's process could be listening on the socket. We send our keys to the
+        // agent, so it's important that we check.
+        if peer_uid(&stream).ok()? != current_uid() {
+            return None;
+        }
+
+        stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+        Some(Connection {
+            reader: BufReader::new(stream.try_clone().ok()?),
+            writer: stream,
+        })
+    }
+
+    /// Sends a single request, returning the response
+    ///
+    /// Responses can carry keys, so they're read straight into a buffer that's wiped on drop.
+    fn request(&mut self, req: &str) -> io::Result<SecretString> {
+        writeln!(self.writer, "{}", req)?;
+
+        secret::read_line(&mut self.reader)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the agent closed the connection",
+            )
+        })
+    }
+}
+
+/// Formats a request or response that carries a key, as "<kind> <id> <key>"
+///
+/// The buffer is allocated with room for the encoded key up front, so it's never reallocated and
+/// the only copy of the key's text is wiped once it's dropped.
+fn key_line(kind: &str, id: &str, key: &[u8]) -> SecretString {
+    let encoded_len = key.len().div_ceil(3) * 4;
+    let mut line = Zeroizing::new(String::with_capacity(
+        kind.len() + id.len() + 2 + encoded_len,
+    ));
+    line.push_str(kind);
+    line.push(' ');
+    line.push_str(id);
+    line.push(' ');
+    base64::encode_config_buf(key, base64::STANDARD, &mut line);
+    line
+}
+
+/// Tries to unlock the file with a key from the agent, returning whether it was unlocked
+///
+/// Any failure to reach the agent is ignored, so that the password can be asked for as usual.
+pub fn unlock(content: &mut dyn FileContent) -> bool {
+    let request = {
+        let ids = content.derived_key_ids();
+        if ids.is_empty() {
+            return false;
+        }
+        format!("GET {}", ids.join(" "))
+    };
+
+    let response = match Connection::open().and_then(|mut c| c.request(&request).ok()) {
+        Some(r) => r,
+        None => return false,
+    };
+
+    match response
+        .strip_prefix("KEY ")
+        .and_then(|r| r.split_once(' '))
+    {
+        Some((id, key)) => match base64::decode(key) {
+            Ok(key) => content.set_derived_key(id, &Zeroizing::new(key)).is_ok(),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+/// Gives the key that the file was unlocked with to the agent, if it's running
+pub fn store(content: &dyn FileContent) {
+    let (id, key) = match content.derived_key() {
+        Some(k) => k,
+        None => return,
+    };
+
+    if let Some(mut conn) = Connection::open() {
+        let _ = conn.request(&key_line("PUT", id, key));
+    }
+}
+
+/// Tells the agent to forget all of its keys
+pub fn lock() -> io::Result<()> {
+    let mut conn = Connection::open().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no agent is running at {:?}", socket_path()),
+        )
+    })?;
+
+    match conn.request("LOCK")?.as_str() {
+        "OK" => Ok(()),
+        other => Err(io::Error::other(format!(
+            "unexpected response from the agent: {:?}",
+            other
+        ))),
+    }
+}
+
+/// Creates the agent's socket, only accessible by the current user
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    if !dir.exists() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+
+    // The directory might have been created by someone else, to listen in on our keys
+    let meta = fs::metadata(dir)?;
+    if meta.uid() != current_uid() || meta.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{:?} must belong to you, and not be writable by others",
+                dir
+            ),
+        ));
+    }
+
+    // A socket may have been left behind by an agent that didn't exit cleanly
+    match fs::symlink_metadata(path) {
+        Ok(_) if UnixStream::connect(path).is_ok() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("an agent is already running at {:?}", path),
+            ))
+        }
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists, and is not a socket", path),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// A derived key held by the agent, until it expires
+struct HeldKey {
+    key: LockedKey,
+    expires: Instant,
+}
+
+type Keys = Arc<Mutex<HashMap<String, HeldKey>>>;
+
+/// Runs the agent, serving connections on the listener until it's killed
+///
+/// Each key is forgotten after `ttl` has passed since it was given to the agent. The socket at
+/// `path` is removed when the agent exits.
+pub fn serve(listener: UnixListener, path: PathBuf, ttl: Duration) -> ! {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM]).unwrap_or_else(|e| {
+        eprintln!("failed to set up signal handlers: {}", e);
+        exit(1);
+    });
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            let _ = fs::remove_file(&path);
+            exit(0);
+        }
+    });
+
+    let keys: Keys = Arc::new(Mutex::new(HashMap::new()));
+
+    // Expired keys are removed on every request, but we don't want to hold onto them between
+    // requests either.
+    let keys_cloned = keys.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let now = Instant::now();
+        keys_cloned.lock().unwrap().retain(|_, k| k.expires > now);
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+
+        // Only serve processes of the same user. The permissions on the socket should already
+        // guarantee this, but it doesn't hurt to check.
+        match peer_uid(&stream) {
+            Ok(uid) if uid == current_uid() => (),
+            _ => continue,
+        }
+
+        let keys = keys.clone();
+        thread::spawn(move || handle_connection(stream, &keys, ttl));
+    }
+
+    unreachable!("`UnixListener::incoming` never returns `None`")
+}
+
+fn handle_connection(stream: UnixStream, keys: &Keys, ttl: Duration) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+
+    // Requests can carry keys, so they're read in the same way as responses in `Connection`
+    while let Ok(Some(line)) = secret::read_line(&mut reader) {
+        let response = respond(line.trim_end(), keys, ttl);
+        if writeln!(writer, "{}", *response).is_err() {
+            break;
+        }
+    }
+}
+
+fn respond(request: &str, keys: &Keys, ttl: Duration) -> SecretString {
+    let mut keys = keys.lock().unwrap();
+    let now = Instant::now();
+    keys.retain(|_, k| k.expires > now);
+
+    let mut words = request.split(' ');
+    match words.next() {
+        Some("GET") => words
+            .find_map(|id| {
+                let key = keys.get(id)?;
+                Some(key_line("KEY", id, &key.key))
+            })
+            .unwrap_or_else(|| "NONE".to_owned().into()),
+        Some("PUT") => match (words.next(), words.next(), words.next()) {
+            (Some(id), Some(key), None) => match base64::decode(key) {
+                Ok(key) => {
+                    let key = LockedKey::from(Zeroizing::new(key));
+                    let expires = now + ttl;
+                    keys.insert(id.to_owned(), HeldKey { key, expires });
+                    "OK".to_owned().into()
+                }
+                Err(_) => "ERR invalid key".to_owned().into(),
+            },
+            _ => "ERR expected 'PUT <id> <key>'".to_owned().into(),
+        },
+        Some("LOCK") => {
+            keys.clear();
+            "OK".to_owned().into()
+        }
+        _ => "ERR unknown request".to_owned().into(),
+    }
+}
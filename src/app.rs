@@ -1,75 +1,191 @@
+use crate::agent;
+use crate::clipboard::{ClipboardArgs, Copied};
+use crate::password::PasswordArgs;
 use crate::ui;
-use crate::version::{
-    self, DecryptError, FieldBuilder, FileContent, GetValueError, PlaintextValue,
-    SwapEncryptionError, UnsupportedFeature,
-};
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use lazy_static::lazy_static;
-use signal_hook::{consts::SIGWINCH, iterator::Signals};
+use passman::breach::{self, HashList};
+use passman::generate::{PassphraseSpec, PasswordSpec};
+use passman::secret::{self, SecretString};
+use passman::strength::{self, Strength};
+use passman::utils;
+use passman::version::{
+    self, ChangePasswordError, DecryptError, FieldBuilder, FileContent, GetValueError, Identity,
+    PlaintextValue, SwapEncryptionError, UnsupportedFeature, ValueKind,
+};
+use signal_hook::consts::{SIGCONT, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGTSTP, SIGWINCH};
+use signal_hook::iterator::Signals;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Display;
-use std::fs::File;
-use std::io::{self, Write};
+use std::fs;
+use std::io;
 use std::mem::take;
 use std::path::PathBuf;
 use std::process::exit;
-use std::sync::atomic::{AtomicUsize, Ordering::Acquire};
+use std::sync::atomic::{
+    AtomicBool, AtomicUsize,
+    Ordering::{AcqRel, Acquire, Release},
+};
 use std::sync::{mpsc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use termion::event::{Event, Key};
 use termion::input::TermRead;
 use tui::style::Color;
+use zeroize::Zeroizing;
+
+/// Arguments for the app itself, when it's run without a subcommand
+#[derive(clap::Args)]
+pub struct Args {
+    /// A keyfile to use in addition to the password, for files that require one
+    #[clap(long, name = "KEYFILE")]
+    keyfile: Option<PathBuf>,
+
+    /// An identity file to unlock the file with, instead of the password
+    #[clap(long, name = "IDENTITY", conflicts_with = "KEYFILE")]
+    identity: Option<PathBuf>,
+
+    /// A local copy of the Have I Been Pwned password list, to mark protected values found in it
+    ///
+    /// This must be the SHA-1 version, ordered by hash. See also: breach-check
+    #[clap(long, name = "PATH")]
+    hibp_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    clipboard: ClipboardArgs,
+
+    /// Hides revealed values again after this many seconds
+    ///
+    /// Protected values are only shown after pressing 'r', and are hidden again when the selection
+    /// moves.
+    #[clap(long, value_name = "SECS")]
+    reveal_timeout: Option<u64>,
+
+    /// Locks the file again after this many seconds without a key press
+    ///
+    /// Locking forgets the key, so the password has to be entered again with ':unlock'. Unsaved
+    /// changes are kept. The file can also be locked at any time with ':lock'.
+    #[clap(long, value_name = "SECS")]
+    lock_after: Option<u64>,
+
+    /// Locks the file when passman is suspended (with 'Ctrl+z' or SIGTSTP)
+    #[clap(long)]
+    lock_on_suspend: bool,
+
+    // If any of these are given, the file is unlocked at startup
+    #[clap(flatten)]
+    password: PasswordArgs,
+}
 
-pub fn run(file_path: PathBuf) {
+pub fn run(file_path: PathBuf, args: Args) {
     // Helper function to extract out the value from a `Result`
     fn handle<T, E: Display>(val: Result<T, E>, err_msg: &str) -> T {
         match val {
             Ok(v) => v,
             Err(e) => {
+                ui::restore_term();
                 eprintln!("{}: {}", err_msg, e);
                 exit(1);
             }
         }
     }
 
-    let mut app = App::new(file_path);
+    let lock_on_suspend = args.lock_on_suspend;
+    let mut app = App::new(file_path, args);
     let mut term = handle(ui::setup_term(), "failed to setup terminal");
 
     // We start off by drawing the app once, just so that we aren't waiting for a keypress to
     // display anything
     handle(ui::draw(&mut term, &app), "failed to draw to the screen");
 
-    for item in handle(events(), "failed to initialize event loop") {
+    let events = handle(events(), "failed to initialize event loop");
+    for item in events {
         if let Some(event) = item {
             let event = match event {
                 // If we encountered an error, it's likely because our IO got disconnected or
                 // something. We probably won't be able to display anything anyways.
-                Err(_) => exit(1),
+                Err(_) => {
+                    ui::restore_term();
+                    exit(1);
+                }
                 Ok(ev) => ev,
             };
 
-            if !app.handle(event) {
-                let code = match term.clear() {
-                    Ok(_) => 0,
-                    Err(_) => 1,
-                };
+            // In raw mode, 'Ctrl+z' doesn't send SIGTSTP, so we have to handle it ourselves
+            if event == Event::Key(Key::Ctrl('z')) {
+                SUSPEND_REQUESTED.store(true, Release);
+            } else if !app.handle(event) {
+                ui::restore_term();
+                exit(0);
+            }
+        }
 
-                exit(code);
+        if SUSPEND_REQUESTED.swap(false, AcqRel) {
+            if lock_on_suspend {
+                app.lock("Locked on suspend");
             }
+            handle(ui::suspend(&mut term), "failed to setup terminal");
+        } else if CONTINUED.swap(false, AcqRel) {
+            // We were stopped by something other than SIGTSTP (e.g. SIGSTOP), so the terminal may
+            // have been changed in the meantime
+            handle(ui::resume(&mut term), "failed to setup terminal");
         }
+        app.lock_if_idle();
 
         handle(ui::draw(&mut term, &app), "failed to draw to the screen");
     }
 }
 
+// Set when we receive SIGTSTP (or 'Ctrl+z'), for the main loop to restore the terminal -- and lock,
+// with --lock-on-suspend -- before suspending
+static SUSPEND_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Set when we receive SIGCONT, for the main loop to set up the terminal again
+static CONTINUED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     pub static ref SIGNAL_TX: Mutex<Option<mpsc::Sender<Option<io::Result<Event>>>>> =
         Mutex::new(None);
+    static ref TIMER_THREAD_TX: Mutex<mpsc::Sender<()>> = Mutex::new(make_timer_thread());
+}
+
+/// Orchestrates sending a timer tick after one second, signalling the app to refresh
+///
+/// This is used to keep TOTP codes up to date while they're displayed.
+pub fn send_refresh_tick_after_1_second() {
+    let _ = TIMER_THREAD_TX.lock().unwrap().send(());
+}
+
+fn make_timer_thread() -> mpsc::Sender<()> {
+    let (tx, rx) = mpsc::channel();
+
+    let signal_tx = SIGNAL_TX
+        .lock()
+        .unwrap()
+        .as_ref()
+        .cloned()
+        .expect("app hasn't been initialized");
+
+    thread::spawn(move || {
+        while let Ok(()) = rx.recv() {
+            thread::sleep(Duration::from_secs(1));
+            // Handle any other buildup, but don't wait.
+            while let Ok(()) = rx.try_recv() {}
+            let _ = signal_tx.send(None);
+        }
+    });
+
+    tx
 }
 
 /// Creates an iterator over key events and resizes
 ///
-/// Normal events are encoded as `Some(e)`, while resizes are just `None`.
+/// Normal events are encoded as `Some(e)`, while resizes are just `None`. SIGTSTP and SIGCONT are
+/// also given as `None`, after setting `SUSPEND_REQUESTED` or `CONTINUED`.
+///
+/// Signals that would otherwise kill us (SIGHUP, SIGINT, SIGQUIT and SIGTERM) still do, but only
+/// after restoring the terminal.
 fn events() -> io::Result<impl Iterator<Item = Option<io::Result<Event>>>> {
     // In order to do this properly, we need multiple threads to handle it
     struct Iter {
@@ -89,18 +205,34 @@ fn events() -> io::Result<impl Iterator<Item = Option<io::Result<Event>>>> {
     let iter = Iter { rx };
 
     // We'll spawn three threads to handle sending into the channel. The first will produce events
-    // from resizes:
-    let mut signals = Signals::new(&[SIGWINCH])?;
+    // from resizes and other signals:
+    let mut signals = Signals::new([SIGWINCH, SIGTSTP, SIGCONT, SIGHUP, SIGINT, SIGQUIT, SIGTERM])?;
     let tx_cloned = tx.clone();
     thread::spawn(move || {
-        for _ in &mut signals {
+        for signal in &mut signals {
+            match signal {
+                SIGWINCH => (),
+                SIGTSTP => SUSPEND_REQUESTED.store(true, Release),
+                SIGCONT => CONTINUED.store(true, Release),
+                _ => {
+                    // Holding onto stdout stops the main thread from drawing anything more after
+                    // the terminal's been restored
+                    let _stdout = io::stdout().lock();
+                    ui::restore_term();
+                    let _ = signal_hook::low_level::emulate_default_handler(signal);
+                    exit(128 + signal);
+                }
+            }
             tx_cloned.send(None).unwrap();
         }
     });
 
-    // While the second will simply forward on the events from stdin, wrapping them with `Some`
+    // While the second will simply forward on the events from the terminal, wrapping them with
+    // `Some`. We read from the terminal directly, rather than stdin, because stdin may have been
+    // used to provide the password.
+    let tty = termion::get_tty()?;
     thread::spawn(move || {
-        for res in io::stdin().events() {
+        for res in tty.events() {
             tx.send(Some(res)).unwrap();
         }
     });
@@ -132,6 +264,35 @@ pub struct App {
     pub main_selected: EntrySelectState,
     // If there's an entry currently being displayed, this gives the index of that entry
     pub displayed_entry_idx: Option<usize>,
+
+    // When the selected field's value was revealed, if it has been. Protected values are hidden
+    // until they're revealed, and again once the selection moves or the timeout passes.
+    revealed_at: Option<Instant>,
+    reveal_timeout: Option<Duration>,
+
+    // The time of the last key press, for locking after `lock_after` without one
+    last_activity: Instant,
+    lock_after: Option<Duration>,
+
+    // The Have I Been Pwned list to check protected values against, if one was given
+    breach_list: Option<RefCell<HashList>>,
+    // Ratings of protected values, by entry and field index. Each value is only decrypted to rate
+    // it the first time it's drawn; the ratings are forgotten whenever the entries change or the
+    // file is locked.
+    ratings: RefCell<HashMap<(usize, usize), Option<Rating>>>,
+
+    clipboard: ClipboardArgs,
+    // The last value copied to the clipboard, if any. Copying another value stops this one from
+    // clearing the clipboard early.
+    copied: Option<Copied>,
+}
+
+/// How easy a protected value would be to guess, shown next to it
+#[derive(Copy, Clone)]
+pub struct Rating {
+    pub strength: Strength,
+    /// Whether the value is in the Have I Been Pwned list
+    pub breached: bool,
 }
 
 /// The region that is currently selected (or should be viewed)
@@ -143,7 +304,8 @@ pub enum SelectState {
     /// Some textual user input is being added to inside the bottom bar
     BottomCommand {
         kind: CommandKind,
-        value: String,
+        // This is where passwords are typed, so it's wiped once it's no longer needed
+        value: SecretString,
         as_stars: bool,
     },
 
@@ -186,6 +348,12 @@ pub enum CommandKind {
         return_to_main: bool,
         redo: bool,
     },
+    ChangePassword {
+        return_to_main: bool,
+        // The new password, once it's been entered the first time. We ask for it twice so that a
+        // typo doesn't lock the user out.
+        first: Option<SecretString>,
+    },
 }
 
 pub enum NewValueKind {
@@ -217,8 +385,72 @@ enum Cmd {
 
 impl App {
     /// Initializes the `App` from the given arguments, exiting on error
-    fn new(file_path: PathBuf) -> Self {
-        let (entries, maybe_warning) = version::parse(&file_path);
+    fn new(file_path: PathBuf, args: Args) -> Self {
+        let Args {
+            keyfile,
+            identity,
+            hibp_file,
+            clipboard,
+            reveal_timeout,
+            lock_after,
+            lock_on_suspend: _,
+            password,
+        } = args;
+
+        let (mut entries, maybe_warning) = version::parse(&file_path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        });
+
+        if let Some(path) = keyfile {
+            let res = fs::read(&path)
+                .map_err(|e| format!("failed to read keyfile {:?}: {}", path, e))
+                .and_then(|contents| entries.set_keyfile(contents).map_err(|e| e.to_string()));
+
+            if let Err(e) = res {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+
+        // With an identity, there's no need to prompt for the password -- we unlock right away
+        if let Some(path) = identity {
+            let res = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read identity file {:?}: {}", path, e))
+                .and_then(|contents| {
+                    Identity::parse(&contents)
+                        .ok_or_else(|| format!("{:?} is not a valid identity file", path))
+                })
+                .and_then(|id| entries.set_identity(&id).map_err(|e| e.to_string()));
+
+            if let Err(e) = res {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        } else if password.given() {
+            // Likewise if the password is given some other way than typing it into the app. This
+            // happens before the terminal is set up, so that a pinentry program can use it.
+            let res = (password.read("Please enter the encryption key: "))
+                .map_err(|e| format!("failed to read the password: {}", e))
+                .and_then(|mut pwd| (entries.set_key(take(&mut *pwd))).map_err(|e| e.to_string()));
+
+            if let Err(e) = res {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            agent::store(&*entries);
+        } else {
+            // Otherwise, the agent might have the key. If not, it's entered in the app as usual.
+            agent::unlock(&mut *entries);
+        }
+
+        let breach_list = hibp_file.map(|path| match HashList::open(&path) {
+            Ok(list) => RefCell::new(list),
+            Err(e) => {
+                eprintln!("failed to open {:?}: {}", path, e);
+                exit(1);
+            }
+        });
 
         let selected = match maybe_warning {
             None => SelectState::Entries,
@@ -243,11 +475,150 @@ impl App {
             file_path,
             main_selected: EntrySelectState::Name,
             displayed_entry_idx: None,
+            revealed_at: None,
+            reveal_timeout: reveal_timeout.map(Duration::from_secs),
+            last_activity: Instant::now(),
+            lock_after: lock_after.map(Duration::from_secs),
+            breach_list,
+            ratings: RefCell::new(HashMap::new()),
+            clipboard,
+            copied: None,
         }
     }
 
+    /// Returns the rating of a protected value, or `None` if the field isn't protected or its
+    /// value can't be decrypted
+    pub fn rating(&self, entry_idx: usize, field_idx: usize) -> Option<Rating> {
+        if let Some(&rating) = self.ratings.borrow().get(&(entry_idx, field_idx)) {
+            return rating;
+        }
+
+        let entry = self.entries.entry(entry_idx);
+        let field = entry.field(field_idx);
+        let rating = match (field.value_kind(), field.value()) {
+            (ValueKind::Protected, Ok(value)) => Some(Rating {
+                strength: strength::estimate(&value),
+                breached: self.is_breached(&value),
+            }),
+            // Without the key, there's nothing to rate yet -- so this isn't worth remembering
+            (ValueKind::Protected, Err(GetValueError::ContentsNotUnlocked)) => return None,
+            _ => None,
+        };
+
+        self.ratings
+            .borrow_mut()
+            .insert((entry_idx, field_idx), rating);
+        rating
+    }
+
+    /// Returns whether the value is in the Have I Been Pwned list, if one was given
+    fn is_breached(&self, value: &str) -> bool {
+        match &self.breach_list {
+            // Failing to read the list only means that we can't show the marker
+            Some(list) => {
+                let hash = Zeroizing::new(breach::hash(value));
+                matches!(list.borrow_mut().find_hash(&hash), Ok(Some(_)))
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the index of the field in the displayed entry whose value has been revealed, if
+    /// there is one
+    pub fn revealed_field(&self) -> Option<usize> {
+        let revealed_at = self.revealed_at?;
+        if let Some(timeout) = self.reveal_timeout {
+            if revealed_at.elapsed() >= timeout {
+                return None;
+            }
+            // Keep redrawing, so that the value is hidden once the timeout passes
+            send_refresh_tick_after_1_second();
+        }
+
+        match self.main_selected {
+            EntrySelectState::Field { idx } => Some(idx),
+            _ => None,
+        }
+    }
+
+    /// Returns how long until the file is locked for inactivity, if it will be
+    pub fn time_until_lock(&self) -> Option<Duration> {
+        match self.entries.decrypted() {
+            true => Some(
+                self.lock_after?
+                    .saturating_sub(self.last_activity.elapsed()),
+            ),
+            false => None,
+        }
+    }
+
+    /// Locks the file if there's been no key press for `lock_after`
+    fn lock_if_idle(&mut self) {
+        if self.time_until_lock() == Some(Duration::ZERO) {
+            self.lock("Locked after inactivity");
+        }
+    }
+
+    /// Locks the file, forgetting the key, and hides anything that was revealed with it
+    ///
+    /// The pop-up explaining why is given by `header`.
+    fn lock(&mut self, header: &'static str) {
+        if !self.entries.decrypted() {
+            return;
+        }
+
+        self.entries.lock();
+        self.revealed_at = None;
+        self.ratings.get_mut().clear();
+
+        // Without the metadata, the search results and the displayed entry don't mean anything
+        if self.entries.metadata_locked() {
+            self.filter = None;
+            self.search_term = None;
+            self.displayed_entry_idx = None;
+            self.start_entries_row = 0;
+            self.selected_entries_row = 0;
+        }
+
+        // Anything that was being typed in (e.g. a new value) is thrown away
+        self.selected = SelectState::PopUp {
+            header,
+            message: vec![
+                "The key has been forgotten; any unsaved changes are kept.".into(),
+                ui::DECRYPT_HELP_MSG.to_owned(),
+            ],
+            border_color: ui::INFO_COLOR,
+        };
+    }
+
     /// Handles a single key input, changing the app state
     pub fn handle(&mut self, event: Event) -> bool {
+        if let Event::Key(_) = event {
+            self.last_activity = Instant::now();
+        }
+
+        let position = (
+            self.displayed_entry_idx,
+            self.main_selected,
+            matches!(self.selected, SelectState::Entries),
+        );
+
+        let keep_running = self.handle_event(event);
+
+        // Moving the selection anywhere else hides the revealed value again
+        let new_position = (
+            self.displayed_entry_idx,
+            self.main_selected,
+            matches!(self.selected, SelectState::Entries),
+        );
+        if new_position != position {
+            self.revealed_at = None;
+        }
+
+        keep_running
+    }
+
+    fn handle_event(&mut self, event: Event) -> bool {
         if let SelectState::PopUp { .. } = self.selected {
             match event {
                 Event::Key(key) => {
@@ -291,10 +662,7 @@ impl App {
             _ => return true,
         };
 
-        let is_search = match kind {
-            CommandKind::Search { .. } => true,
-            _ => false,
-        };
+        let is_search = matches!(kind, CommandKind::Search { .. });
 
         match key {
             Key::Backspace => {
@@ -304,7 +672,7 @@ impl App {
                     App::set_filter(
                         &mut self.filter,
                         &mut self.search_term,
-                        Some(value.clone()),
+                        Some(value.to_string()),
                         &*self.entries,
                     );
                     self.update_displayed_entry();
@@ -315,7 +683,7 @@ impl App {
                     App::set_filter(
                         &mut self.filter,
                         &mut self.search_term,
-                        Some(take(value)),
+                        Some(take(&mut **value)),
                         &*self.entries,
                     );
                     self.start_entries_row = 0;
@@ -328,7 +696,6 @@ impl App {
                 CommandKind::Command { return_to_main } => {
                     let return_to_main = *return_to_main;
                     let value_cloned = value.clone();
-                    drop((kind, value));
                     let should_continue = self.execute_command(&value_cloned, return_to_main);
                     if !should_continue {
                         return false;
@@ -337,7 +704,7 @@ impl App {
                 CommandKind::ModifyEntryMeta => {
                     let mut entry = self.entries.entry_mut(self.displayed_entry_idx.unwrap());
                     match self.main_selected {
-                        EntrySelectState::Name => entry.set_name(take(value)),
+                        EntrySelectState::Name => entry.set_name(take(&mut **value)),
                         EntrySelectState::Tags => {
                             let new_tags = value.split(',').map(String::from).collect();
                             entry.set_tags(new_tags);
@@ -356,7 +723,7 @@ impl App {
                     field_idx,
                 } => match state {
                     ModifyFieldState::Name => {
-                        builder.as_mut().unwrap().set_name(take(value));
+                        builder.as_mut().unwrap().set_name(take(&mut **value));
                         match value_kind {
                             NewValueKind::Manual => {
                                 let mut protected = false;
@@ -369,7 +736,7 @@ impl App {
                                         protected = *p;
                                         v.clone()
                                     }
-                                    _ => "".to_owned(),
+                                    _ => SecretString::default(),
                                 };
                                 *state = ModifyFieldState::ManualValue { protected };
                             }
@@ -377,8 +744,10 @@ impl App {
                                 // Ask for the issuer first:
                                 *state = ModifyFieldState::TotpIssuer;
                                 *value = match old_value {
-                                    Some(PlaintextValue::Totp { issuer, .. }) => issuer.clone(),
-                                    _ => "".to_owned(),
+                                    Some(PlaintextValue::Totp { issuer, .. }) => {
+                                        issuer.clone().into()
+                                    }
+                                    _ => SecretString::default(),
                                 };
                             }
                         }
@@ -392,6 +761,7 @@ impl App {
                         });
 
                         let mut entry = self.entries.entry_mut(self.displayed_entry_idx.unwrap());
+                        self.ratings.get_mut().clear();
                         match entry.set_field(*field_idx, builder) {
                             // If setting the field went ok, we can just return to the entry
                             Ok(()) => self.selected = SelectState::Main,
@@ -406,12 +776,12 @@ impl App {
                     }
                     ModifyFieldState::TotpIssuer => {
                         *state = ModifyFieldState::TotpSecret {
-                            issuer: take(value),
+                            issuer: take(&mut **value),
                         };
                         // Set the secret based on the previous value:
                         *value = match old_value {
                             Some(PlaintextValue::Totp { secret, .. }) => secret.clone(),
-                            _ => "".to_owned(),
+                            _ => SecretString::default(),
                         };
                     }
                     ModifyFieldState::TotpSecret { issuer } => {
@@ -422,6 +792,7 @@ impl App {
                         });
 
                         let mut entry = self.entries.entry_mut(self.displayed_entry_idx.unwrap());
+                        self.ratings.get_mut().clear();
                         match entry.set_field(*field_idx, builder) {
                             // If setting the field went ok, we can just return to the entry
                             Ok(()) => self.selected = SelectState::Main,
@@ -440,24 +811,55 @@ impl App {
                     redo,
                 } => {
                     let (return_to_main, redo) = (*return_to_main, *redo);
-                    let key = value.clone();
-                    drop((kind, value));
+                    let key = take(value);
                     self.decrypt(key, return_to_main, redo);
                 }
+                CommandKind::ChangePassword { first, .. } => match take(first) {
+                    None => *first = Some(take(value)),
+                    Some(new_pwd) => {
+                        let confirm = take(value);
+                        self.change_password(new_pwd, confirm);
+                    }
+                },
             },
             Key::Char(c) => {
-                value.push(c);
+                secret::push_str(value, c.encode_utf8(&mut [0; 4]));
 
                 if is_search {
                     App::set_filter(
                         &mut self.filter,
                         &mut self.search_term,
-                        Some(value.clone()),
+                        Some(value.to_string()),
                         &*self.entries,
                     );
                     self.update_displayed_entry();
                 }
             }
+            Key::Ctrl('g') => {
+                // Generating a value for a manual field, which is then protected by default
+                if let CommandKind::ModifyField {
+                    state: ModifyFieldState::ManualValue { protected },
+                    ..
+                } = kind
+                {
+                    *value = PasswordSpec::default()
+                        .generate()
+                        .expect("default password rules are invalid")
+                        .into();
+                    *protected = true;
+                }
+            }
+            Key::Ctrl('p') => {
+                // Same as above, but with a passphrase instead
+                if let CommandKind::ModifyField {
+                    state: ModifyFieldState::ManualValue { protected },
+                    ..
+                } = kind
+                {
+                    *value = PassphraseSpec::default().generate().into();
+                    *protected = true;
+                }
+            }
             Key::Esc => match kind {
                 CommandKind::Search {
                     return_to_main,
@@ -478,11 +880,12 @@ impl App {
                     }
                 }
                 CommandKind::Command { return_to_main }
-                | CommandKind::Decrypt { return_to_main, .. } => match *return_to_main {
+                | CommandKind::Decrypt { return_to_main, .. }
+                | CommandKind::ChangePassword { return_to_main, .. } => match *return_to_main {
                     true => self.selected = SelectState::Main,
                     false => self.selected = SelectState::Entries,
                 },
-                CommandKind::ModifyEntryMeta { .. } | CommandKind::ModifyField { .. } => {
+                CommandKind::ModifyEntryMeta | CommandKind::ModifyField { .. } => {
                     self.selected = SelectState::Main
                 }
             },
@@ -541,12 +944,17 @@ impl App {
             // Currently there's no scrolling for viewing entries
             Cmd::ScrollDown | Cmd::ScrollUp => (),
             Cmd::StartSearch => {
+                drop(entry); // Need to explicitly drop this because `Box` has drop glue
+                if self.metadata_locked_popup("Cannot search locked entries") {
+                    return true;
+                }
+
                 self.selected = SelectState::BottomCommand {
                     kind: CommandKind::Search {
                         return_to_main: true,
                         previous: self.search_term.take(),
                     },
-                    value: String::new(),
+                    value: SecretString::default(),
                     as_stars: false,
                 };
             }
@@ -555,7 +963,7 @@ impl App {
                     kind: CommandKind::Command {
                         return_to_main: true,
                     },
-                    value: String::new(),
+                    value: SecretString::default(),
                     as_stars: false,
                 };
             }
@@ -633,7 +1041,7 @@ impl App {
 
                 self.selected = SelectState::BottomCommand {
                     kind,
-                    value,
+                    value: value.into(),
                     as_stars: false,
                 };
             }
@@ -666,6 +1074,7 @@ impl App {
                 };
 
                 entry.remove_field(field_idx);
+                self.ratings.get_mut().clear();
                 self.main_selected = match entry.num_fields() {
                     0 => EntrySelectState::Tags,
                     _ => EntrySelectState::Field {
@@ -695,6 +1104,7 @@ impl App {
                     return true;
                 }
 
+                self.ratings.get_mut().clear();
                 if let Err(e) = entry.field_mut(field_idx).swap_encryption() {
                     let mut message = vec![e.to_string()];
                     if let SwapEncryptionError::ContentsNotUnlocked = e {
@@ -719,6 +1129,28 @@ impl App {
                 self.handle_main_cmd(Cmd::Select);
             }
 
+            // Show or hide the field's value
+            Key::Char('r') => {
+                if let EntrySelectState::Field { .. } = self.main_selected {
+                    self.revealed_at = match self.revealed_at {
+                        Some(_) => None,
+                        None => Some(Instant::now()),
+                    };
+                }
+            }
+
+            // Copy the field's value (or the current TOTP code) to the clipboard
+            Key::Char('y') => {
+                let field_idx = match self.main_selected {
+                    EntrySelectState::Field { idx } => idx,
+                    _ => return true,
+                };
+
+                let value = entry.field(field_idx).value();
+                drop(entry); // Need to explicitly drop this because `Box` has drop glue
+                self.copy_value(value);
+            }
+
             // Add a TOTP field
             Key::Char('t') => {
                 let mut builder = entry.field_builder();
@@ -741,7 +1173,7 @@ impl App {
                                 // Setting the field at index = len creates a new one
                                 field_idx: entry.num_fields(),
                             },
-                            value: "".to_owned(),
+                            value: SecretString::default(),
                             as_stars: false,
                         };
                     }
@@ -754,7 +1186,9 @@ impl App {
     }
 
     fn handle_entries_cmd(&mut self, cmd: Cmd) -> bool {
+        // If the entries' metadata is locked, there's nothing in the sidebar to move between
         let num_items = match self.filter.as_ref() {
+            _ if self.entries.metadata_locked() => 0,
             Some(v) => v.len(),
             None => self.entries.num_entries(),
         };
@@ -806,13 +1240,14 @@ impl App {
                 self.start_entries_row += 1;
                 self.selected_entries_row = self.selected_entries_row.saturating_sub(1);
             }
+            Cmd::StartSearch if self.metadata_locked_popup("Cannot search locked entries") => (),
             Cmd::StartSearch => {
                 self.selected = SelectState::BottomCommand {
                     kind: CommandKind::Search {
                         return_to_main: false,
                         previous: self.search_term.take(),
                     },
-                    value: String::new(),
+                    value: SecretString::default(),
                     as_stars: false,
                 };
             }
@@ -821,11 +1256,12 @@ impl App {
                     kind: CommandKind::Command {
                         return_to_main: false,
                     },
-                    value: String::new(),
+                    value: SecretString::default(),
                     as_stars: false,
                 };
             }
             Cmd::Quit => return !self.try_quit(),
+            Cmd::Select if self.metadata_locked_popup("Cannot open locked entries") => (),
             Cmd::Select => {
                 let idx = match self.sidebar_selected_entry() {
                     None => return true,
@@ -849,13 +1285,14 @@ impl App {
     fn execute_command(&mut self, cmd: &str, return_to_main: bool) -> bool {
         match cmd {
             // new entry
+            "new" if self.metadata_locked_popup("Cannot add to locked entries") => (),
             "new" => {
                 let new_entry_idx = self.entries.add_empty_entry("<New Entry>".into());
                 self.displayed_entry_idx = Some(new_entry_idx);
                 self.main_selected = EntrySelectState::Name;
                 self.selected = SelectState::BottomCommand {
                     kind: CommandKind::ModifyEntryMeta,
-                    value: String::new(),
+                    value: SecretString::default(),
                     as_stars: false,
                 };
 
@@ -869,7 +1306,7 @@ impl App {
                         return_to_main,
                         redo: false,
                     },
-                    value: String::new(),
+                    value: SecretString::default(),
                     as_stars: true,
                 };
 
@@ -881,7 +1318,36 @@ impl App {
                         return_to_main,
                         redo: true,
                     },
-                    value: String::new(),
+                    value: SecretString::default(),
+                    as_stars: true,
+                };
+            }
+
+            // lock / forget the key
+            "lock" if !self.entries.decrypted() => match return_to_main {
+                true => self.selected = SelectState::Main,
+                false => self.selected = SelectState::Entries,
+            },
+            "lock" => self.lock("Locked"),
+
+            // change the password
+            "passwd" if !self.entries.decrypted() => {
+                self.selected = SelectState::PopUp {
+                    header: "Cannot change password",
+                    message: vec![
+                        "The contents must be decrypted before changing the password.".into(),
+                        ui::DECRYPT_HELP_MSG.to_owned(),
+                    ],
+                    border_color: ui::INFO_COLOR,
+                };
+            }
+            "passwd" => {
+                self.selected = SelectState::BottomCommand {
+                    kind: CommandKind::ChangePassword {
+                        return_to_main,
+                        first: None,
+                    },
+                    value: SecretString::default(),
                     as_stars: true,
                 };
             }
@@ -910,6 +1376,7 @@ impl App {
             "delete" => match self.displayed_entry_idx {
                 Some(idx) if return_to_main => {
                     self.entries.remove_entry(idx);
+                    self.ratings.get_mut().clear();
                     let removed = match self.filter.as_mut() {
                         Some(filter) => match filter.iter().position(|&i| i == idx) {
                             Some(i) => {
@@ -974,6 +1441,7 @@ impl App {
         let idx = self.selected_entries_row + self.start_entries_row;
 
         match self.filter.as_ref() {
+            _ if self.entries.metadata_locked() => None,
             Some(list) => list.get(idx).cloned(),
             None if idx >= self.entries.num_entries() => None,
             None => Some(idx),
@@ -1008,7 +1476,7 @@ impl App {
             let remaining_diff = diff - (self.start_entries_row - new_start);
 
             self.start_entries_row = new_start;
-            self.selected_entries_row = self.selected_entries_row - remaining_diff;
+            self.selected_entries_row -= remaining_diff;
         }
 
         self.displayed_entry_idx = self.sidebar_selected_entry();
@@ -1021,6 +1489,15 @@ impl App {
         entries: &dyn FileContent,
     ) {
         *search_term = new_term;
+
+        // Searching works on the names and tags of entries, so it isn't possible if they're
+        // still encrypted
+        if entries.metadata_locked() {
+            *search_term = None;
+            *filter = None;
+            return;
+        }
+
         let term = match search_term {
             None => {
                 *filter = None;
@@ -1053,11 +1530,23 @@ impl App {
     /// Attempt to write the content of `self.entries` to the loaded file, producing a pop-up if
     /// it fails
     fn write(&mut self, return_to_main: bool) -> Result<(), ()> {
-        // Try to open the file
-        let res = File::create(&self.file_path).and_then(|mut f| {
-            let s = self.entries.write();
-            write!(f, "{}", s).and_then(|_| f.flush())
-        });
+        // If the file has a MAC over its contents, we can't update it without the key
+        if self.entries.unsaved() && !self.entries.decrypted() && self.entries.write_requires_key()
+        {
+            self.selected = SelectState::PopUp {
+                header: "Error: Cannot write without decrypting",
+                message: vec![
+                    "Saving changes requires the key, in order to update the file's integrity check."
+                        .into(),
+                    ui::DECRYPT_HELP_MSG.to_owned(),
+                ],
+                border_color: ui::ERROR_COLOR,
+            };
+
+            return Err(());
+        }
+
+        let res = utils::write_atomic(&self.file_path, &self.entries.write());
 
         match res {
             Ok(()) => {
@@ -1082,7 +1571,7 @@ impl App {
     }
 
     /// Attempt to decrypt the content of `self.entries`, producing a pop-up widget upon failure
-    fn decrypt(&mut self, key: String, return_to_main: bool, force: bool) {
+    fn decrypt(&mut self, mut key: SecretString, return_to_main: bool, force: bool) {
         if self.entries.decrypted() && !force {
             self.selected = SelectState::PopUp {
                 header: "Already decrypted",
@@ -1096,11 +1585,14 @@ impl App {
             return;
         }
 
-        match self.entries.set_key(key) {
-            Ok(()) => match return_to_main {
-                true => self.selected = SelectState::Main,
-                false => self.selected = SelectState::Entries,
-            },
+        match self.entries.set_key(take(&mut *key)) {
+            Ok(()) => {
+                agent::store(&*self.entries);
+                match return_to_main {
+                    true => self.selected = SelectState::Main,
+                    false => self.selected = SelectState::Entries,
+                }
+            }
             Err(DecryptError::BadCrypt | DecryptError::BadUtf8) => {
                 self.selected = SelectState::PopUp {
                     header: "Error: Failed to decrypt",
@@ -1110,9 +1602,145 @@ impl App {
                     border_color: ui::ERROR_COLOR,
                 };
             }
+            Err(e @ DecryptError::BadMac) => {
+                self.selected = SelectState::PopUp {
+                    header: "Error: File integrity check failed",
+                    message: vec![
+                        e.to_string(),
+                        "The key was correct, but entry names, tags, timestamps or basic values"
+                            .into(),
+                        "have been changed since the file was last written by passman.".into(),
+                        "The contents have not been decrypted.".into(),
+                    ],
+                    border_color: ui::ERROR_COLOR,
+                };
+            }
+            Err(e @ DecryptError::KeyfileRequired) => {
+                self.selected = SelectState::PopUp {
+                    header: "Error: Failed to decrypt",
+                    message: vec![
+                        e.to_string(),
+                        "Re-open the file with 'passman --keyfile <KEYFILE> <FILE>'".into(),
+                    ],
+                    border_color: ui::ERROR_COLOR,
+                };
+            }
+            Err(e) => {
+                self.selected = SelectState::PopUp {
+                    header: "Error: Failed to decrypt",
+                    message: vec![e.to_string()],
+                    border_color: ui::ERROR_COLOR,
+                };
+            }
         }
     }
 
+    /// Copies the value of a field to the clipboard, producing a pop-up with the result
+    fn copy_value(&mut self, value: Result<SecretString, GetValueError>) {
+        let value = match value {
+            Ok(v) => v,
+            Err(e) => {
+                let mut message = vec![e.to_string()];
+                if let GetValueError::ContentsNotUnlocked = e {
+                    message.push(ui::DECRYPT_HELP_MSG.to_owned());
+                }
+
+                self.selected = SelectState::PopUp {
+                    header: "Error: Can't copy field",
+                    message,
+                    border_color: ui::ERROR_COLOR,
+                };
+                return;
+            }
+        };
+
+        if let Some(previous) = self.copied.take() {
+            previous.cancel();
+        }
+
+        self.selected = match self.clipboard.copy(&value) {
+            Ok(copied) => {
+                self.copied = Some(copied);
+                let message = match self.clipboard.timeout() {
+                    Some(secs) => format!("It will be cleared in {} seconds.", secs),
+                    None => "It will not be cleared automatically.".to_owned(),
+                };
+
+                SelectState::PopUp {
+                    header: "Copied to clipboard",
+                    message: vec![message],
+                    border_color: ui::INFO_COLOR,
+                }
+            }
+            Err(e) => SelectState::PopUp {
+                header: "Error: Couldn't copy to clipboard",
+                message: vec![e.to_string()],
+                border_color: ui::ERROR_COLOR,
+            },
+        };
+    }
+
+    /// Attempt to change the password of `self.entries`, producing a pop-up with the result
+    fn change_password(&mut self, new_pwd: SecretString, confirm: SecretString) {
+        if new_pwd != confirm {
+            self.selected = SelectState::PopUp {
+                header: "Error: Passwords do not match",
+                message: vec!["The password has not been changed.".into()],
+                border_color: ui::ERROR_COLOR,
+            };
+            return;
+        }
+
+        match self.entries.change_password(&new_pwd) {
+            Ok(()) => {
+                self.selected = SelectState::PopUp {
+                    header: "Password changed",
+                    message: vec![
+                        "All protected values have been re-encrypted with the new password.".into(),
+                        "Write the file (':w') to save the change.".into(),
+                    ],
+                    border_color: ui::INFO_COLOR,
+                };
+            }
+            Err(ChangePasswordError::Unsupported) => {
+                self.selected = SelectState::PopUp {
+                    header: "Error: Couldn't change password",
+                    message: vec![
+                        ChangePasswordError::Unsupported.to_string(),
+                        "To update, use the 'update' subcommand (passman update ...).".to_owned(),
+                    ],
+                    border_color: ui::ERROR_COLOR,
+                };
+            }
+            Err(e) => {
+                self.selected = SelectState::PopUp {
+                    header: "Error: Couldn't change password",
+                    message: vec![e.to_string()],
+                    border_color: ui::ERROR_COLOR,
+                };
+            }
+        }
+    }
+
+    /// Produces a pop-up and returns true if the metadata of entries is still encrypted
+    ///
+    /// This is used to guard actions that would require the names or tags of entries.
+    fn metadata_locked_popup(&mut self, header: &'static str) -> bool {
+        if !self.entries.metadata_locked() {
+            return false;
+        }
+
+        self.selected = SelectState::PopUp {
+            header,
+            message: vec![
+                "The names and tags of entries in this file are encrypted.".into(),
+                ui::DECRYPT_HELP_MSG.to_owned(),
+            ],
+            border_color: ui::INFO_COLOR,
+        };
+        true
+    }
+
     /// Attempts to quit, returning whether it can successfully done
     fn try_quit(&mut self) -> bool {
         match self.entries.unsaved() {
@@ -1158,7 +1786,16 @@ impl TryFrom<Event> for Cmd {
     }
 }
 
-fn fuzzy_match(target: &str, matcher: &SkimMatcherV2, name: &str, tags: Vec<&str>) -> Option<i64> {
+/// Returns the score of the best fuzzy match for the target against an entry's name or its tags,
+/// or `None` if there's no match at all
+///
+/// This is also used by the 'get' subcommand, so that entries are found the same way.
+pub fn fuzzy_match(
+    target: &str,
+    matcher: &SkimMatcherV2,
+    name: &str,
+    tags: Vec<&str>,
+) -> Option<i64> {
     tags.into_iter()
         .map(|t| matcher.fuzzy_match(t, target))
         .max()
@@ -0,0 +1,127 @@
+//! Checking passwords against a local copy of the Have I Been Pwned password list
+//!
+//! The list is the SHA-1 version "ordered by hash", with one `HASH:COUNT` line per password. It's
+//! far too big to load, so it's binary searched on disk instead -- nothing is ever sent over the
+//! network.
+
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// An open hash list, for looking up passwords in
+pub struct HashList {
+    reader: BufReader<File>,
+    len: u64,
+}
+
+/// Returns the SHA-1 hash of the password, as it appears in the list (in uppercase hex)
+pub fn hash(password: &str) -> String {
+    let mut sha = sha1::Sha1::new();
+    sha.update(password.as_bytes());
+
+    // Allocated up front, so that growing it doesn't leave partial copies behind
+    let mut hex = String::with_capacity(40);
+    for b in sha.digest().bytes() {
+        write!(hex, "{:02X}", b).unwrap();
+    }
+    hex
+}
+
+impl HashList {
+    /// Opens the list at the given path. The list is assumed to be sorted.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+
+        Ok(HashList {
+            reader: BufReader::new(file),
+            len,
+        })
+    }
+
+    /// Looks up the password, returning the number of times it's been seen in breaches if it's
+    /// in the list
+    pub fn find(&mut self, password: &str) -> io::Result<Option<u64>> {
+        self.find_hash(&Zeroizing::new(hash(password)))
+    }
+
+    /// Like [`find`](Self::find), but with the already-hashed password, from [`hash`]
+    pub fn find_hash(&mut self, hash: &str) -> io::Result<Option<u64>> {
+        // The matching line, if there is one, always starts somewhere in lo..hi
+        let (mut lo, mut hi) = (0, self.len);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (start, line) = self.line_from(mid)?;
+            if start >= hi || line.is_empty() {
+                hi = mid;
+                continue;
+            }
+
+            let text = line.trim_end();
+            let (line_hash, count) = match text.split_once(':') {
+                Some((h, c)) => (h, c.parse().ok()),
+                None => (text, None),
+            };
+
+            match line_hash.to_ascii_uppercase().as_str().cmp(hash) {
+                Ordering::Equal => return Ok(Some(count.unwrap_or(1))),
+                Ordering::Less => lo = start + line.len() as u64,
+                Ordering::Greater => hi = mid,
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the first line that starts at or after `pos`, along with where it starts
+    ///
+    /// The line is empty if there isn't one.
+    fn line_from(&mut self, pos: u64) -> io::Result<(u64, String)> {
+        let mut start = pos;
+        if pos != 0 {
+            // Skip the rest of the line that `pos - 1` is in, so that we start on a full line
+            self.reader.seek(SeekFrom::Start(pos - 1))?;
+            let mut skipped = Vec::new();
+            start += self.reader.read_until(b'\n', &mut skipped)? as u64 - 1;
+        } else {
+            self.reader.seek(SeekFrom::Start(0))?;
+        }
+
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        Ok((start, line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn binary_search() {
+        let passwords = ["password", "hunter2", "123456", "letmein", "correct horse"];
+        let mut hashes = passwords.map(hash);
+        hashes.sort();
+
+        let path = std::env::temp_dir().join(format!("passman-hibp-{}.txt", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        for (i, h) in hashes.iter().enumerate() {
+            write!(file, "{}:{}\r\n", h, i + 1).unwrap();
+        }
+        drop(file);
+
+        let mut list = HashList::open(&path).unwrap();
+        for (i, h) in hashes.iter().enumerate() {
+            assert_eq!(list.find_hash(h).unwrap(), Some(i as u64 + 1));
+        }
+        assert_eq!(list.find("not in the list").unwrap(), None);
+        assert_eq!(list.find("hunter3").unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
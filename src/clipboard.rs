@@ -0,0 +1,204 @@
+//! Copying values to the clipboard, and clearing them again after a while
+//!
+//! Values are copied either with OSC 52 escape sequences written to the terminal, or with an
+//! external command like `wl-copy` or `xclip`. Clearing is done by a separate process (the hidden
+//! `clear-clipboard` subcommand), so that it still happens after we exit. That process is only
+//! given a hash of the value, which it uses to check that the clipboard hasn't changed since.
+//!
+//! Terminals generally don't allow the clipboard to be read with OSC 52, so in that case -- or if
+//! there's no known way to read the clipboard for the copy command -- it's always cleared.
+
+use blake2::{Blake2b, Digest};
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Arguments shared by the app and all subcommands that copy to the clipboard
+#[derive(clap::Args)]
+pub struct ClipboardArgs {
+    /// Copies with the command instead of OSC 52 escape sequences (e.g. 'wl-copy' or 'xclip
+    /// -selection clipboard'), giving the value on stdin
+    #[clap(long, name = "COPY_CMD")]
+    copy_cmd: Option<String>,
+
+    /// Reads the clipboard with the command, to check that it hasn't changed before clearing it
+    ///
+    /// This is guessed for wl-copy, xclip and pbcopy. Otherwise, the clipboard is always cleared.
+    #[clap(long, name = "PASTE_CMD", requires = "COPY_CMD")]
+    paste_cmd: Option<String>,
+
+    /// Clears the clipboard this many seconds after copying, or never if 0
+    #[clap(long, name = "SECS", default_value = "45")]
+    clip_timeout: u64,
+}
+
+/// Something that's been copied, which the clipboard will be cleared of after the timeout
+pub struct Copied {
+    // The process that clears the clipboard, if there is one
+    clearer: Option<Child>,
+}
+
+impl ClipboardArgs {
+    /// Returns the number of seconds before the clipboard is cleared, or `None` if it isn't
+    pub fn timeout(&self) -> Option<u64> {
+        match self.clip_timeout {
+            0 => None,
+            secs => Some(secs),
+        }
+    }
+
+    /// Copies the value to the clipboard, and starts the process that clears it
+    ///
+    /// Dropping the returned value doesn't stop the clipboard from being cleared; for that, use
+    /// [`Copied::cancel`].
+    pub fn copy(&self, value: &str) -> io::Result<Copied> {
+        match self.copy_cmd.as_deref() {
+            Some(cmd) => pipe_to(cmd, value)?,
+            None => write_osc52(&mut termion::get_tty()?, value)?,
+        }
+
+        let clearer = match self.timeout() {
+            Some(_) => Some(self.start_clearer(value)?),
+            None => None,
+        };
+        Ok(Copied { clearer })
+    }
+
+    // The clearer is meant to keep running after we exit, so we don't wait on it
+    #[allow(clippy::zombie_processes)]
+    fn start_clearer(&self, value: &str) -> io::Result<Child> {
+        let mut cmd = Command::new(env::current_exe()?);
+        cmd.arg("clear-clipboard")
+            .args(["--clip-timeout", &self.clip_timeout.to_string()]);
+        if let Some(c) = self.copy_cmd.as_ref() {
+            cmd.args(["--copy-cmd", c]);
+        }
+        if let Some(c) = self.paste_cmd.as_ref() {
+            cmd.args(["--paste-cmd", c]);
+        }
+
+        // With OSC 52, the clearer writes to the terminal through its stdout. It can't open the
+        // terminal itself, because it won't have one once it's in its own session.
+        let stdout = match self.copy_cmd {
+            Some(_) => Stdio::null(),
+            None => Stdio::from(termion::get_tty()?),
+        };
+
+        cmd.stdin(Stdio::piped())
+            .stdout(stdout)
+            .stderr(Stdio::null());
+
+        // SAFETY: setsid is async-signal-safe, and we don't touch anything else in the child
+        unsafe {
+            cmd.pre_exec(|| match libc::setsid() {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+
+        let mut child = cmd.spawn()?;
+        // Dropping stdin afterwards closes it, so that the clearer knows it's got the whole hash
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&hash(value))?;
+        Ok(child)
+    }
+
+    /// Runs the clearer: waits for the timeout, then clears the clipboard if it still has the
+    /// value whose hash is given on stdin
+    pub fn run_clearer(&self) -> io::Result<()> {
+        let mut expected = Vec::new();
+        io::stdin().read_to_end(&mut expected)?;
+
+        thread::sleep(Duration::from_secs(self.clip_timeout));
+
+        let copy_cmd = match self.copy_cmd.as_deref() {
+            Some(c) => c,
+            None => return write_osc52(&mut io::stdout(), ""),
+        };
+
+        let paste_cmd = (self.paste_cmd.clone()).or_else(|| guess_paste_cmd(copy_cmd));
+        if let Some(paste) = paste_cmd {
+            let output = command(&paste)?.stderr(Stdio::null()).output()?;
+            // If we can't read the clipboard, it's better to clear it anyways
+            if output.status.success() && hash_bytes(&output.stdout) != expected {
+                return Ok(());
+            }
+        }
+
+        pipe_to(copy_cmd, "")
+    }
+}
+
+impl Copied {
+    /// Stops the clipboard from being cleared by this copy, for when it's being replaced by
+    /// another one
+    pub fn cancel(mut self) {
+        if let Some(mut child) = self.clearer.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn hash(value: &str) -> Vec<u8> {
+    hash_bytes(value.as_bytes())
+}
+
+fn hash_bytes(bytes: &[u8]) -> Vec<u8> {
+    Blake2b::digest(bytes).to_vec()
+}
+
+/// Makes a `Command` from a string of whitespace-separated arguments
+fn command(cmd: &str) -> io::Result<Command> {
+    let mut parts = cmd.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty clipboard command"))?;
+
+    let mut cmd = Command::new(program);
+    cmd.args(parts);
+    Ok(cmd)
+}
+
+/// Runs the command, giving it the value on stdin
+fn pipe_to(cmd: &str, value: &str) -> io::Result<()> {
+    let mut child = command(cmd)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let res = child.stdin.take().unwrap().write_all(value.as_bytes());
+    let status = child.wait()?;
+    res?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!(
+            "clipboard command {:?} failed ({})",
+            cmd, status
+        ))),
+    }
+}
+
+/// Returns the command that reads what the copy command wrote, for the ones we know about
+fn guess_paste_cmd(copy_cmd: &str) -> Option<String> {
+    let mut parts = copy_cmd.split_whitespace();
+    match parts.next()?.rsplit('/').next()? {
+        "wl-copy" if parts.next().is_none() => Some("wl-paste --no-newline".to_owned()),
+        "xclip" => Some(format!("{} -o", copy_cmd)),
+        "pbcopy" => Some("pbpaste".to_owned()),
+        _ => None,
+    }
+}
+
+/// Sets the clipboard with an OSC 52 escape sequence, which most terminals support
+///
+/// An empty value clears the clipboard.
+fn write_osc52(out: &mut impl Write, value: &str) -> io::Result<()> {
+    write!(out, "\x1b]52;c;{}\x07", base64::encode(value))?;
+    out.flush()
+}
@@ -0,0 +1,224 @@
+//! Generating random passwords and passphrases
+//!
+//! Passphrases are made of words from a list of 7776 common English words (the same size as the
+//! EFF's large wordlist, for rolling with five dice), which is compiled into the binary.
+
+use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_core::OsRng;
+use thiserror::Error;
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~";
+
+/// Characters that are easily mistaken for each other, depending on the font
+const AMBIGUOUS: &str = "Il1|O0o";
+
+/// The rules for generating a password
+#[derive(Debug, Copy, Clone)]
+pub struct PasswordSpec {
+    pub length: usize,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+    // Whether to leave out the characters in `AMBIGUOUS`
+    pub exclude_ambiguous: bool,
+    // Whether each of the enabled classes must appear at least once
+    pub require_each_class: bool,
+}
+
+impl Default for PasswordSpec {
+    fn default() -> Self {
+        PasswordSpec {
+            length: 20,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+            exclude_ambiguous: false,
+            require_each_class: true,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum GenerateError {
+    #[error("At least one class of characters must be enabled")]
+    NoClasses,
+
+    #[error("A password with {required} required classes of characters must be at least {required} long")]
+    TooShort { required: usize },
+}
+
+impl PasswordSpec {
+    /// Returns the characters in each of the enabled classes
+    fn classes(&self) -> Vec<Vec<char>> {
+        let all = [
+            (self.lowercase, LOWERCASE),
+            (self.uppercase, UPPERCASE),
+            (self.digits, DIGITS),
+            (self.symbols, SYMBOLS),
+        ];
+
+        (all.iter())
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, chars)| {
+                (chars.chars())
+                    .filter(|c| !(self.exclude_ambiguous && AMBIGUOUS.contains(*c)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Generates a password, returning `Err` if no password can satisfy the rules
+    pub fn generate(&self) -> Result<String, GenerateError> {
+        let classes = self.classes();
+        if classes.is_empty() {
+            return Err(GenerateError::NoClasses);
+        }
+
+        let mut chars = Vec::with_capacity(self.length);
+
+        // Required classes get one character each, and the rest is filled in from all of them.
+        // Every character is drawn uniformly from its set, and the result is shuffled so that the
+        // required characters don't always come first.
+        if self.require_each_class {
+            if self.length < classes.len() {
+                return Err(GenerateError::TooShort {
+                    required: classes.len(),
+                });
+            }
+
+            for class in &classes {
+                chars.push(class[OsRng.gen_range(0..class.len())]);
+            }
+        }
+
+        let all = classes.concat();
+        while chars.len() < self.length {
+            chars.push(all[OsRng.gen_range(0..all.len())]);
+        }
+
+        chars.shuffle(&mut OsRng);
+        Ok(chars.into_iter().collect())
+    }
+}
+
+lazy_static! {
+    pub(crate) static ref WORDLIST: Vec<&'static str> =
+        include_str!("wordlist.txt").lines().collect();
+}
+
+/// The rules for generating a passphrase
+#[derive(Debug, Clone)]
+pub struct PassphraseSpec {
+    pub words: usize,
+    pub separator: String,
+    // Whether to capitalize the first letter of each word
+    pub capitalize: bool,
+    // Whether to add a random digit to the end
+    pub append_digit: bool,
+}
+
+impl Default for PassphraseSpec {
+    fn default() -> Self {
+        PassphraseSpec {
+            words: 6,
+            separator: "-".to_owned(),
+            capitalize: false,
+            append_digit: false,
+        }
+    }
+}
+
+impl PassphraseSpec {
+    /// Generates a passphrase
+    pub fn generate(&self) -> String {
+        let mut words = (0..self.words)
+            .map(|_| {
+                let word = WORDLIST[OsRng.gen_range(0..WORDLIST.len())];
+                match self.capitalize {
+                    false => word.to_owned(),
+                    true => word[..1].to_uppercase() + &word[1..],
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if self.append_digit {
+            let digit = OsRng.gen_range(0..10).to_string();
+            match words.last_mut() {
+                Some(w) => w.push_str(&digit),
+                None => words.push(digit),
+            }
+        }
+
+        words.join(&self.separator)
+    }
+
+    /// Returns the entropy of the generated passphrases, in bits
+    ///
+    /// The separator and capitalization don't add anything, because they're known in advance.
+    pub fn entropy_bits(&self) -> f64 {
+        let digit = match self.append_digit {
+            true => 10_f64.log2(),
+            false => 0.0,
+        };
+
+        self.words as f64 * (WORDLIST.len() as f64).log2() + digit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_rules() {
+        let spec = PasswordSpec {
+            length: 4,
+            symbols: false,
+            exclude_ambiguous: true,
+            ..PasswordSpec::default()
+        };
+
+        for _ in 0..100 {
+            let pwd = spec.generate().unwrap();
+            assert_eq!(pwd.chars().count(), 4);
+            assert!(pwd.chars().any(|c| c.is_ascii_lowercase()));
+            assert!(pwd.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(pwd.chars().any(|c| c.is_ascii_digit()));
+            assert!(pwd.chars().all(|c| c.is_ascii_alphanumeric()));
+            assert!(!pwd.chars().any(|c| AMBIGUOUS.contains(c)));
+        }
+
+        let too_short = PasswordSpec { length: 2, ..spec };
+        assert!(too_short.generate().is_err());
+        let any_classes = PasswordSpec {
+            require_each_class: false,
+            ..too_short
+        };
+        assert_eq!(any_classes.generate().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn passphrases() {
+        assert_eq!(WORDLIST.len(), 7776);
+
+        let spec = PassphraseSpec {
+            words: 4,
+            separator: " ".to_owned(),
+            capitalize: true,
+            append_digit: true,
+        };
+        let phrase = spec.generate();
+        let words = phrase.split(' ').collect::<Vec<_>>();
+        assert_eq!(words.len(), 4);
+        assert!(words.iter().all(|w| w.starts_with(char::is_uppercase)));
+        assert!(words[3].ends_with(|c: char| c.is_ascii_digit()));
+        assert!((spec.entropy_bits() - 55.0).abs() < 1.0);
+    }
+}
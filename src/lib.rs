@@ -0,0 +1,19 @@
+//! The storage format behind passman, for use by other tools
+//!
+//! Files are opened with [`parse`], which detects the version of the file format and returns a
+//! [`FileContent`] that abstracts over it. Older versions can be converted to the current one with
+//! [`FileContent::to_current`].
+//!
+//! Nothing here prints or exits the process; all failures are returned as errors.
+
+pub mod breach;
+pub mod generate;
+pub mod secret;
+pub mod strength;
+pub mod utils;
+pub mod version;
+
+pub use version::{
+    parse, parse_str, CurrentFileContent, EntryMut, EntryRef, FieldMut, FieldRef, FileContent,
+    ParseError, PlaintextContent, Warning,
+};
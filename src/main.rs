@@ -1,14 +1,19 @@
 use clap::{IntoApp, Parser};
+use clipboard::ClipboardArgs;
+use std::panic;
 use std::path::PathBuf;
 use std::process::exit;
 
+mod agent;
 mod app;
+mod clipboard;
+mod password;
 mod subcmd;
 mod ui;
-mod utils;
-mod version;
 
 fn main() {
+    disable_core_dumps();
+    set_panic_hook();
     let args = Args::parse();
 
     // We're expecting that EITHER:
@@ -21,21 +26,76 @@ fn main() {
     }
 
     match args.subcmd {
-        None => app::run(args.file.unwrap()),
+        None => app::run(args.file.unwrap(), args.app),
         Some(Subcommand::New(args)) => subcmd::new::run(args),
         Some(Subcommand::Update(args)) => subcmd::update::run(args),
+        Some(Subcommand::Get(args)) => subcmd::get::run(args),
+        Some(Subcommand::Generate(args)) => subcmd::generate::run(args),
+        Some(Subcommand::List(args)) => subcmd::list::run(args),
+        Some(Subcommand::Audit(args)) => subcmd::audit::run(args),
+        Some(Subcommand::BreachCheck(args)) => subcmd::breach_check::run(args),
+        Some(Subcommand::Add(args)) => subcmd::edit::add(args),
+        Some(Subcommand::Set(args)) => subcmd::edit::set(args),
+        Some(Subcommand::Rm(args)) => subcmd::edit::rm(args),
+        Some(Subcommand::Tag(args)) => subcmd::edit::tag(args),
         Some(Subcommand::EmitPlaintext(args)) => subcmd::emit_plaintext::run(args),
         Some(Subcommand::FromPlaintext(args)) => subcmd::from_plaintext::run(args),
+        Some(Subcommand::Rekey(args)) => subcmd::rekey::run(args),
+        Some(Subcommand::Slot(args)) => subcmd::slot::run(args),
+        Some(Subcommand::Keygen(args)) => subcmd::keygen::run(args),
+        Some(Subcommand::Recipients(args)) => subcmd::recipients::run(args),
+        Some(Subcommand::TuneKdf(args)) => subcmd::tune_kdf::run(args),
+        Some(Subcommand::Agent(args)) => subcmd::agent::run(args),
+        Some(Subcommand::ClearClipboard(args)) => {
+            if args.run_clearer().is_err() {
+                exit(1);
+            }
+        }
     }
 }
 
+/// Stops the process from leaving keys or decrypted values behind in a core dump if it crashes
+///
+/// On Linux, this also stops other processes (even of the same user) from attaching to us and
+/// reading our memory. Failures are ignored; there isn't anything more we could do about them.
+fn disable_core_dumps() {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // SAFETY: `limit` is valid for reads, and PR_SET_DUMPABLE takes a single integer argument
+    unsafe {
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+        #[cfg(target_os = "linux")]
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+
+/// Replaces the default panic message with one that can't include any secrets, restoring the
+/// terminal first if the app is running
+///
+/// The message is only printed if it's a string literal. Anything formatted might include a key or
+/// a decrypted value -- e.g. from `Result::expect`, which includes the error.
+fn set_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        ui::restore_term();
+
+        let msg = (info.payload().downcast_ref::<&'static str>()).unwrap_or(&"<message hidden>");
+        match info.location() {
+            Some(loc) => eprintln!("passman panicked at {}: {}", loc, msg),
+            None => eprintln!("passman panicked: {}", msg),
+        }
+    }));
+}
+
 #[derive(Parser)]
 #[clap(
     version,
     author,
     about,
     // The 'ArgsNegateSubcommands' ensures that we either get 'file' or a subcommand, but not both.
-    override_usage = "passman <FILE>  or  passman <SUBCOMMAND>",
+    override_usage = "passman [OPTIONS] <FILE>  or  passman <SUBCOMMAND>",
 )]
 struct Args {
     #[clap(subcommand)]
@@ -44,6 +104,9 @@ struct Args {
     /// The passwords file to read from (and write to)
     #[clap(name = "FILE")]
     file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    app: app::Args,
 }
 
 #[derive(clap::Subcommand)]
@@ -58,6 +121,65 @@ enum Subcommand {
     #[clap(name = "update")]
     Update(subcmd::update::Args),
 
+    /// Prints a single value from the file, for use in scripts
+    ///
+    /// The entry is found by its exact name, or otherwise by fuzzy matching its name and tags. If
+    /// the search matches more than one entry, the candidates are listed and nothing is printed.
+    /// For TOTP fields, the current code is printed.
+    ///
+    /// Basic values are read without the key, so by default they aren't checked against the file's
+    /// integrity check (MAC) -- use '--verify' for that.
+    #[clap(name = "get")]
+    Get(subcmd::get::Args),
+
+    /// Lists the entries in the file, with their tags, fields and timestamps
+    ///
+    /// Values are only included with --with-values; otherwise, files without private metadata can
+    /// be listed without the password.
+    #[clap(name = "list")]
+    List(subcmd::list::Args),
+
+    /// Reports reused, weak and stale secrets, and invalid TOTP secrets
+    ///
+    /// Protected values are reported as reused if they're shared by more than one entry. If any
+    /// kind of finding is more than its --max-* threshold (0 by default), the exit code is 2.
+    #[clap(name = "audit")]
+    Audit(subcmd::audit::Args),
+
+    /// Checks protected values against a local copy of the Have I Been Pwned password list
+    ///
+    /// The list is binary searched on disk, so it must be the SHA-1 version ordered by hash.
+    /// Nothing is sent over the network, and only the names of matching entries and fields are
+    /// printed. If any are found, the exit code is 2.
+    #[clap(name = "breach-check")]
+    BreachCheck(subcmd::breach_check::Args),
+
+    /// Prints a randomly-generated password
+    ///
+    /// By default, the password uses lowercase and uppercase letters, digits and symbols, with at
+    /// least one of each. In the app, 'Ctrl+g' fills in a generated password when editing a value.
+    #[clap(name = "generate")]
+    Generate(subcmd::generate::Args),
+
+    /// Adds a new entry to the file
+    #[clap(name = "add")]
+    Add(subcmd::edit::AddArgs),
+
+    /// Sets the value of a field, reading it from stdin
+    ///
+    /// A single trailing newline is removed from the value. If the entry doesn't have the field,
+    /// it's added. For TOTP fields, this sets the secret.
+    #[clap(name = "set")]
+    Set(subcmd::edit::SetArgs),
+
+    /// Removes an entry, or a single field from it
+    #[clap(name = "rm")]
+    Rm(subcmd::edit::RmArgs),
+
+    /// Adds or removes tags on an entry, e.g. 'passman tag <FILE> <ENTRY> +work -old'
+    #[clap(name = "tag")]
+    Tag(subcmd::edit::TagArgs),
+
     // /// Adds a TOTP (e.g. Google Authenticator) generator to the passwords file
     // ///
     // /// Adding an authenticator requires manually entering the secret in plaintext; you will have
@@ -70,8 +192,8 @@ enum Subcommand {
     // AddTotp(subcmd::add_totp::Args),
     /// Outputs a plaintext (fully decrypted) version of the file
     ///
-    /// This can be used with the from-plaintext subcommand as a roundabout way of changing the
-    /// password for a file. Remember to `shred` any plaintext files after you're done.
+    /// Remember to `shred` any plaintext files after you're done. To change the password for a
+    /// file, use the rekey subcommand instead.
     ///
     /// See also: from-plaintext
     #[clap(name = "emit-plaintext")]
@@ -83,4 +205,55 @@ enum Subcommand {
     /// editing or analysis might be useful in some cases.
     #[clap(name = "from-plaintext")]
     FromPlaintext(subcmd::from_plaintext::Args),
+
+    /// Changes the password for a file, re-encrypting it in place
+    ///
+    /// No plaintext version of the file is ever written. Older files are converted to the current
+    /// version.
+    #[clap(name = "rekey")]
+    Rekey(subcmd::rekey::Args),
+
+    /// Manages the key slots of a file, each of which has its own password
+    ///
+    /// Any one slot's password can unlock the file, so slots can be used to share a file between
+    /// several people without a shared password.
+    #[clap(name = "slot")]
+    Slot(subcmd::slot::Args),
+
+    /// Generates a new identity, which can unlock files that its public key is a recipient of
+    ///
+    /// The public key is printed alongside the identity, and can be shared freely. Keep the
+    /// identity itself private.
+    #[clap(name = "keygen")]
+    Keygen(subcmd::keygen::Args),
+
+    /// Manages the recipients of a file -- the public keys whose identities can unlock it
+    ///
+    /// Recipients are listed in the file in the clear, so anyone with the file can see who has
+    /// access to it. Open a file with an identity using 'passman --identity <IDENTITY> <FILE>'.
+    #[clap(name = "recipients")]
+    Recipients(subcmd::recipients::Args),
+
+    /// Tunes the key derivation to take a target amount of time on this machine
+    ///
+    /// This benchmarks the key derivation, then re-encrypts the file in place with the chosen
+    /// parameters (and a new salt) for the key slot that the password belongs to. Older files are
+    /// converted to the current version.
+    #[clap(name = "tune-kdf")]
+    TuneKdf(subcmd::tune_kdf::Args),
+
+    /// Starts the agent, which holds onto keys so that files can be unlocked without deriving
+    /// the key from the password each time
+    ///
+    /// Once a file has been unlocked with a password, the key is given to the agent, and later
+    /// uses of the file are unlocked with it until it expires (see --ttl). The agent's socket is
+    /// at $PASSMAN_AGENT_SOCK, or in $XDG_RUNTIME_DIR by default. Older files must be updated to
+    /// the current version to use the agent.
+    #[clap(name = "agent")]
+    Agent(subcmd::agent::Args),
+
+    // Clears the clipboard after a copy, if it hasn't changed since. This is started by passman
+    // itself, so it isn't listed in the help.
+    #[clap(name = "clear-clipboard", setting = clap::AppSettings::Hidden)]
+    ClearClipboard(ClipboardArgs),
 }
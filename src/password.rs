@@ -0,0 +1,232 @@
+//! Reading passwords from somewhere other than the terminal
+//!
+//! By default, passwords are prompted for on the terminal. The arguments here allow them to be
+//! given through a file descriptor, a file, or stdin -- one per line, in the order they would be
+//! prompted for -- or to be asked for with a pinentry program, as used by GnuPG.
+
+use passman::secret::{self, SecretBytes, SecretString};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Arguments shared by the app and all subcommands that read a password
+#[derive(clap::Args)]
+pub struct PasswordArgs {
+    /// Reads passwords from the file descriptor, one per line, instead of prompting for them
+    #[clap(
+        long,
+        name = "FD",
+        conflicts_with_all = &["PASSWORD_FILE", "password-stdin", "PROGRAM"],
+    )]
+    password_fd: Option<i32>,
+
+    /// Reads passwords from the file, one per line, instead of prompting for them
+    #[clap(long, name = "PASSWORD_FILE", conflicts_with_all = &["password-stdin", "PROGRAM"])]
+    password_file: Option<PathBuf>,
+
+    /// Reads passwords from stdin, one per line, instead of prompting for them
+    #[clap(long, conflicts_with = "PROGRAM")]
+    password_stdin: bool,
+
+    /// Prompts for passwords with the pinentry program (e.g. 'pinentry-curses')
+    #[clap(long, name = "PROGRAM")]
+    pinentry: Option<PathBuf>,
+
+    // The source of passwords, once it's been opened. Multiple passwords are read from the same
+    // source, so this has to be kept around between them.
+    #[clap(skip)]
+    reader: RefCell<Option<Box<dyn BufRead>>>,
+}
+
+impl PasswordArgs {
+    /// Returns whether any of the arguments were given, i.e. whether passwords won't be read from
+    /// the terminal
+    pub fn given(&self) -> bool {
+        self.pinentry.is_some() || self.non_interactive()
+    }
+
+    /// Returns whether passwords are read without prompting for them
+    ///
+    /// New passwords only need to be confirmed when they're typed in.
+    pub fn non_interactive(&self) -> bool {
+        self.password_fd.is_some() || self.password_file.is_some() || self.password_stdin
+    }
+
+    /// Returns whether passwords are read from stdin, which then can't be used for anything else
+    pub fn uses_stdin(&self) -> bool {
+        self.password_stdin || self.password_fd == Some(0)
+    }
+
+    /// Reads a single password, displaying the prompt if it's read interactively
+    pub fn read(&self, prompt: &str) -> io::Result<SecretString> {
+        if let Some(program) = self.pinentry.as_ref() {
+            return get_pin(program, prompt);
+        } else if !self.non_interactive() {
+            return rpassword::read_password_from_tty(Some(prompt)).map(SecretString::new);
+        }
+
+        let mut reader = self.reader.borrow_mut();
+        if reader.is_none() {
+            *reader = Some(self.open()?);
+        }
+
+        secret::read_line(reader.as_mut().unwrap())?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "ran out of passwords to read")
+        })
+    }
+
+    fn open(&self) -> io::Result<Box<dyn BufRead>> {
+        if let Some(fd) = self.password_fd {
+            if fd < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid file descriptor {}", fd),
+                ));
+            }
+
+            // SAFETY: the file descriptor was explicitly given to us to read from, and nothing
+            // else in the program uses it.
+            let file = unsafe { File::from_raw_fd(fd) };
+            Ok(Box::new(BufReader::new(file)))
+        } else if let Some(path) = self.password_file.as_ref() {
+            let file = File::open(path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to open password file {:?}: {}", path, e),
+                )
+            })?;
+            Ok(Box::new(BufReader::new(file)))
+        } else {
+            Ok(Box::new(BufReader::new(io::stdin())))
+        }
+    }
+}
+
+/// Asks for a single password with a pinentry program, using the Assuan protocol
+///
+/// The protocol is line-based: each command we send is answered with either "OK" or "ERR", and
+/// the password itself is sent back in a data line before the final "OK". For the full
+/// description, see the "Assuan" and "pinentry" manuals that come with GnuPG.
+fn get_pin(program: &Path, prompt: &str) -> io::Result<SecretString> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to start pinentry {:?}: {}", program, e),
+            )
+        })?;
+
+    let mut conn = Assuan {
+        input: child.stdin.take().unwrap(),
+        output: BufReader::new(child.stdout.take().unwrap()),
+    };
+
+    let result = (|| {
+        // The server greets us with an "OK" before it'll accept any commands
+        conn.response()?;
+
+        // Terminal-based pinentry programs need to be told which terminal to use, because their
+        // stdin is connected to us. GnuPG uses $GPG_TTY for this, so we do the same.
+        if let Ok(tty) = std::env::var("GPG_TTY") {
+            conn.command(&format!("OPTION ttyname={}", tty))?;
+        }
+        if let Ok(term) = std::env::var("TERM") {
+            conn.command(&format!("OPTION ttytype={}", term))?;
+        }
+
+        let desc = prompt.trim_end().trim_end_matches(':');
+        conn.command("SETTITLE passman")?;
+        conn.command(&format!("SETDESC {}", encode(desc)))?;
+        conn.command("SETPROMPT Password:")?;
+        let pin = conn.command("GETPIN")?;
+        let _ = conn.command("BYE");
+        Ok(pin)
+    })();
+
+    drop(conn);
+    let _ = child.wait();
+    result
+}
+
+/// A connection to an Assuan server
+struct Assuan<W, R> {
+    input: W,
+    output: R,
+}
+
+impl<W: Write, R: BufRead> Assuan<W, R> {
+    /// Sends a single command, returning any data that was sent back
+    fn command(&mut self, cmd: &str) -> io::Result<SecretString> {
+        writeln!(self.input, "{}", cmd)?;
+        self.input.flush()?;
+        self.response()
+    }
+
+    /// Reads lines up to and including the final "OK" or "ERR", returning the data sent before it
+    fn response(&mut self) -> io::Result<SecretString> {
+        let mut data = SecretString::default();
+
+        loop {
+            // The password comes back in a data line, so every line is read as if it's secret
+            let line = secret::read_line(&mut self.output)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "pinentry exited unexpectedly")
+            })?;
+            let line = line.as_str();
+
+            if line == "OK" || line.starts_with("OK ") {
+                return Ok(data);
+            } else if let Some(err) = line.strip_prefix("ERR ") {
+                // Errors are given as "ERR <code> <description>"; only the description is useful
+                let msg = err.split_once(' ').map(|(_, m)| m).unwrap_or(err);
+                return Err(io::Error::other(format!("pinentry: {}", msg)));
+            } else if let Some(d) = line.strip_prefix("D ") {
+                secret::push_str(&mut data, &decode(d)?);
+            }
+            // Anything else is a status line or a comment, which we don't need
+        }
+    }
+}
+
+/// Percent-encodes the characters that can't appear directly in an Assuan command
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | '\r' | '\n' => out.push_str(&format!("%{:02X}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Decodes the percent-escapes in a data line
+///
+/// The decoded value is never longer than the data line, so it's allocated once and never has to
+/// grow.
+fn decode(s: &str) -> io::Result<SecretString> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "pinentry sent invalid data");
+
+    let mut bytes = SecretBytes::new(Vec::with_capacity(s.len()));
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [
+                    iter.next().ok_or_else(invalid)?,
+                    iter.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            b => bytes.push(b),
+        }
+    }
+
+    secret::into_string(bytes).ok_or_else(invalid)
+}
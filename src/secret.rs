@@ -0,0 +1,163 @@
+//! Wrappers for keys and decrypted values, so that they're wiped from memory once they're dropped
+//!
+//! Decrypted values are given out as [`SecretString`]s, which zero their contents on drop. Keys
+//! are held in a [`LockedKey`], which additionally locks its memory so that it can't be swapped to
+//! disk.
+//!
+//! Growing a `String` or `Vec` moves its contents to a new allocation and frees the old one as-is,
+//! so secrets that are built up piece by piece have to be appended with [`extend`] or [`push_str`].
+
+use std::io::{self, BufRead, Read};
+use std::mem::take;
+use std::ops::Deref;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A decrypted value, which is zeroed once it's dropped
+pub type SecretString = Zeroizing<String>;
+
+/// Bytes of a key or decrypted value, which are zeroed once they're dropped
+pub type SecretBytes = Zeroizing<Vec<u8>>;
+
+/// A key, kept in memory that's locked and zeroed once it's dropped
+///
+/// The key is never reallocated, so there aren't any stray copies of it left behind.
+pub struct LockedKey {
+    bytes: Box<[u8]>,
+}
+
+impl LockedKey {
+    pub fn new(key: &[u8]) -> Self {
+        let bytes = key.to_vec().into_boxed_slice();
+
+        // Locking can fail if we're over the limit for locked memory (RLIMIT_MEMLOCK). The key is
+        // still usable in that case, so we carry on anyways.
+        //
+        // SAFETY: the pointer and length refer to a single live allocation
+        unsafe { libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+
+        LockedKey { bytes }
+    }
+}
+
+impl From<SecretBytes> for LockedKey {
+    fn from(bytes: SecretBytes) -> Self {
+        LockedKey::new(&bytes)
+    }
+}
+
+impl Deref for LockedKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Drop for LockedKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+
+        // SAFETY: the pointer and length refer to a single live allocation
+        unsafe { libc::munlock(self.bytes.as_ptr() as *const libc::c_void, self.bytes.len()) };
+    }
+}
+
+/// Converts decrypted bytes into a string, returning `None` if they aren't valid UTF-8
+///
+/// The bytes are moved into the string rather than copied, so nothing is left behind.
+pub fn into_string(mut bytes: SecretBytes) -> Option<SecretString> {
+    match String::from_utf8(take(&mut *bytes)) {
+        Ok(s) => Some(Zeroizing::new(s)),
+        Err(e) => {
+            e.into_bytes().zeroize();
+            None
+        }
+    }
+}
+
+/// Appends to the bytes, moving them into a new (zeroing) allocation if they have to grow so that
+/// the old one is wiped rather than left behind
+pub fn extend(bytes: &mut SecretBytes, extra: &[u8]) {
+    if bytes.capacity() - bytes.len() < extra.len() {
+        let capacity = (bytes.len() + extra.len()).max(2 * bytes.capacity());
+        let mut grown = Zeroizing::new(Vec::with_capacity(capacity));
+        grown.extend_from_slice(bytes);
+        *bytes = grown;
+    }
+    bytes.extend_from_slice(extra);
+}
+
+/// Appends to the string, in the same way as [`extend`]
+pub fn push_str(s: &mut SecretString, extra: &str) {
+    if s.capacity() - s.len() < extra.len() {
+        let capacity = (s.len() + extra.len()).max(2 * s.capacity());
+        let mut grown = Zeroizing::new(String::with_capacity(capacity));
+        grown.push_str(s);
+        *s = grown;
+    }
+    s.push_str(extra);
+}
+
+/// Reads a single line, without the trailing newline (or "\r\n"), returning `None` at the end of
+/// the input
+pub fn read_line(reader: &mut dyn BufRead) -> io::Result<Option<SecretString>> {
+    let mut line = SecretBytes::default();
+    let mut read_any = false;
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(bs) => bs,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            break;
+        }
+        read_any = true;
+
+        match available.iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                extend(&mut line, &available[..i]);
+                reader.consume(i + 1);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                break;
+            }
+            None => {
+                let len = available.len();
+                extend(&mut line, available);
+                reader.consume(len);
+            }
+        }
+    }
+
+    match read_any {
+        false => Ok(None),
+        true => into_string(line).map(Some).ok_or_else(invalid_utf8),
+    }
+}
+
+/// Reads everything that's left in the input
+pub fn read_to_end(reader: &mut dyn Read) -> io::Result<SecretString> {
+    let mut bytes = SecretBytes::default();
+    let mut buf = Zeroizing::new([0_u8; 1024]);
+
+    loop {
+        match reader.read(&mut *buf) {
+            Ok(0) => break,
+            Ok(n) => extend(&mut bytes, &buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    into_string(bytes).ok_or_else(invalid_utf8)
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
@@ -0,0 +1,453 @@
+//! Estimating the strength of passwords, without going online
+//!
+//! This is a simplified take on zxcvbn: the password is split into the patterns that an attacker
+//! would try first -- common passwords, dictionary words, sequences, repeats, rows of keys and
+//! years -- and the number of guesses needed for the cheapest way of covering it is estimated.
+//! Anything that doesn't fit a pattern is treated as random characters.
+
+use crate::generate::WORDLIST;
+use chrono::{Datelike, Local};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+
+lazy_static! {
+    /// Common passwords, mapped to how common they are (1 is the most common)
+    static ref COMMON: HashMap<&'static str, usize> = (include_str!("common_passwords.txt").lines())
+        .enumerate()
+        .map(|(i, pwd)| (pwd, i + 1))
+        .collect();
+
+    static ref WORDS: HashSet<&'static str> = WORDLIST.iter().copied().collect();
+}
+
+/// Only this many characters are looked at; anything past it is already far out of reach
+const MAX_LEN: usize = 100;
+
+// The shortest matches considered for each kind of pattern
+const MIN_DICTIONARY_LEN: usize = 3;
+const MIN_SEQUENCE_LEN: usize = 3;
+const MIN_REPEAT_LEN: usize = 3;
+const MIN_KEYBOARD_LEN: usize = 4;
+
+const KEYBOARD_ROWS: &[&str] = &[
+    "`1234567890-=",
+    "qwertyuiop[]\\",
+    "asdfghjkl;'",
+    "zxcvbnm,./",
+    "~!@#$%^&*()_+",
+    "QWERTYUIOP{}|",
+    "ASDFGHJKL:\"",
+    "ZXCVBNM<>?",
+];
+
+/// Common substitutions of letters, undone before looking for dictionary words
+const LEET: &[(char, char)] = &[
+    ('4', 'a'),
+    ('@', 'a'),
+    ('3', 'e'),
+    ('1', 'i'),
+    ('!', 'i'),
+    ('0', 'o'),
+    ('$', 's'),
+    ('5', 's'),
+    ('7', 't'),
+];
+
+/// The estimated strength of a password, from [`estimate`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Strength {
+    /// The estimated number of guesses needed to find the password, as a power of ten
+    pub guesses_log10: f64,
+    /// A score from 0 (trivial to guess) to 4 (very hard to guess)
+    pub score: u8,
+    /// The main reason the password is weak, if there's a particular one
+    pub warning: Option<&'static str>,
+}
+
+impl Strength {
+    /// Returns whether the password is too easy to guess to be used
+    pub fn is_weak(&self) -> bool {
+        self.score < 3
+    }
+
+    /// Returns a short description of the score
+    pub fn label(&self) -> &'static str {
+        match self.score {
+            0 => "very weak",
+            1 => "weak",
+            2 => "fair",
+            3 => "strong",
+            _ => "very strong",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Pattern {
+    Common,
+    Word,
+    Sequence,
+    Repeat,
+    Keyboard,
+    Year,
+    Random,
+}
+
+impl Pattern {
+    fn warning(&self) -> Option<&'static str> {
+        match self {
+            Pattern::Common => Some("This is a commonly used password"),
+            Pattern::Word => Some("Single words are easy to guess"),
+            Pattern::Sequence => Some("Sequences like 'abc' or '6543' are easy to guess"),
+            Pattern::Repeat => Some("Repeats like 'aaa' are easy to guess"),
+            Pattern::Keyboard => Some("Straight rows of keys are easy to guess"),
+            Pattern::Year => Some("Recent years are easy to guess"),
+            Pattern::Random => None,
+        }
+    }
+}
+
+/// A part of the password that fits some pattern
+struct Match {
+    start: usize,
+    end: usize,
+    guesses_log10: f64,
+    pattern: Pattern,
+}
+
+/// Estimates how hard the password would be to guess
+pub fn estimate(password: &str) -> Strength {
+    let chars: Vec<char> = password.chars().take(MAX_LEN).collect();
+    let n = chars.len();
+
+    let mut matches = Vec::new();
+    dictionary_matches(&chars, &mut matches);
+    sequence_matches(&chars, &mut matches);
+    repeat_matches(&chars, &mut matches);
+    keyboard_matches(&chars, &mut matches);
+    year_matches(&chars, &mut matches);
+
+    // Anything can also be guessed as random characters
+    for start in 0..n {
+        for end in start + 1..=n {
+            let len = end - start;
+            let min = if len == 1 { 11.0 } else { 51.0_f64 };
+            matches.push(Match {
+                start,
+                end,
+                guesses_log10: (len as f64).max(min.log10()),
+                pattern: Pattern::Random,
+            });
+        }
+    }
+
+    // Find the cheapest way to cover the password with `count` matches, for each prefix. The total
+    // is the product of the guesses for each part, times the number of ways to order the parts.
+    //
+    // best[end][count] = (log10 of the product, index of the last match)
+    let mut best: Vec<Vec<Option<(f64, usize)>>> = vec![vec![None; n + 1]; n + 1];
+    best[0][0] = Some((0.0, usize::MAX));
+    let mut by_end: Vec<Vec<usize>> = vec![Vec::new(); n + 1];
+    for (i, m) in matches.iter().enumerate() {
+        by_end[m.end].push(i);
+    }
+
+    for end in 1..=n {
+        for &i in &by_end[end] {
+            let m = &matches[i];
+            for count in 0..n {
+                if let Some((prev, _)) = best[m.start][count] {
+                    let total = prev + m.guesses_log10;
+                    let slot = &mut best[end][count + 1];
+                    if slot.map(|(t, _)| total < t).unwrap_or(true) {
+                        *slot = Some((total, i));
+                    }
+                }
+            }
+        }
+    }
+
+    let (count, guesses_log10) = (1..=n)
+        .filter_map(|c| best[n][c].map(|(total, _)| (c, total + log10_factorial(c))))
+        .min_by(|(_, x), (_, y)| x.total_cmp(y))
+        .unwrap_or((0, 0.0));
+
+    let score = match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    };
+
+    // The warning comes from the part of the password that's the easiest to guess, relative to
+    // its length
+    let mut warning = None;
+    if score < 3 {
+        let (mut end, mut count) = (n, count);
+        let mut weakest = f64::INFINITY;
+        while count != 0 {
+            let m = &matches[best[end][count].unwrap().1];
+            let per_char = m.guesses_log10 / (m.end - m.start) as f64;
+            if m.pattern.warning().is_some() && per_char < weakest {
+                weakest = per_char;
+                warning = m.pattern.warning();
+            }
+
+            end = m.start;
+            count -= 1;
+        }
+
+        if warning.is_none() {
+            warning = Some("Short passwords are easy to guess");
+        }
+    }
+
+    Strength {
+        guesses_log10,
+        score,
+        warning,
+    }
+}
+
+fn log10_factorial(n: usize) -> f64 {
+    (2..=n).map(|i| (i as f64).log10()).sum()
+}
+
+fn dictionary_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let lower: Vec<char> = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    // Characters that lowercase to more than one aren't in any of the lists anyways
+    if lower.len() != chars.len() {
+        return;
+    }
+
+    let unleet = |c: char| LEET.iter().find(|(l, _)| *l == c).map(|(_, r)| *r);
+
+    for start in 0..chars.len() {
+        for end in start + MIN_DICTIONARY_LEN..=chars.len() {
+            let raw = &chars[start..end];
+            let word: String = lower[start..end].iter().collect();
+            let subs = lower[start..end]
+                .iter()
+                .filter(|c| unleet(**c).is_some())
+                .count();
+            let plain: String = (lower[start..end].iter())
+                .map(|c| unleet(*c).unwrap_or(*c))
+                .collect();
+            let reversed: String = word.chars().rev().collect();
+
+            let candidates = [
+                (word.as_str(), 1.0),
+                (plain.as_str(), 2_f64.powi(subs as i32)),
+                (reversed.as_str(), 2.0),
+            ];
+
+            for (candidate, factor) in candidates {
+                let (rank, pattern) = if let Some(&rank) = COMMON.get(candidate) {
+                    (rank, Pattern::Common)
+                } else if WORDS.contains(candidate) {
+                    (WORDS.len(), Pattern::Word)
+                } else {
+                    continue;
+                };
+
+                let guesses = rank as f64 * factor * uppercase_variations(raw);
+                matches.push(Match {
+                    start,
+                    end,
+                    guesses_log10: guesses.log10(),
+                    pattern,
+                });
+            }
+        }
+    }
+}
+
+/// Returns the number of ways the letters in the word could have been capitalized
+fn uppercase_variations(word: &[char]) -> f64 {
+    let upper = word.iter().filter(|c| c.is_uppercase()).count();
+    let lower = word.iter().filter(|c| c.is_lowercase()).count();
+
+    let first_upper = word.first().map(|c| c.is_uppercase()).unwrap_or(false);
+    let last_upper = word.last().map(|c| c.is_uppercase()).unwrap_or(false);
+
+    if upper == 0 {
+        1.0
+    } else if lower == 0 || (upper == 1 && (first_upper || last_upper)) {
+        2.0
+    } else {
+        // The sum of (upper + lower) choose i, for i up to the smaller of the two
+        let total = upper + lower;
+        let mut choose = 1.0;
+        let mut sum = 0.0;
+        for i in 1..=upper.min(lower) {
+            choose = choose * (total + 1 - i) as f64 / i as f64;
+            sum += choose;
+        }
+        sum
+    }
+}
+
+fn sequence_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let mut start = 0;
+    while start + 1 < chars.len() {
+        let delta = chars[start + 1] as i64 - chars[start] as i64;
+        let mut end = start + 2;
+        while end < chars.len() && chars[end] as i64 - chars[end - 1] as i64 == delta {
+            end += 1;
+        }
+
+        if delta.abs() == 1 && end - start >= MIN_SEQUENCE_LEN {
+            let first = chars[start];
+            let base: f64 = if "aAzZ019".contains(first) {
+                4.0
+            } else if first.is_ascii_digit() {
+                10.0
+            } else {
+                26.0
+            };
+            let direction = if delta < 0 { 2.0 } else { 1.0 };
+
+            matches.push(Match {
+                start,
+                end,
+                guesses_log10: (base * direction * (end - start) as f64).log10(),
+                pattern: Pattern::Sequence,
+            });
+        }
+
+        start = end - 1;
+    }
+}
+
+fn repeat_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = start + 1;
+        while end < chars.len() && chars[end] == chars[start] {
+            end += 1;
+        }
+
+        if end - start >= MIN_REPEAT_LEN {
+            matches.push(Match {
+                start,
+                end,
+                guesses_log10: (cardinality(chars[start]) * (end - start) as f64).log10(),
+                pattern: Pattern::Repeat,
+            });
+        }
+
+        start = end;
+    }
+}
+
+fn cardinality(c: char) -> f64 {
+    if c.is_ascii_digit() {
+        10.0
+    } else if c.is_alphabetic() {
+        26.0
+    } else {
+        33.0
+    }
+}
+
+fn keyboard_matches(chars: &[char], matches: &mut Vec<Match>) {
+    // Returns the row and position of the key, if it's in one of the rows
+    let position = |c: char| {
+        (KEYBOARD_ROWS.iter().enumerate())
+            .find_map(|(row, keys)| keys.chars().position(|k| k == c).map(|col| (row, col)))
+    };
+
+    let mut start = 0;
+    while start + 1 < chars.len() {
+        let mut end = start + 1;
+        let mut direction = None;
+        while end < chars.len() {
+            let step = match (position(chars[end - 1]), position(chars[end])) {
+                (Some((r1, c1)), Some((r2, c2))) if r1 % 4 == r2 % 4 => c2 as i64 - c1 as i64,
+                _ => break,
+            };
+
+            if step.abs() != 1 || direction.map(|d| d != step).unwrap_or(false) {
+                break;
+            }
+            direction = Some(step);
+            end += 1;
+        }
+
+        if end - start >= MIN_KEYBOARD_LEN {
+            // Roughly: any starting key, in either direction
+            matches.push(Match {
+                start,
+                end,
+                guesses_log10: (47.0 * 2.0 * (end - start) as f64).log10(),
+                pattern: Pattern::Keyboard,
+            });
+        }
+
+        start = end.max(start + 1);
+    }
+}
+
+fn year_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let this_year = Local::now().year() as i64;
+
+    for start in 0..chars.len().saturating_sub(3) {
+        let digits: String = chars[start..start + 4].iter().collect();
+        let year = match digits.parse::<i64>() {
+            Ok(y) if digits.chars().all(|c| c.is_ascii_digit()) && (1900..2100).contains(&y) => y,
+            _ => continue,
+        };
+
+        let guesses = (year - this_year).abs().max(20);
+        matches.push(Match {
+            start,
+            end: start + 4,
+            guesses_log10: (guesses as f64).log10(),
+            pattern: Pattern::Year,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{PassphraseSpec, PasswordSpec};
+
+    #[test]
+    fn scores() {
+        for weak in [
+            "",
+            "pw",
+            "password",
+            "P@ssw0rd1",
+            "hunter2",
+            "qwerty",
+            "abcdef",
+            "aaaaaaaa",
+        ] {
+            let strength = estimate(weak);
+            assert!(strength.is_weak(), "{:?}: {:?}", weak, strength);
+            assert!(strength.warning.is_some(), "{:?}: {:?}", weak, strength);
+        }
+
+        assert_eq!(
+            estimate("Password1990").warning,
+            Some("This is a commonly used password")
+        );
+        assert_eq!(
+            estimate("asdfghjk").warning,
+            Some("Straight rows of keys are easy to guess")
+        );
+
+        let strong = [
+            PasswordSpec::default().generate().unwrap(),
+            PassphraseSpec::default().generate(),
+        ];
+        for pwd in strong {
+            let strength = estimate(&pwd);
+            assert_eq!(strength.score, 4, "{:?}: {:?}", pwd, strength);
+            assert_eq!(strength.warning, None);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Running the agent, which caches derived keys so that files can be unlocked quickly
+
+use super::print_err_and_exit;
+use crate::agent;
+use std::env;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(subcommand)]
+    cmd: Option<Cmd>,
+
+    /// How long to keep each key for, in seconds
+    #[clap(long, default_value = "900")]
+    ttl: u64,
+
+    /// Runs the agent in the foreground, instead of detaching from the terminal
+    #[clap(long)]
+    foreground: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Cmd {
+    /// Makes the agent forget all of its keys immediately
+    #[clap(name = "lock")]
+    Lock,
+}
+
+pub fn run(args: Args) {
+    match args.cmd {
+        Some(Cmd::Lock) => {
+            agent::lock()
+                .map_err(|e| format!("error: {}", e))
+                .unwrap_or_else(print_err_and_exit);
+            println!("The agent has forgotten all of its keys");
+        }
+        None if args.foreground => {
+            let path = agent::socket_path();
+            let listener = (agent::bind(&path))
+                .map_err(|e| format!("error: failed to create the agent's socket: {}", e))
+                .unwrap_or_else(print_err_and_exit);
+
+            eprintln!("Agent listening on {:?}", path);
+            agent::serve(listener, path, Duration::from_secs(args.ttl))
+        }
+        None => start_detached(args.ttl),
+    }
+}
+
+/// Starts the agent as a separate process, in its own session so that it outlives the terminal,
+/// and waits for it to start listening
+//
+// The agent is meant to keep running after we exit, so we don't wait on it
+#[allow(clippy::zombie_processes)]
+fn start_detached(ttl: u64) {
+    // Otherwise, we'd mistake the running agent for the one we're starting
+    let path = agent::socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        print_err_and_exit(format!("error: an agent is already running at {:?}", path))
+    }
+
+    let exe = env::current_exe().unwrap_or_else(print_err_and_exit);
+
+    let mut cmd = Command::new(exe);
+    cmd.args(["agent", "--foreground", "--ttl", &ttl.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // SAFETY: setsid is async-signal-safe, and we don't touch anything else in the child
+    unsafe {
+        cmd.pre_exec(|| match libc::setsid() {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("error: failed to start the agent: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if UnixStream::connect(&path).is_ok() {
+            println!(
+                "Started the agent (pid {}), listening on {:?}",
+                child.id(),
+                path
+            );
+            return;
+        }
+
+        if let Ok(Some(_)) = child.try_wait() {
+            print_err_and_exit(
+                "error: the agent exited on startup; run 'passman agent --foreground' to see why",
+            )
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    print_err_and_exit("error: timed out waiting for the agent to start")
+}
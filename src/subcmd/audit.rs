@@ -0,0 +1,208 @@
+//! Auditing the values in a storage file, for reused, weak or stale secrets
+
+use super::list::{local_date, rfc3339};
+use super::{print_err_and_exit, UnlockArgs};
+use blake2::{Blake2b, Digest};
+use passman::strength;
+use passman::version::{self, GetValueError, ValueKind};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::{Duration, SystemTime};
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The file to audit
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// Reports entries that haven't been updated in this many days
+    #[clap(long, name = "DAYS", default_value = "365")]
+    max_age: u64,
+
+    /// Sets the output format
+    #[clap(long, arg_enum, default_value = "text")]
+    format: Format,
+
+    /// The number of reused values allowed before exiting with an error
+    #[clap(long, default_value = "0")]
+    max_reused: usize,
+
+    /// The number of weak values allowed before exiting with an error
+    #[clap(long, default_value = "0")]
+    max_weak: usize,
+
+    /// The number of stale entries allowed before exiting with an error
+    #[clap(long, default_value = "0")]
+    max_stale: usize,
+
+    /// The number of invalid TOTP secrets allowed before exiting with an error
+    #[clap(long, default_value = "0")]
+    max_bad_totp: usize,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(Copy, Clone, clap::ArgEnum)]
+enum Format {
+    /// A human-readable report
+    Text,
+    /// A single JSON object, with a list for each kind of finding
+    Json,
+}
+
+#[derive(Default, Serialize)]
+struct Report {
+    reused: Vec<Reused>,
+    weak: Vec<Weak>,
+    stale: Vec<Stale>,
+    bad_totp: Vec<FieldId>,
+}
+
+#[derive(Clone, Serialize)]
+struct FieldId {
+    entry: String,
+    field: String,
+}
+
+/// A protected value that's used by more than one entry
+#[derive(Serialize)]
+struct Reused {
+    fields: Vec<FieldId>,
+}
+
+#[derive(Serialize)]
+struct Weak {
+    #[serde(flatten)]
+    field: FieldId,
+    score: u8,
+    strength: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Stale {
+    entry: String,
+    #[serde(serialize_with = "rfc3339")]
+    last_update: SystemTime,
+}
+
+pub fn run(args: Args) {
+    let (mut content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    if !content.decrypted() {
+        content = args.unlock.unlock(content);
+    }
+
+    let max_age = Duration::from_secs(args.max_age * 24 * 60 * 60);
+    let now = SystemTime::now();
+
+    let mut report = Report::default();
+    // Values mapped to the fields they're in, in the order they were first seen. The values are
+    // hashed, so that we aren't holding onto copies of them.
+    let mut by_value: HashMap<Vec<u8>, Vec<FieldId>> = HashMap::new();
+    let mut value_order = Vec::new();
+
+    for entry in content.all_entries() {
+        for i in 0..entry.num_fields() {
+            let field = entry.field(i);
+            let id = FieldId {
+                entry: entry.name().to_owned(),
+                field: field.name().to_owned(),
+            };
+
+            match (field.value_kind(), field.value()) {
+                (ValueKind::Protected, Ok(value)) if !value.is_empty() => {
+                    let strength = strength::estimate(&value);
+                    if strength.is_weak() {
+                        report.weak.push(Weak {
+                            field: id.clone(),
+                            score: strength.score,
+                            strength: strength.label(),
+                            warning: strength.warning,
+                        });
+                    }
+
+                    let hash = Blake2b::digest(value.as_bytes()).to_vec();
+                    let fields = by_value.entry(hash).or_insert_with_key(|v| {
+                        value_order.push(v.clone());
+                        Vec::new()
+                    });
+                    fields.push(id);
+                }
+                (ValueKind::Totp, Err(GetValueError::BadTotpSecret)) => report.bad_totp.push(id),
+                (_, Err(GetValueError::Decrypt(e))) => print_err_and_exit(format!(
+                    "error: failed to read field {:?} of entry {:?}: {}",
+                    id.field, id.entry, e
+                )),
+                _ => (),
+            }
+        }
+
+        let age = now.duration_since(entry.last_update()).unwrap_or_default();
+        if age > max_age {
+            report.stale.push(Stale {
+                entry: entry.name().to_owned(),
+                last_update: entry.last_update(),
+            });
+        }
+    }
+
+    for hash in value_order {
+        let fields = by_value.remove(&hash).unwrap();
+        let first_entry = &fields[0].entry;
+        if fields.iter().any(|f| &f.entry != first_entry) {
+            report.reused.push(Reused { fields });
+        }
+    }
+
+    match args.format {
+        Format::Json => {
+            let s = serde_json::to_string_pretty(&report)
+                .expect("unrecoverable error: failed to serialize the report");
+            println!("{}", s);
+        }
+        Format::Text => print_report(&report, args.max_age),
+    }
+
+    let exceeded = report.reused.len() > args.max_reused
+        || report.weak.len() > args.max_weak
+        || report.stale.len() > args.max_stale
+        || report.bad_totp.len() > args.max_bad_totp;
+    if exceeded {
+        exit(2);
+    }
+}
+
+fn print_report(report: &Report, max_age: u64) {
+    fn section<T>(title: &str, items: &[T], describe: impl Fn(&T) -> String) {
+        if items.is_empty() {
+            println!("{}: none", title);
+            return;
+        }
+
+        println!("{} ({}):", title, items.len());
+        for item in items {
+            println!("  {}", describe(item));
+        }
+    }
+
+    let field = |f: &FieldId| format!("{} ({})", f.entry, f.field);
+
+    section("Reused values", &report.reused, |r| {
+        let fields = r.fields.iter().map(field).collect::<Vec<_>>();
+        format!("shared by {}", fields.join(", "))
+    });
+    section("Weak values", &report.weak, |w| match w.warning {
+        Some(warning) => format!("{}: {} -- {}", field(&w.field), w.strength, warning),
+        None => format!("{}: {}", field(&w.field), w.strength),
+    });
+    section(
+        &format!("Entries not updated in {} days", max_age),
+        &report.stale,
+        |s| format!("{}: last updated {}", s.entry, local_date(s.last_update)),
+    );
+    section("Invalid TOTP secrets", &report.bad_totp, field);
+}
@@ -0,0 +1,77 @@
+//! Checking protected values against a local copy of the Have I Been Pwned password list
+
+use super::{print_err_and_exit, UnlockArgs};
+use passman::breach::{self, HashList};
+use passman::version::{self, GetValueError, ValueKind};
+use std::path::PathBuf;
+use std::process::exit;
+use zeroize::Zeroizing;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The file to check
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The SHA-1 password list from Have I Been Pwned, in the version ordered by hash
+    #[clap(long, name = "PATH")]
+    hibp_file: PathBuf,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+pub fn run(args: Args) {
+    let mut list = HashList::open(&args.hibp_file)
+        .map_err(|e| format!("error: failed to open {:?}: {}", args.hibp_file, e))
+        .unwrap_or_else(print_err_and_exit);
+
+    let (mut content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    if !content.decrypted() {
+        content = args.unlock.unlock(content);
+    }
+
+    let mut found = 0;
+
+    for entry in content.all_entries() {
+        for i in 0..entry.num_fields() {
+            let field = entry.field(i);
+            if !matches!(field.value_kind(), ValueKind::Protected) {
+                continue;
+            }
+
+            let value = match field.value() {
+                Ok(v) => v,
+                Err(GetValueError::ContentsNotUnlocked) => continue,
+                Err(e) => print_err_and_exit(format!(
+                    "error: failed to read field {:?} of entry {:?}: {}",
+                    field.name(),
+                    entry.name(),
+                    e
+                )),
+            };
+
+            // The hash is as good as the value itself for weak passwords, so it's wiped as well.
+            // Lookups are cheap enough that we don't keep hashes around to avoid repeating them.
+            let hash = Zeroizing::new(breach::hash(&value));
+            let count = (list.find_hash(&hash))
+                .map_err(|e| format!("error: failed to read {:?}: {}", args.hibp_file, e))
+                .unwrap_or_else(print_err_and_exit);
+
+            if let Some(count) = count {
+                println!(
+                    "{} ({}): seen {} times in breaches",
+                    entry.name(),
+                    field.name(),
+                    count
+                );
+                found += 1;
+            }
+        }
+    }
+
+    match found {
+        0 => println!("No protected values were found in the list"),
+        _ => exit(2),
+    }
+}
@@ -0,0 +1,324 @@
+//! Non-interactive editing of storage files: the 'add', 'set', 'rm' and 'tag' subcommands
+//!
+//! All changes go through the same `EntryMut` methods as edits in the app, so that timestamps are
+//! updated in the same way.
+
+use super::{print_err_and_exit, UnlockArgs};
+use passman::secret::{self, SecretString};
+use passman::utils;
+use passman::version::{self, EntryMut, FileContent, PlaintextValue, ValueKind};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(clap::Args)]
+pub struct AddArgs {
+    /// The file to add the entry to
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The name of the new entry
+    #[clap(name = "ENTRY")]
+    entry: String,
+
+    /// A tag for the entry. May be given more than once
+    #[clap(long, name = "TAG", multiple_occurrences = true)]
+    tag: Vec<String>,
+
+    /// A basic field, given as 'name=value'. May be given more than once
+    #[clap(long, name = "NAME=VALUE", multiple_occurrences = true)]
+    field: Vec<String>,
+
+    /// A protected field, given as 'name=value'. May be given more than once
+    ///
+    /// Values given on the command line may be visible to other users of the machine; use the
+    /// 'set' subcommand to read a value from stdin instead.
+    #[clap(long, name = "NAME=SECRET", multiple_occurrences = true)]
+    protected_field: Vec<String>,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct SetArgs {
+    /// The file containing the entry
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The exact name of the entry
+    #[clap(name = "ENTRY")]
+    entry: String,
+
+    /// The name of the field to set, which is added if it doesn't exist
+    #[clap(name = "FIELD")]
+    field: String,
+
+    /// Stores the value as protected
+    ///
+    /// Fields that are already protected always stay protected.
+    #[clap(long)]
+    protected: bool,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct RmArgs {
+    /// The file containing the entry
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The exact name of the entry
+    #[clap(name = "ENTRY")]
+    entry: String,
+
+    /// The name of a field to remove. If not given, the entire entry is removed
+    #[clap(name = "FIELD")]
+    field: Option<String>,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+pub struct TagArgs {
+    /// The file containing the entry
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The exact name of the entry
+    #[clap(name = "ENTRY")]
+    entry: String,
+
+    /// The changes to make, each either '+tag' to add a tag or '-tag' to remove one
+    #[clap(name = "CHANGES", required = true, allow_hyphen_values = true)]
+    changes: Vec<String>,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+pub fn add(args: AddArgs) {
+    let parse_field = |s: &String| -> (String, String) {
+        match s.split_once('=') {
+            Some((name, value)) if !name.is_empty() => (name.to_owned(), value.to_owned()),
+            _ => print_err_and_exit(format!(
+                "error: invalid field {:?}; expected 'name=value'",
+                s
+            )),
+        }
+    };
+
+    let fields = (args.field.iter().map(|f| (parse_field(f), false)))
+        .chain(args.protected_field.iter().map(|f| (parse_field(f), true)))
+        .collect::<Vec<_>>();
+
+    let mut content = open(&args.file, &args.unlock, !args.protected_field.is_empty());
+    if content.all_entries().iter().any(|e| e.name() == args.entry) {
+        print_err_and_exit(format!("error: entry {:?} already exists", args.entry))
+    }
+
+    let idx = content.add_empty_entry(args.entry.clone());
+    let mut entry = content.entry_mut(idx);
+    if !args.tag.is_empty() {
+        entry.set_tags(args.tag);
+    }
+    for (i, ((name, value), protected)) in fields.into_iter().enumerate() {
+        set_manual_field(&mut *entry, i, name, value.into(), protected);
+    }
+    drop(entry);
+
+    save(&args.file, &*content);
+    println!("Added entry {:?}", args.entry);
+}
+
+pub fn set(args: SetArgs) {
+    if args.unlock.password_uses_stdin() {
+        print_err_and_exit("error: the value is read from stdin, so the password can't be as well")
+    }
+
+    let mut content = open(&args.file, &args.unlock, args.protected);
+    let entry_idx = find_entry_exact(&*content, &args.entry);
+
+    let existing = {
+        let entry = content.entry(entry_idx);
+        (0..entry.num_fields()).find(|&i| entry.field(i).name() == args.field)
+    };
+    let existing_kind = existing.map(|i| content.entry(entry_idx).field(i).value_kind());
+
+    // Replacing a protected value requires the key as well, even if the new one isn't given as
+    // protected
+    let is_basic = matches!(existing_kind, None | Some(ValueKind::Basic));
+    if !is_basic && !content.decrypted() {
+        args.unlock.unlock_in_place(&mut *content);
+    }
+
+    let mut value = secret::read_to_end(&mut io::stdin()).unwrap_or_else(print_err_and_exit);
+    // Only strip the single trailing newline, as would be added by 'echo'
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+
+    let mut entry = content.entry_mut(entry_idx);
+    let field_idx = existing.unwrap_or_else(|| entry.num_fields());
+    match existing_kind {
+        Some(ValueKind::Totp) => {
+            // Keep the issuer, replacing only the secret
+            let issuer = match entry.field(field_idx).plaintext_value() {
+                Ok(PlaintextValue::Totp { issuer, .. }) => issuer,
+                Ok(_) => unreachable!("TOTP field has a manual value"),
+                Err(e) => print_err_and_exit(format!("error: {}", e)),
+            };
+
+            let mut builder = entry.field_builder();
+            builder.make_totp().expect("file already has TOTP fields");
+            builder.set_name(args.field.clone());
+            builder.set_value(PlaintextValue::Totp {
+                issuer,
+                secret: value,
+            });
+            entry
+                .set_field(field_idx, builder)
+                .map_err(|e| format!("error: {}", e))
+                .unwrap_or_else(print_err_and_exit);
+        }
+        _ => {
+            let protected = args.protected || !is_basic;
+            set_manual_field(&mut *entry, field_idx, args.field.clone(), value, protected);
+        }
+    }
+    drop(entry);
+
+    save(&args.file, &*content);
+    println!("Set field {:?} of entry {:?}", args.field, args.entry);
+}
+
+pub fn rm(args: RmArgs) {
+    let mut content = open(&args.file, &args.unlock, false);
+    let entry_idx = find_entry_exact(&*content, &args.entry);
+
+    match args.field.as_deref() {
+        None => {
+            content.remove_entry(entry_idx);
+            save(&args.file, &*content);
+            println!("Removed entry {:?}", args.entry);
+        }
+        Some(field) => {
+            let mut entry = content.entry_mut(entry_idx);
+            let field_idx = (0..entry.num_fields())
+                .find(|&i| entry.field(i).name() == field)
+                .unwrap_or_else(|| {
+                    print_err_and_exit(format!(
+                        "error: entry {:?} has no field {:?}",
+                        args.entry, field
+                    ))
+                });
+            entry.remove_field(field_idx);
+            drop(entry);
+
+            save(&args.file, &*content);
+            println!("Removed field {:?} from entry {:?}", field, args.entry);
+        }
+    }
+}
+
+pub fn tag(args: TagArgs) {
+    // Check the changes before asking for the password, so that mistakes are caught early
+    let changes = (args.changes.iter())
+        .map(|c| match (c.strip_prefix('+'), c.strip_prefix('-')) {
+            (Some(t), _) if !t.is_empty() => (true, t),
+            (_, Some(t)) if !t.is_empty() => (false, t),
+            _ => print_err_and_exit(format!(
+                "error: invalid tag change {:?}; expected '+tag' or '-tag'",
+                c
+            )),
+        })
+        .collect::<Vec<_>>();
+
+    let mut content = open(&args.file, &args.unlock, false);
+    let entry_idx = find_entry_exact(&*content, &args.entry);
+
+    let mut entry = content.entry_mut(entry_idx);
+    let mut tags = entry
+        .tags()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    for (add, tag) in changes {
+        match add {
+            true if !tags.iter().any(|t| t == tag) => tags.push(tag.to_owned()),
+            true => (),
+            false => tags.retain(|t| t != tag),
+        }
+    }
+
+    entry.set_tags(tags);
+    drop(entry);
+
+    save(&args.file, &*content);
+    println!("Updated the tags of entry {:?}", args.entry);
+}
+
+/// Parses the file, unlocking it if that's required in order to make changes
+///
+/// The key is required if any protected values will be set, and also to write any changes to
+/// files that have a MAC or private metadata.
+fn open(file: &Path, unlock: &UnlockArgs, setting_protected: bool) -> Box<dyn FileContent> {
+    let (mut content, _warning) = version::parse(file).unwrap_or_else(print_err_and_exit);
+
+    if setting_protected || content.write_requires_key() || content.metadata_locked() {
+        unlock.unlock_in_place(&mut *content);
+    }
+
+    content
+}
+
+/// Finds the entry with exactly this name, exiting if there isn't exactly one
+///
+/// Unlike 'get', we don't do any fuzzy matching here -- a typo shouldn't change the wrong entry.
+fn find_entry_exact(content: &dyn FileContent, name: &str) -> usize {
+    let entries = content.all_entries();
+    let mut matches = (entries.iter().enumerate())
+        .filter(|(_, e)| e.name() == name)
+        .map(|(i, _)| i);
+
+    match (matches.next(), matches.next()) {
+        (Some(idx), None) => idx,
+        (None, _) => print_err_and_exit(format!("error: no entry named {:?}", name)),
+        (Some(_), Some(_)) => print_err_and_exit(format!(
+            "error: there is more than one entry named {:?}",
+            name
+        )),
+    }
+}
+
+/// Sets the field at `idx` to a manual value, exiting on failure
+///
+/// As with `EntryMut::set_field`, the index may be equal to the number of fields, in which case
+/// the field is appended.
+fn set_manual_field(
+    entry: &mut dyn EntryMut,
+    idx: usize,
+    name: String,
+    value: SecretString,
+    protected: bool,
+) {
+    let mut builder = entry.field_builder();
+    builder.make_manual();
+    builder.set_name(name);
+    builder.set_value(PlaintextValue::Manual { value, protected });
+
+    entry
+        .set_field(idx, builder)
+        .map_err(|e| format!("error: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+}
+
+fn save(file: &Path, content: &dyn FileContent) {
+    utils::write_atomic(file, &content.write()).unwrap_or_else(print_err_and_exit);
+}
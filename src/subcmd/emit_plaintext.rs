@@ -1,7 +1,7 @@
 //! Emits the plaintext version of a file
 
-use super::print_err_and_exit;
-use crate::version;
+use super::{print_err_and_exit, UnlockArgs};
+use passman::version;
 use std::fs;
 use std::path::PathBuf;
 
@@ -14,18 +14,17 @@ pub struct Args {
     /// Sets the output file to write to
     #[clap(short, long, name = "OUTPUT")]
     output: PathBuf,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
 }
 
 pub fn run(args: Args) {
-    let (content, _warning) = version::parse(&args.input);
-
-    let pwd = rpassword::read_password_from_tty(Some("Please enter the current encryption key: "))
-        .unwrap_or_else(print_err_and_exit);
+    let (content, _warning) = version::parse(&args.input).unwrap_or_else(print_err_and_exit);
 
-    let output = content
-        .to_current(pwd)
-        .and_then(|c| c.to_plaintext())
-        .map_err(|_| "error: decryption failed")
+    let output = (args.unlock.unlock(content))
+        .into_plaintext()
+        .map_err(|e| format!("error: {}", e))
         .unwrap_or_else(print_err_and_exit);
 
     let s = serde_yaml::to_string(&output)
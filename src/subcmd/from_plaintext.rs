@@ -1,7 +1,7 @@
 //! Creates a config file from its plaintext version
 
-use super::print_err_and_exit;
-use crate::version::{CurrentFileContent, FileContent};
+use super::{print_err_and_exit, KeyArgs};
+use passman::version::{CurrentFileContent, FileContent};
 use std::fs;
 use std::path::PathBuf;
 
@@ -14,6 +14,9 @@ pub struct Args {
     /// Sets the output file to write to
     #[clap(short, long, name = "OUTPUT")]
     output: PathBuf,
+
+    #[clap(flatten)]
+    key: KeyArgs,
 }
 
 #[rustfmt::skip]
@@ -24,10 +27,9 @@ pub fn run(args: Args) {
     let plaintext = serde_yaml::from_str(&content_str)
         .unwrap_or_else(print_err_and_exit);
 
-    let pwd = rpassword::read_password_from_tty(Some("Please enter a new encryption key: "))
-        .unwrap_or_else(print_err_and_exit);
+    let pwd = args.key.read_password("Please enter a new encryption key: ");
 
-    let encrypted = CurrentFileContent::from_plaintext(pwd, plaintext);
+    let encrypted = CurrentFileContent::from_plaintext(&pwd, args.key.read(), plaintext);
     let output_str = encrypted.write();
 
     fs::write(&args.output, &output_str)
@@ -0,0 +1,87 @@
+//! Generating random passwords and passphrases, without needing a storage file
+
+use super::print_err_and_exit;
+use passman::generate::{PassphraseSpec, PasswordSpec};
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The number of characters in the password
+    #[clap(short, long, default_value = "20")]
+    length: usize,
+
+    /// Leaves out lowercase letters
+    #[clap(long)]
+    no_lowercase: bool,
+
+    /// Leaves out uppercase letters
+    #[clap(long)]
+    no_uppercase: bool,
+
+    /// Leaves out digits
+    #[clap(long)]
+    no_digits: bool,
+
+    /// Leaves out symbols
+    #[clap(long)]
+    no_symbols: bool,
+
+    /// Leaves out characters that are easily mistaken for each other, like 'l', '1' and 'I'
+    #[clap(long)]
+    exclude_ambiguous: bool,
+
+    /// Doesn't require that every class of characters appears at least once
+    #[clap(long)]
+    no_require: bool,
+
+    /// Generates a passphrase with this many words instead
+    #[clap(
+        short,
+        long,
+        conflicts_with_all = &["length", "no-lowercase", "no-uppercase", "no-digits", "no-symbols", "exclude-ambiguous", "no-require"],
+    )]
+    words: Option<usize>,
+
+    /// Sets the text between the words of a passphrase (with --words)
+    #[clap(long, default_value = "-")]
+    separator: String,
+
+    /// Capitalizes each word of a passphrase (with --words)
+    #[clap(long)]
+    capitalize: bool,
+
+    /// Adds a random digit to the end of a passphrase (with --words)
+    #[clap(long)]
+    digit: bool,
+}
+
+pub fn run(args: Args) {
+    if let Some(words) = args.words {
+        let spec = PassphraseSpec {
+            words,
+            separator: args.separator,
+            capitalize: args.capitalize,
+            append_digit: args.digit,
+        };
+        println!("{}", spec.generate());
+        return;
+    } else if args.capitalize || args.digit {
+        print_err_and_exit(
+            "error: '--capitalize' and '--digit' only apply to passphrases (use --words)",
+        )
+    }
+
+    let spec = PasswordSpec {
+        length: args.length,
+        lowercase: !args.no_lowercase,
+        uppercase: !args.no_uppercase,
+        digits: !args.no_digits,
+        symbols: !args.no_symbols,
+        exclude_ambiguous: args.exclude_ambiguous,
+        require_each_class: !args.no_require,
+    };
+
+    let pwd = (spec.generate())
+        .map_err(|e| format!("error: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+    println!("{}", pwd);
+}
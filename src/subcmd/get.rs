@@ -0,0 +1,126 @@
+//! Printing a single value from a storage file, for use in scripts
+
+use super::{find_entry, pick_one, print_err_and_exit, UnlockArgs};
+use crate::app::fuzzy_match;
+use crate::clipboard::ClipboardArgs;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use passman::version::{self, EntryRef, ValueKind};
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The file to read from
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The name of the entry, or a fuzzy search for it by name or tag
+    #[clap(name = "ENTRY")]
+    entry: String,
+
+    /// The name of the field to print, or a fuzzy search for it
+    ///
+    /// If not given, the first protected field is used -- or the first field, if none of them are
+    /// protected.
+    #[clap(name = "FIELD")]
+    field: Option<String>,
+
+    /// Copies the value to the clipboard instead of printing it
+    #[clap(long)]
+    clip: bool,
+
+    /// Unlocks the file even for basic values, so that the file's integrity check (MAC) is
+    /// verified before anything is printed
+    ///
+    /// Without this, basic values are printed without the key, and so aren't authenticated: they
+    /// could have been changed by anyone able to write to the file.
+    #[clap(long)]
+    verify: bool,
+
+    #[clap(flatten)]
+    clipboard: ClipboardArgs,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+pub fn run(args: Args) {
+    let (mut content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+
+    // With private metadata, we can't search by name until the file is unlocked
+    if content.metadata_locked() {
+        content = args.unlock.unlock(content);
+    }
+
+    let entry_idx = find_entry(&*content, &args.entry);
+    let field_idx = find_field(&*content.entry(entry_idx), args.field.as_deref());
+
+    // Basic values can be read without the key, so we only ask for it if it's needed -- or if the
+    // integrity check is wanted, which happens when the file is unlocked
+    let kind = content.entry(entry_idx).field(field_idx).value_kind();
+    if (args.verify || !matches!(kind, ValueKind::Basic)) && !content.decrypted() {
+        content = args.unlock.unlock(content);
+    }
+
+    let value = (content.entry(entry_idx).field(field_idx))
+        .value()
+        .map_err(|e| format!("error: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+
+    if !args.clip {
+        println!("{}", *value);
+        return;
+    }
+
+    (args.clipboard.copy(&value))
+        .map_err(|e| format!("error: failed to copy to the clipboard: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+    match args.clipboard.timeout() {
+        Some(secs) => eprintln!(
+            "Copied to the clipboard; it will be cleared in {} seconds",
+            secs
+        ),
+        None => eprintln!("Copied to the clipboard"),
+    }
+}
+
+/// Finds the field matching `query`, or the default field if there's no query, exiting on failure
+fn find_field(entry: &dyn EntryRef, query: Option<&str>) -> usize {
+    let fields = (0..entry.num_fields())
+        .map(|i| entry.field(i))
+        .collect::<Vec<_>>();
+
+    let query = match query {
+        Some(q) => q,
+        None => {
+            let protected = fields
+                .iter()
+                .position(|f| !matches!(f.value_kind(), ValueKind::Basic));
+            return match (protected, fields.is_empty()) {
+                (Some(idx), _) => idx,
+                (None, false) => 0,
+                (None, true) => {
+                    print_err_and_exit(format!("error: entry {:?} has no fields", entry.name()))
+                }
+            };
+        }
+    };
+
+    let exact = (fields.iter().enumerate())
+        .filter(|(_, f)| f.name() == query)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let candidates = match exact.is_empty() {
+        false => exact,
+        true => {
+            let matcher = SkimMatcherV2::default();
+            let mut matches = (fields.iter().enumerate())
+                .filter_map(|(i, f)| Some((i, fuzzy_match(query, &matcher, f.name(), vec![])?)))
+                .collect::<Vec<_>>();
+            matches.sort_by_key(|(_, score)| -score);
+            matches.into_iter().map(|(i, _)| i).collect()
+        }
+    };
+
+    pick_one("field", query, candidates, |i| fields[i].name().to_owned())
+}
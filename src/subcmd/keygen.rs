@@ -0,0 +1,40 @@
+//! Generating identities, which can unlock files without a password
+
+use super::print_err_and_exit;
+use passman::version::Identity;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// Sets the file to write the identity to, instead of stdout
+    ///
+    /// The file must not already exist.
+    #[clap(short, long, name = "OUTPUT")]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: Args) {
+    let identity = Identity::generate();
+    let contents = identity.to_file_string();
+
+    let path = match args.output {
+        Some(p) => p,
+        None => return print!("{}", contents),
+    };
+
+    // Only the owner should be able to read the identity, and we never want to overwrite an
+    // existing one
+    let () = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()).and_then(|_| f.sync_all()))
+        .map_err(|e| format!("failed to write identity file {:?}: {}", path, e))
+        .unwrap_or_else(print_err_and_exit);
+
+    eprintln!("Public key: {}", base64::encode(identity.public_key()));
+}
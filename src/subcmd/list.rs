@@ -0,0 +1,245 @@
+//! Listing the entries in a storage file, for use in scripts and reports
+
+use super::{print_err_and_exit, UnlockArgs};
+use crate::app::fuzzy_match;
+use chrono::{DateTime, Local, Utc};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use passman::secret::SecretString;
+use passman::utils;
+use passman::version::{self, EntryRef, FileContent, GetValueError, ValueKind};
+use serde::{Serialize, Serializer};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The file to list the entries of
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// Only lists entries with this tag. May be given more than once, to require all of them
+    #[clap(long, name = "TAG", multiple_occurrences = true)]
+    tag: Vec<String>,
+
+    /// Only lists entries that fuzzy-match the search, ordered by how well they match
+    #[clap(long, name = "QUERY")]
+    search: Option<String>,
+
+    /// Sets the output format
+    #[clap(long, arg_enum, default_value = "table")]
+    format: Format,
+
+    /// Includes the values of fields, decrypting protected values and computing TOTP codes
+    ///
+    /// This requires the password, if there are any protected fields to list.
+    #[clap(long)]
+    with_values: bool,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(Copy, Clone, clap::ArgEnum)]
+enum Format {
+    /// A JSON array, with one object per entry
+    Json,
+    /// A human-readable table
+    Table,
+    /// Just the entry names, one per line
+    Names,
+}
+
+/// A single entry, as output in JSON
+#[derive(Serialize)]
+struct ListedEntry<'a> {
+    name: &'a str,
+    tags: Vec<&'a str>,
+    fields: Vec<ListedField>,
+    #[serde(serialize_with = "rfc3339")]
+    first_added: SystemTime,
+    #[serde(serialize_with = "rfc3339")]
+    last_update: SystemTime,
+}
+
+#[derive(Serialize)]
+struct ListedField {
+    name: String,
+    kind: &'static str,
+    // Only given with `--with-values`. If the value couldn't be read, it's `null` and the reason
+    // is given in `error` instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Option<SecretString>>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "display")]
+    error: Option<GetValueError>,
+}
+
+pub fn run(args: Args) {
+    let (mut content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+
+    // With private metadata, there's nothing to list until the file is unlocked
+    if content.metadata_locked() {
+        content = args.unlock.unlock(content);
+    }
+
+    let entries = select_entries(&*content, &args.tag, args.search.as_deref());
+
+    // Only ask for the password if there's a value we actually need it for
+    let needs_key = args.with_values
+        && entries.iter().any(|&i| {
+            let entry = content.entry(i);
+            (0..entry.num_fields())
+                .any(|f| !matches!(entry.field(f).value_kind(), ValueKind::Basic))
+        });
+    if needs_key && !content.decrypted() {
+        content = args.unlock.unlock(content);
+    }
+
+    let listed = (entries.iter())
+        .map(|&i| content.entry(i))
+        .collect::<Vec<_>>();
+    let listed = (listed.iter())
+        .map(|e| list_entry(&**e, args.with_values))
+        .collect::<Vec<_>>();
+
+    match args.format {
+        Format::Json => {
+            let s = serde_json::to_string_pretty(&listed)
+                .expect("unrecoverable error: failed to serialize the entries");
+            println!("{}", s);
+        }
+        Format::Names => {
+            for entry in listed {
+                println!("{}", entry.name);
+            }
+        }
+        Format::Table => print_table(&listed, args.with_values),
+    }
+}
+
+/// Returns the indexes of the entries matching the tags and search, in the order to list them
+fn select_entries(content: &dyn FileContent, tags: &[String], search: Option<&str>) -> Vec<usize> {
+    let matcher = SkimMatcherV2::default();
+    let mut selected = (content.all_entries().into_iter().enumerate())
+        .filter(|(_, e)| tags.iter().all(|t| e.tags().contains(&t.as_str())))
+        .filter_map(|(i, e)| match search {
+            None => Some((i, 0)),
+            Some(q) => Some((i, fuzzy_match(q, &matcher, e.name(), e.tags())?)),
+        })
+        .collect::<Vec<_>>();
+
+    // Sort in reverse order so that the best matches are first. Without a search, this is stable
+    // and so the entries stay in the order they're stored.
+    selected.sort_by_key(|(_, score)| -score);
+    selected.into_iter().map(|(i, _)| i).collect()
+}
+
+fn list_entry(entry: &dyn EntryRef, with_values: bool) -> ListedEntry<'_> {
+    let fields = (0..entry.num_fields())
+        .map(|i| {
+            let field = entry.field(i);
+
+            // A single bad value (e.g. an invalid TOTP secret) is marked as such, so that it
+            // doesn't stop the rest from being listed. Failing to decrypt is still fatal, because
+            // that means the file itself is broken.
+            let (value, error) = match with_values.then(|| field.value()) {
+                None => (None, None),
+                Some(Ok(v)) => (Some(Some(v)), None),
+                Some(Err(e @ GetValueError::Decrypt(_))) => print_err_and_exit(format!(
+                    "error: failed to read field {:?} of entry {:?}: {}",
+                    field.name(),
+                    entry.name(),
+                    e
+                )),
+                Some(Err(e)) => (Some(None), Some(e)),
+            };
+
+            ListedField {
+                name: field.name().to_owned(),
+                kind: match field.value_kind() {
+                    ValueKind::Basic => "basic",
+                    ValueKind::Protected => "protected",
+                    ValueKind::Totp => "totp",
+                },
+                value,
+                error,
+            }
+        })
+        .collect();
+
+    ListedEntry {
+        name: entry.name(),
+        tags: entry.tags(),
+        fields,
+        first_added: entry.first_added(),
+        last_update: entry.last_update(),
+    }
+}
+
+fn display<S: Serializer>(err: &Option<GetValueError>, serializer: S) -> Result<S::Ok, S::Error> {
+    // `skip_serializing_if` means that this is only called with `Some`
+    serializer.collect_str(err.as_ref().unwrap())
+}
+
+pub(super) fn rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&DateTime::<Utc>::from(*time).to_rfc3339())
+}
+
+fn print_table(entries: &[ListedEntry], with_values: bool) {
+    let header = ["NAME", "TAGS", "FIELDS", "ADDED", "UPDATED"];
+
+    let rows = (entries.iter())
+        .map(|e| {
+            let fields = (e.fields.iter())
+                .map(|f| match (&f.value, f.kind) {
+                    (Some(Some(v)), _) if with_values => format!("{}={}", f.name, v.as_str()),
+                    (Some(None), _) if with_values => {
+                        format!("{}={}", f.name, error_marker(f.error.as_ref()))
+                    }
+                    (_, "basic") => f.name.clone(),
+                    (_, kind) => format!("{} ({})", f.name, kind),
+                })
+                .collect::<Vec<_>>();
+
+            [
+                e.name.to_owned(),
+                utils::comma_strings(&e.tags),
+                fields.join(", "),
+                local_date(e.first_added),
+                local_date(e.last_update),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let mut widths = header.map(|h| h.chars().count());
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: &[&str]| {
+        let line = (cells.iter().zip(widths))
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+
+    print_row(&header);
+    for row in &rows {
+        print_row(&row.each_ref().map(|s| s.as_str()));
+    }
+}
+
+/// The text shown in place of a value that couldn't be read, matching the app
+fn error_marker(err: Option<&GetValueError>) -> &'static str {
+    match err {
+        Some(GetValueError::BadTotpSecret) => "<BAD TOTP SECRET>",
+        Some(GetValueError::ContentsNotUnlocked) => crate::ui::PROTECTED_STR,
+        Some(GetValueError::Decrypt(_)) | None => "<BAD CRYPT>",
+    }
+}
+
+pub(super) fn local_date(time: SystemTime) -> String {
+    DateTime::<Local>::from(time).format("%Y-%m-%d").to_string()
+}
@@ -1,11 +1,31 @@
 //! Implementations of miscelaneous other subcommands provided
 
+use crate::app::fuzzy_match;
+use crate::password::PasswordArgs;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use passman::secret::SecretString;
+use passman::version::{CurrentFileContent, FileContent, Identity};
 use std::fmt::Display;
+use std::fs;
+use std::mem::take;
+use std::path::{Path, PathBuf};
 use std::process;
 
+pub mod agent;
+pub mod audit;
+pub mod breach_check;
+pub mod edit;
 pub mod emit_plaintext;
 pub mod from_plaintext;
+pub mod generate;
+pub mod get;
+pub mod keygen;
+pub mod list;
 pub mod new;
+pub mod recipients;
+pub mod rekey;
+pub mod slot;
+pub mod tune_kdf;
 pub mod update;
 
 /// Helper function used by subcommands
@@ -15,3 +35,227 @@ fn print_err_and_exit<T>(err: impl Display) -> T {
     eprintln!("{}", err);
     process::exit(1)
 }
+
+/// Reads the contents of a keyfile, exiting on failure
+fn read_keyfile(path: &Path) -> Vec<u8> {
+    fs::read(path)
+        .map_err(|e| format!("failed to read keyfile {:?}: {}", path, e))
+        .unwrap_or_else(print_err_and_exit)
+}
+
+/// Reads and parses an identity file, exiting on failure
+fn read_identity(path: &Path) -> Identity {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read identity file {:?}: {}", path, e))
+        .unwrap_or_else(print_err_and_exit);
+
+    Identity::parse(&contents).unwrap_or_else(|| {
+        print_err_and_exit(format!("error: {:?} is not a valid identity file", path))
+    })
+}
+
+/// Finds the entry matching `query`, exiting on failure or if there's more than one match
+///
+/// An entry with exactly that name is always preferred. Otherwise, entries are fuzzy-matched by
+/// name and tags, in the same way as searching in the app.
+fn find_entry(content: &dyn FileContent, query: &str) -> usize {
+    let entries = content.all_entries();
+
+    let exact = (entries.iter().enumerate())
+        .filter(|(_, e)| e.name() == query)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let candidates = match exact.is_empty() {
+        false => exact,
+        true => {
+            let matcher = SkimMatcherV2::default();
+            let mut matches = (entries.iter().enumerate())
+                .filter_map(|(i, e)| Some((i, fuzzy_match(query, &matcher, e.name(), e.tags())?)))
+                .collect::<Vec<_>>();
+            // Sort in reverse order so that the best matches are listed first
+            matches.sort_by_key(|(_, score)| -score);
+            matches.into_iter().map(|(i, _)| i).collect()
+        }
+    };
+
+    pick_one("entry", query, candidates, |i| entries[i].name().to_owned())
+}
+
+/// Returns the only candidate, exiting with an error listing all of them if there isn't exactly one
+fn pick_one(
+    kind: &str,
+    query: &str,
+    candidates: Vec<usize>,
+    name: impl Fn(usize) -> String,
+) -> usize {
+    match candidates.as_slice() {
+        [] => print_err_and_exit(format!("error: no {} matching {:?}", kind, query)),
+        [idx] => *idx,
+        _ => {
+            eprintln!("error: {:?} matches more than one {}:", query, kind);
+            for idx in candidates {
+                eprintln!("  {}", name(idx));
+            }
+            process::exit(1)
+        }
+    }
+}
+
+/// Arguments shared by all subcommands that read the password
+#[derive(clap::Args)]
+pub struct KeyArgs {
+    /// A keyfile to use in addition to the password
+    #[clap(long, name = "KEYFILE")]
+    keyfile: Option<PathBuf>,
+
+    #[clap(flatten)]
+    password: PasswordArgs,
+}
+
+impl KeyArgs {
+    /// Reads the password, exiting on failure
+    fn read_password(&self, prompt: &str) -> SecretString {
+        (self.password.read(prompt))
+            .map_err(|e| format!("error: failed to read the password: {}", e))
+            .unwrap_or_else(print_err_and_exit)
+    }
+
+    /// Reads a new password, exiting on failure
+    ///
+    /// When prompting for it, the password is asked for twice, exiting if the two don't match.
+    fn read_new_password(&self) -> SecretString {
+        let pwd = self.read_password("Please enter the new encryption key: ");
+        if self.password.non_interactive() {
+            return pwd;
+        }
+
+        let confirm = self.read_password("Please re-enter the new encryption key: ");
+        if pwd != confirm {
+            print_err_and_exit("error: keys do not match; the file has not been changed")
+        }
+
+        pwd
+    }
+
+    /// Unlocks the file and converts it to the current version, exiting on failure
+    ///
+    /// The key is taken from the agent if it has it; otherwise, the password is read. Once the file
+    /// is unlocked with a password, the key derived from it is given to the agent.
+    fn unlock(&self, mut content: Box<dyn FileContent>) -> Box<CurrentFileContent> {
+        self.apply(&mut *content);
+        let pwd = match self.unlock_with_agent(&mut *content) {
+            true => SecretString::default(),
+            false => self.read_password("Please enter the current encryption key: "),
+        };
+
+        let content = content
+            .to_current(&pwd)
+            .map_err(|e| format!("error: {}", e))
+            .unwrap_or_else(print_err_and_exit);
+        crate::agent::store(&*content);
+        content
+    }
+
+    /// Unlocks the file without converting it to the current version, exiting on failure
+    ///
+    /// Like `unlock`, this uses the agent if it has the key.
+    fn unlock_in_place(&self, content: &mut dyn FileContent) {
+        self.apply(content);
+        if self.unlock_with_agent(content) {
+            return;
+        }
+
+        let mut pwd = self.read_password("Please enter the current encryption key: ");
+        content
+            .set_key(take(&mut *pwd))
+            .map_err(|e| format!("error: {}", e))
+            .unwrap_or_else(print_err_and_exit);
+        crate::agent::store(content);
+    }
+
+    /// Tries to unlock the file with a key from the agent, returning whether it was unlocked
+    ///
+    /// The agent isn't used if the password was given explicitly, so that passwords read from a
+    /// file or stdin are still used in the expected order.
+    fn unlock_with_agent(&self, content: &mut dyn FileContent) -> bool {
+        !self.password.given() && crate::agent::unlock(content)
+    }
+
+    /// Reads the contents of the keyfile, if one was given, exiting on failure
+    fn read(&self) -> Option<Vec<u8>> {
+        self.keyfile.as_deref().map(read_keyfile)
+    }
+
+    /// Provides the keyfile to the file content, exiting on failure or if the file requires a
+    /// keyfile that wasn't given
+    fn apply(&self, content: &mut dyn FileContent) {
+        match self.read() {
+            Some(contents) => content
+                .set_keyfile(contents)
+                .map_err(|e| format!("error: {}", e))
+                .unwrap_or_else(print_err_and_exit),
+            None if content.keyfile_required() => {
+                print_err_and_exit("error: This file requires a keyfile to unlock (use --keyfile)")
+            }
+            None => (),
+        }
+    }
+}
+
+/// Arguments shared by subcommands that only need to unlock the file, which may be done with an
+/// identity instead of the password
+#[derive(clap::Args)]
+pub struct UnlockArgs {
+    /// An identity file to unlock with, instead of the password
+    #[clap(long, name = "IDENTITY", conflicts_with = "KEYFILE")]
+    identity: Option<PathBuf>,
+
+    #[clap(flatten)]
+    key: KeyArgs,
+}
+
+impl UnlockArgs {
+    /// Unlocks the file and converts it to the current version, exiting on failure
+    ///
+    /// If no identity was given, this unlocks the file as with `KeyArgs::unlock`.
+    fn unlock(&self, mut content: Box<dyn FileContent>) -> Box<CurrentFileContent> {
+        let path = match self.identity.as_deref() {
+            Some(p) => p,
+            None => return self.key.unlock(content),
+        };
+
+        content
+            .set_identity(&read_identity(path))
+            .map_err(|e| format!("error: {}", e))
+            .unwrap_or_else(print_err_and_exit);
+        content
+            .to_current("")
+            .map_err(|e| format!("error: {}", e))
+            .unwrap_or_else(print_err_and_exit)
+    }
+
+    /// Reads a new password; see `KeyArgs::read_new_password`
+    fn read_new_password(&self) -> SecretString {
+        self.key.read_new_password()
+    }
+
+    /// Returns whether passwords are read from stdin, which then can't be used for anything else
+    fn password_uses_stdin(&self) -> bool {
+        self.identity.is_none() && self.key.password.uses_stdin()
+    }
+
+    /// Unlocks the file without converting it to the current version, exiting on failure
+    ///
+    /// This is used when changes are written back to the file, so that editing a single value
+    /// doesn't also change the file's format.
+    fn unlock_in_place(&self, content: &mut dyn FileContent) {
+        match self.identity.as_deref() {
+            Some(path) => content
+                .set_identity(&read_identity(path))
+                .map_err(|e| format!("error: {}", e))
+                .unwrap_or_else(print_err_and_exit),
+            None => self.key.unlock_in_place(content),
+        }
+    }
+}
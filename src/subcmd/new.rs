@@ -1,7 +1,8 @@
 //! Wrapper module for the interface around creating a new storage file
 
-use super::print_err_and_exit;
-use crate::version::{CurrentFileContent, FileContent};
+use super::{print_err_and_exit, KeyArgs};
+use passman::generate::PassphraseSpec;
+use passman::version::{CurrentFileContent, FileContent};
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
@@ -11,15 +12,36 @@ pub struct Args {
     /// Sets the file to write to
     #[clap(name = "FILE")]
     file_name: PathBuf,
+
+    /// Encrypts the names, tags, and timestamps of entries as well
+    ///
+    /// Without this, entries can be browsed without the encryption key -- only the protected
+    /// values are hidden.
+    #[clap(long)]
+    private_metadata: bool,
+
+    #[clap(flatten)]
+    key: KeyArgs,
 }
 
 pub fn run(args: Args) {
-    let mut file = File::create(&args.file_name).unwrap_or_else(print_err_and_exit);
+    // Read the keyfile and password first, so that we don't leave an empty file behind if either
+    // fails
+    let keyfile = args.key.read();
 
-    let pwd = rpassword::read_password_from_tty(Some("Please enter an encryption key: "))
-        .unwrap_or_else(print_err_and_exit);
+    if !args.key.password.given() {
+        let spec = PassphraseSpec::default();
+        println!(
+            "Suggested passphrase: {} ({:.0} bits of entropy)",
+            spec.generate(),
+            spec.entropy_bits()
+        );
+    }
+
+    let pwd = args.key.read_new_password();
+    let mut file = File::create(&args.file_name).unwrap_or_else(print_err_and_exit);
 
-    let content = CurrentFileContent::make_new(pwd);
+    let content = CurrentFileContent::make_new(&pwd, keyfile, args.private_metadata);
     let as_string = content.write();
 
     file.write_all(as_string.as_ref())
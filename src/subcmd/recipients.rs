@@ -0,0 +1,163 @@
+//! Managing the recipients of a storage file -- the public keys that can unlock it
+
+use super::{print_err_and_exit, UnlockArgs};
+use passman::utils::{self, Base64Vec};
+use passman::version::{self, FileContent, Recipient};
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(clap::Subcommand)]
+enum Cmd {
+    /// Adds a public key as a recipient, so that its identity can unlock the file
+    ///
+    /// Public keys are produced by the keygen subcommand. The file must first be unlocked, either
+    /// with a password or with an identity that's already a recipient.
+    #[clap(name = "add")]
+    Add(AddArgs),
+
+    /// Removes a recipient
+    ///
+    /// The file is re-encrypted under a new key, so that the removed recipient's identity can't be
+    /// used to unlock it any more.
+    #[clap(name = "rm")]
+    Remove(RemoveArgs),
+
+    /// Lists the recipients of a file
+    #[clap(name = "list")]
+    List(ListArgs),
+}
+
+#[derive(clap::Args)]
+struct AddArgs {
+    /// The file to add the recipient to
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The recipient's public key
+    #[clap(name = "PUBKEY")]
+    public_key: String,
+
+    /// A name for the recipient, to help tell it apart from the others
+    #[clap(long)]
+    name: Option<String>,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+struct RemoveArgs {
+    /// The file to remove the recipient from
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The public key or name of the recipient to remove
+    #[clap(name = "RECIPIENT")]
+    recipient: String,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+struct ListArgs {
+    /// The file to list the recipients of
+    #[clap(name = "FILE")]
+    file: PathBuf,
+}
+
+pub fn run(args: Args) {
+    match args.cmd {
+        Cmd::Add(args) => add(args),
+        Cmd::Remove(args) => remove(args),
+        Cmd::List(args) => list(args),
+    }
+}
+
+fn add(args: AddArgs) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+
+    // Check the key before asking for the password, so that mistakes are caught early
+    let recipients = content.recipients().unwrap_or_else(unsupported);
+    let public = base64::decode(args.public_key.trim())
+        .map_err(|_| format!("error: {:?} is not a valid public key", args.public_key))
+        .unwrap_or_else(print_err_and_exit);
+    if find_recipient(recipients, &args.public_key).is_some() {
+        print_err_and_exit("error: that public key is already a recipient")
+    }
+
+    let mut content = args.unlock.unlock(content);
+    let name = args.name.unwrap_or_default();
+    let idx = content
+        .add_recipient(name, Base64Vec(public))
+        .unwrap_or_else(|| {
+            print_err_and_exit(format!(
+                "error: {:?} is not a valid public key",
+                args.public_key
+            ))
+        });
+    utils::write_atomic(&args.file, &content.write()).unwrap_or_else(print_err_and_exit);
+
+    println!("Added recipient {} to {:?}", idx, args.file);
+}
+
+fn remove(args: RemoveArgs) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+
+    let recipients = content.recipients().unwrap_or_else(unsupported);
+    let idx = find_recipient(recipients, &args.recipient).unwrap_or_else(|| {
+        print_err_and_exit(format!("error: no recipient matching {:?}", args.recipient))
+    });
+    let num_slots = content.key_slots().map(|ss| ss.len()).unwrap_or(0);
+    if recipients.len() + num_slots == 1 {
+        print_err_and_exit("error: cannot remove the only recipient of a file without key slots")
+    }
+
+    let mut content = args.unlock.unlock(content);
+    content
+        .remove_recipient(idx)
+        .unwrap_or_else(print_err_and_exit);
+    utils::write_atomic(&args.file, &content.write()).unwrap_or_else(print_err_and_exit);
+
+    println!("Removed recipient {} from {:?}", idx, args.file);
+}
+
+fn list(args: ListArgs) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    let recipients = content.recipients().unwrap_or_else(unsupported);
+
+    for (idx, recipient) in recipients.iter().enumerate() {
+        let name = match recipient.name.is_empty() {
+            true => "<unnamed>",
+            false => &recipient.name,
+        };
+
+        println!(
+            "{}: {} ({})",
+            idx,
+            name,
+            base64::encode(recipient.public_key())
+        );
+    }
+}
+
+/// Finds the recipient given by public key or by name
+fn find_recipient(recipients: &[Recipient], recipient: &str) -> Option<usize> {
+    let public = base64::decode(recipient.trim()).ok();
+
+    recipients.iter().position(|r| {
+        public.as_deref() == Some(r.public_key().as_ref())
+            || (!r.name.is_empty() && r.name == recipient)
+    })
+}
+
+fn unsupported<T>() -> T {
+    print_err_and_exit(
+        "error: recipients are not supported with this file's version; use the 'update' subcommand first",
+    )
+}
@@ -0,0 +1,30 @@
+//! Changing the password of a storage file in place
+
+use super::{print_err_and_exit, KeyArgs};
+use passman::utils;
+use passman::version::{self, FileContent};
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The file to re-encrypt, in place
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    #[clap(flatten)]
+    key: KeyArgs,
+}
+
+pub fn run(args: Args) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    let mut content = args.key.unlock(content);
+
+    let new_pwd = args.key.read_new_password();
+
+    content
+        .change_password(&new_pwd)
+        .unwrap_or_else(print_err_and_exit);
+    utils::write_atomic(&args.file, &content.write()).unwrap_or_else(print_err_and_exit);
+
+    println!("Changed the encryption key for {:?}", args.file);
+}
@@ -0,0 +1,149 @@
+//! Managing the key slots of a storage file
+
+use super::{print_err_and_exit, read_keyfile, UnlockArgs};
+use passman::utils;
+use passman::version::{self, FileContent, KdfParams, KeySlot};
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(clap::Subcommand)]
+enum Cmd {
+    /// Adds a key slot with its own password
+    ///
+    /// The file must first be unlocked, with the password of an existing slot or with an identity
+    /// that's a recipient of the file. The new slot uses the default key derivation parameters;
+    /// use tune-kdf with its password to change them.
+    #[clap(name = "add")]
+    Add(AddArgs),
+
+    /// Removes a key slot
+    ///
+    /// The file is re-encrypted under a new key, so that the removed slot's password can't be
+    /// used to unlock it any more.
+    #[clap(name = "remove")]
+    Remove(RemoveArgs),
+
+    /// Lists the key slots in a file
+    #[clap(name = "list")]
+    List(ListArgs),
+}
+
+#[derive(clap::Args)]
+struct AddArgs {
+    /// The file to add the slot to
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// A name for the slot, to help tell it apart from the others
+    #[clap(long)]
+    name: Option<String>,
+
+    /// A keyfile that the new slot will require, in addition to its password
+    #[clap(long, name = "NEW_KEYFILE")]
+    new_keyfile: Option<PathBuf>,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+struct RemoveArgs {
+    /// The file to remove the slot from
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    /// The index or name of the slot to remove
+    #[clap(name = "SLOT")]
+    slot: String,
+
+    #[clap(flatten)]
+    unlock: UnlockArgs,
+}
+
+#[derive(clap::Args)]
+struct ListArgs {
+    /// The file to list the slots of
+    #[clap(name = "FILE")]
+    file: PathBuf,
+}
+
+pub fn run(args: Args) {
+    match args.cmd {
+        Cmd::Add(args) => add(args),
+        Cmd::Remove(args) => remove(args),
+        Cmd::List(args) => list(args),
+    }
+}
+
+fn add(args: AddArgs) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    let new_keyfile = args.new_keyfile.as_deref().map(read_keyfile);
+    let mut content = args.unlock.unlock(content);
+
+    let new_pwd = args.unlock.read_new_password();
+    let name = args.name.unwrap_or_default();
+    let idx = content
+        .add_slot(name, &new_pwd, new_keyfile.as_deref(), KdfParams::DEFAULT)
+        .unwrap_or_else(print_err_and_exit);
+    utils::write_atomic(&args.file, &content.write()).unwrap_or_else(print_err_and_exit);
+
+    println!("Added key slot {} to {:?}", idx, args.file);
+}
+
+fn remove(args: RemoveArgs) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+
+    // Find the slot before asking for the password, so that mistakes are caught early
+    let slots = content.key_slots().unwrap_or_else(unsupported);
+    let idx = find_slot(slots, &args.slot).unwrap_or_else(|| {
+        print_err_and_exit(format!("error: no key slot matching {:?}", args.slot))
+    });
+    let num_recipients = content.recipients().map(|rs| rs.len()).unwrap_or(0);
+    if slots.len() + num_recipients == 1 {
+        print_err_and_exit("error: cannot remove the only key slot")
+    }
+
+    let mut content = args.unlock.unlock(content);
+
+    content.remove_slot(idx).unwrap_or_else(print_err_and_exit);
+    utils::write_atomic(&args.file, &content.write()).unwrap_or_else(print_err_and_exit);
+
+    println!("Removed key slot {} from {:?}", idx, args.file);
+}
+
+fn list(args: ListArgs) {
+    let (content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    let slots = content.key_slots().unwrap_or_else(unsupported);
+
+    for (idx, slot) in slots.iter().enumerate() {
+        let name = match slot.name.is_empty() {
+            true => "<unnamed>",
+            false => &slot.name,
+        };
+        let keyfile = match slot.keyfile_required {
+            true => ", requires keyfile",
+            false => "",
+        };
+
+        println!("{}: {} ({}{})", idx, name, slot.kdf, keyfile);
+    }
+}
+
+/// Finds the slot given by index or by name
+fn find_slot(slots: &[KeySlot], slot: &str) -> Option<usize> {
+    match slot.parse::<usize>() {
+        Ok(idx) if idx < slots.len() => Some(idx),
+        _ => slots.iter().position(|s| s.name == slot),
+    }
+}
+
+fn unsupported<T>() -> T {
+    print_err_and_exit(
+        "error: key slots are not supported with this file's version; use the 'update' subcommand first",
+    )
+}
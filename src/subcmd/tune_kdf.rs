@@ -0,0 +1,115 @@
+//! Tuning the key derivation parameters of a storage file to the local machine
+
+use super::{print_err_and_exit, KeyArgs};
+use argon2::password_hash::SaltString;
+use passman::utils;
+use passman::version::{self, FileContent, KdfParams};
+use rand_core::OsRng;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// The file to re-encrypt, in place
+    #[clap(name = "FILE")]
+    file: PathBuf,
+
+    #[clap(flatten)]
+    key: KeyArgs,
+
+    /// The target time to take when unlocking the file, in milliseconds
+    #[clap(long, default_value = "1000")]
+    target_ms: u64,
+
+    /// The maximum amount of memory to use when unlocking the file, in MiB
+    #[clap(long, default_value = "1024")]
+    max_memory: u32,
+}
+
+// The smallest memory cost we'll start from, in MiB
+const MIN_MEMORY: u32 = 8;
+
+pub fn run(args: Args) {
+    if args.max_memory < MIN_MEMORY {
+        print_err_and_exit(format!(
+            "error: --max-memory must be at least {} MiB",
+            MIN_MEMORY
+        ))
+    }
+    if args.max_memory > KdfParams::MAX_M_COST / 1024 {
+        print_err_and_exit(format!(
+            "error: --max-memory must be at most {} MiB",
+            KdfParams::MAX_M_COST / 1024
+        ))
+    }
+
+    let (mut content, _warning) = version::parse(&args.file).unwrap_or_else(print_err_and_exit);
+    args.key.apply(&mut *content);
+
+    let pwd = args.key.read_password("Please enter the encryption key: ");
+    let mut content = (content.to_current(&pwd))
+        .map_err(|e| format!("error: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+
+    println!("Benchmarking key derivation for {} ms...", args.target_ms);
+    let target = Duration::from_millis(args.target_ms);
+    let (kdf, elapsed) = tune(target, args.max_memory.saturating_mul(1024));
+    println!("Selected {} (took {} ms)", kdf, elapsed.as_millis());
+
+    (content.rekey(&pwd, kdf))
+        .map_err(|e| format!("error: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+    utils::write_atomic(&args.file, &content.write())
+        .map_err(|e| format!("error: failed to write {:?}: {}", args.file, e))
+        .unwrap_or_else(print_err_and_exit);
+
+    println!("Re-encrypted {:?} with the new parameters", args.file);
+}
+
+/// Picks the KDF parameters that come closest to the target time without exceeding it, returning
+/// them alongside the time they took
+///
+/// If even the smallest parameters take longer than the target, those are returned instead.
+///
+/// `max_m_cost` is given in KiB.
+fn tune(target: Duration, max_m_cost: u32) -> (KdfParams, Duration) {
+    let mut kdf = KdfParams {
+        t_cost: 1,
+        m_cost: MIN_MEMORY * 1024,
+        p_cost: 1,
+        ..KdfParams::DEFAULT
+    };
+    let mut elapsed = time_kdf(&kdf);
+
+    // Memory is what makes brute-forcing with dedicated hardware expensive, so we raise that
+    // first -- doubling it for as long as a single pass still fits within the target.
+    while elapsed * 2 <= target && kdf.m_cost <= max_m_cost / 2 {
+        kdf.m_cost *= 2;
+        elapsed = time_kdf(&kdf);
+    }
+
+    // Then use up any remaining time with extra passes, which each take about as long as the
+    // first.
+    let passes = ((target.as_secs_f64() / elapsed.as_secs_f64()) as u32).min(KdfParams::MAX_T_COST);
+    if passes > 1 {
+        kdf.t_cost = passes;
+        elapsed = time_kdf(&kdf);
+
+        // Passes don't take exactly as long as the first, so we might have gone over
+        while elapsed > target && kdf.t_cost > 1 {
+            kdf.t_cost -= 1;
+            elapsed = time_kdf(&kdf);
+        }
+    }
+
+    (kdf, elapsed)
+}
+
+fn time_kdf(kdf: &KdfParams) -> Duration {
+    let salt = SaltString::generate(&mut OsRng);
+    let start = Instant::now();
+    kdf.hash_key(salt.as_salt(), "benchmark", None)
+        .map_err(|e| format!("error: {}", e))
+        .unwrap_or_else(print_err_and_exit);
+    start.elapsed()
+}
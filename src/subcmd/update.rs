@@ -1,7 +1,7 @@
 //! Tools for updating a storage file
 
-use super::print_err_and_exit;
-use crate::version::{self, FileContent};
+use super::{print_err_and_exit, KeyArgs};
+use passman::version::{self, FileContent};
 use std::fs::File;
 use std::io::{self, Write};
 use std::path::PathBuf;
@@ -15,20 +15,21 @@ pub struct Args {
     /// Sets the output file to write to
     #[clap(short, long)]
     output: PathBuf,
+
+    #[clap(flatten)]
+    key: KeyArgs,
 }
 
 pub fn run(args: Args) {
-    let (content, _warning) = version::parse(&args.input);
+    let (mut content, _warning) = version::parse(&args.input).unwrap_or_else(print_err_and_exit);
+    args.key.apply(&mut *content);
 
-    let pwd = rpassword::read_password_from_tty(Some("Please enter the encryption key: "))
-        .unwrap_or_else(print_err_and_exit);
-    let output_content = content.to_current(pwd);
+    let pwd = args.key.read_password("Please enter the encryption key: ");
+    let output_content = content.to_current(&pwd);
 
     let () = File::create(args.output)
         .and_then(|mut f| {
-            let s = output_content
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "decryption failed"))?
-                .write();
+            let s = output_content.map_err(io::Error::other)?.write();
             write!(f, "{}", s).and_then(|_| f.flush())
         })
         .unwrap_or_else(print_err_and_exit);
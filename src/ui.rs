@@ -1,18 +1,25 @@
 //! Displays the current state of the `App`
 
-use crate::app::{App, CommandKind, EntrySelectState, ModifyFieldState, NewValueKind, SelectState};
-use crate::utils;
-use crate::version::{GetValueError, ValueKind};
-use std::io::{self, Stdout};
+use crate::app::{
+    self, App, CommandKind, EntrySelectState, ModifyFieldState, NewValueKind, SelectState,
+};
+use passman::strength::{self, Strength};
+use passman::utils;
+use passman::version::{GetValueError, ValueKind};
+use signal_hook::consts::SIGTSTP;
+use std::io::{self, Stdout, Write};
+use std::mem;
 use std::sync::atomic::Ordering::Release;
-use termion::raw::{IntoRawMode, RawTerminal};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use termion::{clear, cursor, screen};
 use tui::backend::TermionBackend;
 use tui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use tui::style::{Color, Modifier, Style};
 use tui::text::{Span, Spans};
 use tui::widgets::{self, Block, Borders, Paragraph};
 
-type Backend = TermionBackend<RawTerminal<Stdout>>;
+type Backend = TermionBackend<Stdout>;
 type Terminal = tui::Terminal<Backend>;
 type Frame<'a> = tui::terminal::Frame<'a, Backend>;
 
@@ -37,19 +44,114 @@ const fn default_style() -> Style {
     }
 }
 
+/// The state of the terminal from before it was set up, so that it can be restored
+///
+/// This is kept outside of the `Terminal`, because it's also restored by the panic hook and on
+/// fatal signals, neither of which have access to it.
+struct SavedTerm {
+    original: libc::termios,
+    // Whether we're currently in raw mode on the alternate screen
+    active: bool,
+}
+
+static SAVED_TERM: Mutex<Option<SavedTerm>> = Mutex::new(None);
+
 /// Performs the necessary setup for drawing to the screen
 ///
-/// This should only be run once and before ever calling [`draw`].
+/// This should only be run once and before ever calling [`draw`]. Everything is drawn on the
+/// alternate screen, so that none of it is left in the terminal's scrollback once we exit. Refer
+/// to [`restore_term`] for switching back.
 pub fn setup_term() -> io::Result<Terminal> {
-    let stdout = io::stdout().into_raw_mode()?;
-    let backend = TermionBackend::new(stdout);
+    enter_term()?;
+    let backend = TermionBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
     terminal.clear()?;
     Ok(terminal)
 }
 
+/// Puts the terminal into raw mode and switches to the alternate screen
+fn enter_term() -> io::Result<()> {
+    {
+        let mut saved = SAVED_TERM.lock().unwrap_or_else(PoisonError::into_inner);
+        let saved = match saved.as_mut() {
+            Some(s) => s,
+            None => {
+                // SAFETY: `termios` is plain data, and is valid for writes
+                let mut original = unsafe { mem::zeroed() };
+                if unsafe { libc::tcgetattr(libc::STDOUT_FILENO, &mut original) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                saved.insert(SavedTerm {
+                    original,
+                    active: false,
+                })
+            }
+        };
+
+        let mut raw = saved.original;
+        // SAFETY: `raw` is a valid termios, as given by `tcgetattr`
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        saved.active = true;
+    }
+
+    // The lock has to be released before writing, so that it's never held at the same time as the
+    // lock on stdout -- which a fatal signal may be holding onto while it restores the terminal.
+    let mut stdout = io::stdout();
+    write!(stdout, "{}{}", screen::ToAlternateScreen, cursor::Hide)?;
+    stdout.flush()
+}
+
+/// Clears the alternate screen, switches back to the main one and restores the terminal's original
+/// settings
+///
+/// This does nothing if the terminal isn't set up, so it can be called on every exit path -- more
+/// than once, or before [`setup_term`]. Errors are ignored, because there's nothing more we could
+/// do about them.
+pub fn restore_term() {
+    {
+        let mut saved = SAVED_TERM.lock().unwrap_or_else(PoisonError::into_inner);
+        let saved = match saved.as_mut() {
+            Some(s) if s.active => s,
+            _ => return,
+        };
+
+        // SAFETY: `original` is a valid termios, as given by `tcgetattr`
+        unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, &saved.original) };
+        saved.active = false;
+    }
+
+    // Clearing first means that revealed values aren't kept around in the alternate screen either
+    let mut stdout = io::stdout();
+    let _ = write!(
+        stdout,
+        "{}{}{}",
+        clear::All,
+        cursor::Show,
+        screen::ToMainScreen
+    );
+    let _ = stdout.flush();
+}
+
+/// Stops the process, as if we hadn't handled SIGTSTP, restoring the terminal while we're stopped
+///
+/// This only returns once we've been continued, at which point everything is redrawn.
+pub fn suspend(term: &mut Terminal) -> io::Result<()> {
+    restore_term();
+    let _ = signal_hook::low_level::emulate_default_handler(SIGTSTP);
+    resume(term)
+}
+
+/// Sets up the terminal again after we've been stopped, and redraws everything
+pub fn resume(term: &mut Terminal) -> io::Result<()> {
+    enter_term()?;
+    term.clear()
+}
+
 pub fn draw(term: &mut Terminal, app: &App) -> io::Result<()> {
-    term.draw(|mut f| {
+    term.draw(|f| {
         // The general layout of the UI can be represented by this diagram:
         //   +-----+---------------------------------+-----+
         //   |     |                                 |saved|
@@ -85,16 +187,21 @@ pub fn draw(term: &mut Terminal, app: &App) -> io::Result<()> {
             vec![Constraint::Min(30), Constraint::Length(26)],
         );
 
+        // The status has an extra line for the time until the file is locked, if it will be
+        let status_height = match app.time_until_lock() {
+            Some(_) => 5,
+            None => 4,
+        };
         let options_chunks = vertical_chunks(
             main_chunks[1],
-            vec![Constraint::Length(4), Constraint::Min(0)],
+            vec![Constraint::Length(status_height), Constraint::Min(0)],
         );
 
-        render_entries(&mut f, outer_chunks[0], app);
-        render_cmd(&mut f, cmd_chunks[1], app);
-        render_main(&mut f, main_chunks[0], app);
-        render_status(&mut f, options_chunks[0], app);
-        render_options(&mut f, options_chunks[1], app);
+        render_entries(f, outer_chunks[0], app);
+        render_cmd(f, cmd_chunks[1], app);
+        render_main(f, main_chunks[0], app);
+        render_status(f, options_chunks[0], app);
+        render_options(f, options_chunks[1], app);
 
         // In addition to the above, we'll also render a pop-up if it's there
         if let SelectState::PopUp {
@@ -104,7 +211,7 @@ pub fn draw(term: &mut Terminal, app: &App) -> io::Result<()> {
         } = &app.selected
         {
             let rect = f.size();
-            render_popup(&mut f, rect, header, message, *border_color);
+            render_popup(f, rect, header, message, *border_color);
         }
     })?;
 
@@ -146,6 +253,20 @@ fn render_entries(f: &mut Frame, rect: Rect, app: &App) {
         .border_style(style);
 
     let num_entries = app.entries.num_entries();
+
+    // If the names of entries are encrypted, there's nothing to show yet
+    if app.entries.metadata_locked() {
+        let text = vec![
+            Spans::from(Span::raw(format!("🔒 {} locked entries", num_entries))),
+            Spans::from(Span::raw("")),
+            Spans::from(Span::raw("Use ':unlock' to view")),
+        ];
+
+        let paragraph = Paragraph::new(text).block(block).alignment(Alignment::Left);
+        f.render_widget(paragraph, rect);
+        return;
+    }
+
     let entries_list = match app.filter.as_ref() {
         None => app.entries.entries_range(start_row..num_entries),
         Some(list) => list.iter().map(|&i| app.entries.entry(i)).collect(),
@@ -204,15 +325,17 @@ fn render_cmd(f: &mut Frame, rect: Rect, app: &App) {
                 (ModifyFieldState::Name, NewValueKind::Manual) => "Editing standard field name",
                 (ModifyFieldState::Name, NewValueKind::Totp) => "Editing TOTP field name",
                 (ModifyFieldState::ManualValue { protected: false }, _) => {
-                    "Editing basic field value"
+                    "Editing basic field value (generate with 'Ctrl+g', or 'Ctrl+p' for words)"
                 }
                 (ModifyFieldState::ManualValue { protected: true }, _) => {
-                    "Editing protected field value"
+                    "Editing protected field value (generate with 'Ctrl+g', or 'Ctrl+p' for words)"
                 }
                 (ModifyFieldState::TotpIssuer, _) => "Editing TOTP field issuer",
                 (ModifyFieldState::TotpSecret { .. }, _) => "Editing TOTP field secret",
             },
             CommandKind::Decrypt { .. } => "Decryption key",
+            CommandKind::ChangePassword { first: None, .. } => "New password",
+            CommandKind::ChangePassword { first: Some(_), .. } => "Confirm new password",
         },
         _ => {
             f.render_widget(Block::default().borders(Borders::ALL), rect);
@@ -224,7 +347,7 @@ fn render_cmd(f: &mut Frame, rect: Rect, app: &App) {
         SelectState::BottomCommand {
             value, as_stars, ..
         } => match *as_stars {
-            false => Span::raw(value),
+            false => Span::raw(value.as_str()),
             true => Span::raw("*".repeat(value.len())),
         },
         _ => unreachable!(),
@@ -239,7 +362,33 @@ fn render_cmd(f: &mut Frame, rect: Rect, app: &App) {
         .bg(Color::White)
         .add_modifier(Modifier::SLOW_BLINK);
 
-    let paragraph = Paragraph::new(Spans(vec![span, Span::styled(" ", cursor_style)]))
+    let mut spans = vec![span, Span::styled(" ", cursor_style)];
+
+    // Warn about weak values as they're typed in, so that they can be changed before saving
+    if let SelectState::BottomCommand {
+        kind:
+            CommandKind::ModifyField {
+                state: ModifyFieldState::ManualValue { protected: true },
+                ..
+            },
+        value,
+        ..
+    } = &app.selected
+    {
+        let strength = strength::estimate(value);
+        if !value.is_empty() && strength.is_weak() {
+            spans.push(Span::styled(
+                format!(
+                    "  {} password: {}",
+                    strength.label(),
+                    strength.warning.unwrap_or_default()
+                ),
+                default_style().fg(WARNING_COLOR),
+            ));
+        }
+    }
+
+    let paragraph = Paragraph::new(Spans(spans))
         .block(block)
         .alignment(Alignment::Left);
 
@@ -252,8 +401,8 @@ fn render_main(f: &mut Frame, rect: Rect, app: &App) {
         _ => (default_style(), None),
     };
 
-    let entry = match app.displayed_entry_idx {
-        Some(idx) => app.entries.entry(idx),
+    let (entry_idx, entry) = match app.displayed_entry_idx {
+        Some(idx) => (idx, app.entries.entry(idx)),
         None => {
             // If there's no entry selected, we'll just display that there isn't one
             let block = Block::default()
@@ -310,6 +459,8 @@ fn render_main(f: &mut Frame, rect: Rect, app: &App) {
         selected == Some(Tags),
     ));
 
+    let revealed = app.revealed_field();
+
     for idx in 0..entry.num_fields() {
         let is_selected = selected == Some(Field { idx });
         let field = entry.field(idx);
@@ -320,22 +471,55 @@ fn render_main(f: &mut Frame, rect: Rect, app: &App) {
             ValueKind::Totp => ("⏳", true),
         };
 
-        let value = if is_selected || !is_protected {
-            field.value().unwrap_or_else(|e| match e {
-                GetValueError::ContentsNotUnlocked => PROTECTED_STR.to_owned(),
-                GetValueError::Decrypt(_) => "<BAD CRYPT>".to_owned(),
-                GetValueError::BadTotpSecret => "<BAD TOTP SECRET>".to_owned(),
+        // The time left on a TOTP code is shown separately, so that the code itself isn't copied
+        let mut suffix = None;
+
+        let value = if revealed == Some(idx) || !is_protected {
+            let value = field.value();
+            // TOTP codes change over time, so we need to keep redrawing while one's displayed
+            if let (Ok(_), ValueKind::Totp) = (&value, field.value_kind()) {
+                let unix_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                // TOTP works with 30-second time slices
+                let secs_remaining = 30 - unix_time % 30;
+                suffix = Some(format!("  (00:{secs_remaining:02} remaining)"));
+                app::send_refresh_tick_after_1_second();
+            }
+
+            value.unwrap_or_else(|e| match e {
+                GetValueError::ContentsNotUnlocked => PROTECTED_STR.to_owned().into(),
+                GetValueError::Decrypt(_) => "<BAD CRYPT>".to_owned().into(),
+                GetValueError::BadTotpSecret => "<BAD TOTP SECRET>".to_owned().into(),
             })
         } else {
-            PROTECTED_STR.to_owned()
+            PROTECTED_STR.to_owned().into()
         };
 
-        text.push(styled(
+        let mut line = styled(
             prefix,
             format!("{}: ", field.name()),
-            value,
+            value.as_str(),
             is_selected,
-        ));
+        );
+        if let Some(s) = suffix {
+            line.0.push(Span::raw(s));
+        }
+
+        // Protected values get a rating of how easy they'd be to guess, once we can see them
+        if let Some(rating) = app.rating(entry_idx, idx) {
+            line.0.push(Span::raw("  "));
+            line.0.push(strength_bar(rating.strength));
+            if rating.breached {
+                line.0.push(Span::styled(
+                    "  found in breaches",
+                    default_style().fg(ERROR_COLOR),
+                ));
+            }
+        }
+
+        text.push(line);
     }
 
     text.push(styled("", "", "[+]", selected == Some(Plus)));
@@ -370,6 +554,26 @@ fn render_main(f: &mut Frame, rect: Rect, app: &App) {
     f.render_widget(paragraph, rect);
 }
 
+/// Returns a short bar showing the strength of a password, colored by how good it is
+fn strength_bar(strength: Strength) -> Span<'static> {
+    let filled = strength.score as usize + 1;
+    let color = match strength.score {
+        0 | 1 => ERROR_COLOR,
+        2 => WARNING_COLOR,
+        _ => Color::Green,
+    };
+
+    Span::styled(
+        format!(
+            "{}{} {}",
+            "▮".repeat(filled),
+            "▯".repeat(5 - filled),
+            strength.label()
+        ),
+        default_style().fg(color),
+    )
+}
+
 fn render_status(f: &mut Frame, rect: Rect, app: &App) {
     const NO_CHAR: char = '◇';
     const YES_CHAR: char = '◆';
@@ -384,11 +588,22 @@ fn render_status(f: &mut Frame, rect: Rect, app: &App) {
     let decrypted = format!("{} Decrypted", status_char(app.entries.decrypted()));
     let unsaved = format!("{} Unsaved", status_char(app.entries.unsaved()));
 
-    let text = vec![
+    let mut text = vec![
         Spans::from(Span::raw(decrypted)),
         Spans::from(Span::raw(unsaved)),
     ];
 
+    if let Some(left) = app.time_until_lock() {
+        let secs = left.as_secs();
+        text.push(Spans::from(Span::raw(format!(
+            "Locks in {}:{:02}",
+            secs / 60,
+            secs % 60
+        ))));
+        // Keep the countdown up to date, and make sure that we lock once it's done
+        app::send_refresh_tick_after_1_second();
+    }
+
     let paragraph = Paragraph::new(text)
         .block(Block::default().title("Status").borders(Borders::ALL))
         .alignment(Alignment::Left);
@@ -397,7 +612,7 @@ fn render_status(f: &mut Frame, rect: Rect, app: &App) {
 }
 
 fn render_options(f: &mut Frame, rect: Rect, app: &App) {
-    use CommandKind::{Command, Decrypt, ModifyEntryMeta, ModifyField, Search};
+    use CommandKind::{ChangePassword, Command, Decrypt, ModifyEntryMeta, ModifyField, Search};
 
     #[rustfmt::skip]
     let (normal, moves): (&[_], &[_]) = match app.selected {
@@ -406,6 +621,7 @@ fn render_options(f: &mut Frame, rect: Rect, app: &App) {
             kind: Search { return_to_main: true, ..  }
                 | Command { return_to_main: true, ..  }
                 | Decrypt { return_to_main: true, ..  }
+                | ChangePassword { return_to_main: true, ..  }
                 | ModifyEntryMeta
                 | ModifyField { .. },
             ..
@@ -416,6 +632,8 @@ fn render_options(f: &mut Frame, rect: Rect, app: &App) {
                 "New entry:    ':new'",
                 "Decrypt:      ':unlock'",
                 "              ':decrypt'",
+                "Change key:   ':passwd'",
+                "Lock:         ':lock'",
                 "Delete entry: ':delete'",
                 "Exit:         ':q(uit)'",
                 "Force-exit:   ':q(uit)!'",
@@ -425,9 +643,12 @@ fn render_options(f: &mut Frame, rect: Rect, app: &App) {
                 "Exit:           'q'",
                 "Search:         '/'",
                 "Delete field:   'd'",
+                "Reveal value:   'r'",
+                "Copy value:     'y'",
                 "Swap encrypt:   's'",
                 "Add field:      '+'",
                 "Add TOTP field: 't'",
+                "Suspend:        'Ctrl+z'",
             ],
             &[
                 " ---- movement ---- ",
@@ -441,7 +662,8 @@ fn render_options(f: &mut Frame, rect: Rect, app: &App) {
         | SelectState::BottomCommand {
             kind: Search { return_to_main: false, ..  }
                 | Command { return_to_main: false }
-                | Decrypt { return_to_main: false, ..  },
+                | Decrypt { return_to_main: false, ..  }
+                | ChangePassword { return_to_main: false, ..  },
             ..
         } => (
             &[
@@ -449,6 +671,8 @@ fn render_options(f: &mut Frame, rect: Rect, app: &App) {
                 "New entry:  ':new'",
                 "Decrypt:    ':unlock'",
                 "            ':decrypt'",
+                "Change key: ':passwd'",
+                "Lock:       ':lock'",
                 "Exit:       ':q(uit)'",
                 "Force-exit: ':q(uit)!'",
                 "Write:      ':w(rite)'",
@@ -456,6 +680,7 @@ fn render_options(f: &mut Frame, rect: Rect, app: &App) {
                 " --- single keys --- ",
                 "Exit:         'q'",
                 "Search:       '/'",
+                "Suspend:      'Ctrl+z'",
             ],
             &[
                 " --- movement --- ",
@@ -520,7 +745,7 @@ fn render_popup(
         .iter()
         .map(|line| line.len())
         .max()
-        .unwrap_or_else(|| header.len());
+        .unwrap_or(header.len());
     let width = max_length as u16 + 2;
     let horiz_margin = total_rect.width.saturating_sub(width) / 2;
     let horiz = horizontal_chunks(
@@ -541,8 +766,7 @@ fn render_popup(
 
     let text = message
         .iter()
-        .map(|line| textwrap::wrap(line, rect.width.saturating_sub(2) as usize))
-        .flatten()
+        .flat_map(|line| textwrap::wrap(line, rect.width.saturating_sub(2) as usize))
         .map(|line| Spans::from(Span::raw(line)))
         .collect::<Vec<_>>();
     let paragraph = Paragraph::new(text)
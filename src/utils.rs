@@ -1,13 +1,16 @@
 //! Various standalone utilities and helper functions
 
 use chrono::{DateTime, Local};
-use lazy_static::lazy_static;
 use serde::{de::Error, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+use rand::{thread_rng, Rng};
 use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::sync::{mpsc, Mutex};
-use std::thread;
-use std::time::{Duration, SystemTime};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub fn format_time(time: SystemTime) -> String {
     let time: DateTime<Local> = time.into();
@@ -27,13 +30,82 @@ pub fn comma_strings<S: AsRef<str>>(strs: &[S]) -> String {
     result
 }
 
-pub fn escape_quotes(s: &str) -> Cow<str> {
+pub fn escape_quotes(s: &str) -> Cow<'_, str> {
     match s.contains('"') {
-        true => Cow::Owned(s.replace('"', "\"")),
+        true => Cow::Owned(s.replace('"', "\\\"")),
         false => Cow::Borrowed(s),
     }
 }
 
+/// Writes the contents to the file at `path`, replacing it atomically
+///
+/// The contents are first written to a temporary file in the same directory, which is then renamed
+/// over the original. A failure part-way through will never leave a partially-written file.
+///
+/// The temporary file is always newly created and only readable by the current user until it's
+/// given the original's permissions, so nothing else can open it while the contents are written.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::other("path has no file name"))?;
+    let (tmp_path, mut file) = create_temp(path, file_name)?;
+
+    let result = (|| {
+        if let Ok(meta) = fs::metadata(path) {
+            file.set_permissions(meta.permissions())?;
+        }
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    // The rename itself is only durable once the directory has been synced as well
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Creates the temporary file for [`write_atomic`], next to `path`
+///
+/// The first choice of name is `.<file_name>.tmp`; if that's already taken (e.g. left over from a
+/// crash, or by someone else entirely), a random suffix is added instead of touching it.
+fn create_temp(path: &Path, file_name: &OsStr) -> io::Result<(PathBuf, File)> {
+    const ATTEMPTS: usize = 16;
+
+    for attempt in 0..ATTEMPTS {
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(file_name);
+        if attempt != 0 {
+            tmp_name.push(format!(".{:08x}", thread_rng().gen::<u32>()));
+        }
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let opened = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path);
+        match opened {
+            Ok(f) => return Ok((tmp_path, f)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "failed to find an unused temporary file name",
+    ))
+}
+
 /// A wrapper around a `Vec<u8>` so that we can serialize and deserialize it as base-64 encoded
 #[derive(Debug, Clone)]
 pub struct Base64Vec(pub Vec<u8>);
@@ -70,34 +142,3 @@ impl<'de> Visitor<'de> for Base64VecVisitor {
         base64::decode(s).map(Base64Vec).map_err(E::custom)
     }
 }
-
-lazy_static! {
-    static ref TIMER_THREAD_TX: Mutex<mpsc::Sender<()>> = Mutex::new(make_timer_thread());
-}
-
-/// Orchestrates sending a timer tick after one second, signalling the app to refresh
-pub fn send_refresh_tick_after_1_second() {
-    let _ = TIMER_THREAD_TX.lock().unwrap().send(());
-}
-
-fn make_timer_thread() -> mpsc::Sender<()> {
-    let (tx, rx) = mpsc::channel();
-
-    let signal_tx = crate::app::SIGNAL_TX
-        .lock()
-        .unwrap()
-        .as_ref()
-        .cloned()
-        .expect("app hasn't been initialized");
-
-    thread::spawn(move || {
-        while let Ok(()) = rx.recv() {
-            thread::sleep(Duration::from_secs(1));
-            // Handle any other buildup, but don't wait.
-            while let Ok(()) = rx.try_recv() {}
-            let _ = signal_tx.send(None);
-        }
-    });
-
-    tx
-}
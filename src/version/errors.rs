@@ -1,7 +1,47 @@
 //! All the errors generated & used by different file format versions
 
+use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
+/// An error resulting from `parse`
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("failed to read file {path:?}: {err}")]
+    Read { path: PathBuf, err: io::Error },
+
+    #[error("failed to parse file: {0}")]
+    Yaml(serde_yaml::Error),
+
+    #[error(
+        "unrecognized file version, should be one of: {}",
+        supported_versions()
+    )]
+    UnknownVersion,
+
+    #[error("this file uses the {version} format, which requires passman to be built with the '{feature}' feature")]
+    FeatureDisabled {
+        version: &'static str,
+        feature: &'static str,
+    },
+}
+
+/// Lists the versions that can be parsed, leaving out those whose features are disabled
+fn supported_versions() -> String {
+    let versions = [
+        ("v0.2", cfg!(feature = "format-v0_2")),
+        ("v0.3", cfg!(feature = "format-v0_3")),
+        ("v0.4", true),
+        ("v0.5", true),
+    ];
+
+    let enabled = (versions.iter())
+        .filter(|(_, enabled)| *enabled)
+        .map(|(v, _)| format!("'{}'", v))
+        .collect::<Vec<_>>();
+    format!("[{}]", enabled.join(", "))
+}
+
 #[derive(Debug, Error)]
 #[error("Encryption failed")]
 pub struct EncryptError;
@@ -13,6 +53,27 @@ pub enum DecryptError {
 
     #[error("Decryption result gave non UTF-8 bytes (likely incorrect key?)")]
     BadUtf8,
+
+    #[error("File integrity check failed; its contents may have been tampered with")]
+    BadMac,
+
+    #[error("The file's key derivation parameters are invalid")]
+    BadKdfParams,
+
+    #[error("This file requires a keyfile to unlock")]
+    KeyfileRequired,
+
+    #[error("This file does not use a keyfile")]
+    KeyfileNotUsed,
+
+    #[error("This identity is not a recipient of the file")]
+    NotARecipient,
+
+    #[error("Unlocking with an identity is not supported with your current file version")]
+    IdentitiesUnsupported,
+
+    #[error("Unlocking with a cached key is not supported with your current file version")]
+    DerivedKeysUnsupported,
 }
 
 #[derive(Debug, Error)]
@@ -55,6 +116,22 @@ pub enum SwapEncryptionError {
     IsTotp,
 }
 
+/// An error resulting from `FileContent::change_password`
+#[derive(Debug, Error)]
+pub enum ChangePasswordError {
+    #[error("Contents have not been decrypted")]
+    ContentsNotUnlocked,
+
+    #[error("Changing the password in place is not supported with your current file version")]
+    Unsupported,
+
+    #[error("The file was not unlocked with a password")]
+    NoPassword,
+
+    #[error("{0}")]
+    Decrypt(DecryptError),
+}
+
 impl From<DecryptError> for GetValueError {
     fn from(e: DecryptError) -> Self {
         GetValueError::Decrypt(e)
@@ -72,3 +149,15 @@ impl From<DecryptError> for SwapEncryptionError {
         SwapEncryptionError::Decrypt(e)
     }
 }
+
+impl From<DecryptError> for ChangePasswordError {
+    fn from(e: DecryptError) -> Self {
+        ChangePasswordError::Decrypt(e)
+    }
+}
+
+impl From<serde_yaml::Error> for ParseError {
+    fn from(e: serde_yaml::Error) -> Self {
+        ParseError::Yaml(e)
+    }
+}
@@ -3,58 +3,242 @@
 //! We import everything from current version - as if it's just part of that file as well.
 
 // Just use everything from the current version:
-use super::v0_4::*;
+use super::v0_5::*;
 
-use super::errors::DecryptError;
+use super::errors::{ChangePasswordError, DecryptError};
 use super::{Keyed, PlaintextContent, PlaintextEntry, PlaintextField, PlaintextValue};
 use crate::utils::Base64Vec;
 use argon2::password_hash::SaltString;
-use rand::{thread_rng, Rng};
 use rand_core::OsRng;
+use zeroize::Zeroizing;
 
 impl Keyed<FileContent> {
-    /// Creates a new `FileContent` with the given password
-    pub fn make_new(pwd: String) -> Self {
-        Self::from_plaintext(pwd, PlaintextContent::init())
+    /// Creates a new `FileContent` with the given password (and keyfile contents, if provided)
+    ///
+    /// If `private_metadata` is true, the names, tags, and timestamps of entries will be stored
+    /// encrypted.
+    pub fn make_new(pwd: &str, keyfile: Option<Vec<u8>>, private_metadata: bool) -> Self {
+        let mut this = Self::from_plaintext(pwd, keyfile, PlaintextContent::init());
+        this.content.private_metadata = private_metadata;
+        this
     }
 
     /// Produces a `FileContent` from the plaintext content
+    ///
+    /// The file starts with a single key slot. If the contents of a keyfile are given, that slot
+    /// will require it.
     #[rustfmt::skip]
-    pub fn from_plaintext(pwd: String, content: PlaintextContent) -> Self {
-        let pwd_salt = SaltString::generate(&mut OsRng); // Have to use OsRng here for CSPRNG
-        let iv = thread_rng().gen::<[u8; 16]>();
-
-        let hashed_key = hash_key(pwd_salt.as_salt(), &pwd);
-        let token = encrypt(ENCRYPT_TOKEN, &iv, &hashed_key);
+    pub fn from_plaintext(
+        pwd: &str,
+        keyfile: Option<Vec<u8>>,
+        content: PlaintextContent,
+    ) -> Self {
+        let keyfile = keyfile.map(Zeroizing::new);
+        let keyfile_ref = keyfile.as_ref().map(|k| k.as_slice());
+        let data_key = new_data_key();
+        let slot = KeySlot::new(String::new(), pwd, keyfile_ref, KdfParams::DEFAULT, &data_key)
+            .expect("unrecoverable error: default KDF parameters are invalid");
 
-        Keyed::new(FileContent {
+        let mut this = Keyed::new(FileContent {
             version: VERSION_STR.to_owned(),
-            token: Base64Vec(token),
-            iv: Base64Vec(iv.to_vec()),
-            salt: pwd_salt.as_str().to_owned(),
+            slots: vec![slot],
+            recipients: Vec::new(),
+            private_metadata: false,
             last_update: content.last_update,
             inner: content.entries.into_iter().map(|e| Entry {
                 name: e.name,
                 tags: e.tags,
                 first_added: e.first_added,
                 last_update: e.last_update,
+                meta: None,
                 fields: e.fields.into_iter().map(|f| Field {
                     name: f.name,
                     value: match f.value {
-                        PlaintextValue::Manual { value, protected: false } => Value::Basic(value),
+                        PlaintextValue::Manual { value, protected: false } => {
+                            Value::Basic(value.as_str().to_owned())
+                        }
                         PlaintextValue::Manual { value, protected: true } => {
                             Value::Protected(
-                                Base64Vec(encrypt(value.as_bytes(), &iv, &hashed_key))
+                                Base64Vec(encrypt(value.as_bytes(), &data_key))
                             )
                         },
                         PlaintextValue::Totp { issuer, secret } => {
-                            let secret = Base64Vec(encrypt(secret.as_bytes(), &iv, &hashed_key));
+                            let secret = Base64Vec(encrypt(secret.as_bytes(), &data_key));
                             Value::Totp { issuer, secret }
                         }
                     },
                 }).collect()
             }).collect(),
-        })
+            // The MAC is always recomputed on write, so we don't need to set it here
+            mac: Base64Vec(Vec::new()),
+            saved_bytes: None,
+            keyfile,
+            unlocked_slot: Some(0),
+            unlocked_secret: None,
+        });
+
+        this.key = Some(data_key.into());
+        this
+    }
+
+    /// Replaces the password of the slot the file was unlocked with, deriving its key with the
+    /// given KDF parameters
+    ///
+    /// A new salt is always generated, but the keyfile (if the slot requires one) stays the same.
+    /// The data key is also rotated; refer to `rotate_data_key`. This method should only ever be
+    /// called once a key has been supplied, and returns an error if it wasn't from a password.
+    pub fn rekey(&mut self, pwd: &str, kdf: KdfParams) -> Result<(), ChangePasswordError> {
+        let idx = (self.content.unlocked_slot).ok_or(ChangePasswordError::NoPassword)?;
+        let slot = &self.content.slots[idx];
+
+        // Without the keyfile (e.g. when unlocked by the agent), the new slot would silently stop
+        // requiring one, so we have to refuse instead.
+        let keyfile = match (slot.keyfile_required, &self.content.keyfile) {
+            (true, Some(k)) => Some(k.as_slice()),
+            (true, None) => return Err(DecryptError::KeyfileRequired.into()),
+            (false, _) => None,
+        };
+        let data_key = self.key.as_ref().unwrap();
+
+        // The new slot wraps the current data key, so the content is still consistent if rotating
+        // the data key fails.
+        self.content.slots[idx] = KeySlot::new(slot.name.clone(), pwd, keyfile, kdf, data_key)?;
+        self.content.unlocked_secret = None;
+        Ok(self.rotate_data_key()?)
+    }
+
+    /// Adds a new key slot with the given password, returning its index
+    ///
+    /// This method should only ever be called once a key has been supplied.
+    pub fn add_slot(
+        &mut self,
+        name: String,
+        pwd: &str,
+        keyfile: Option<&[u8]>,
+        kdf: KdfParams,
+    ) -> Result<usize, DecryptError> {
+        let data_key = self
+            .key
+            .as_ref()
+            .expect("`add_slot` called without supplied key");
+        let slot = KeySlot::new(name, pwd, keyfile, kdf, data_key)?;
+
+        self.content.slots.push(slot);
+        self.unsaved = true;
+        Ok(self.content.slots.len() - 1)
+    }
+
+    /// Removes the key slot at the given index, rotating the data key so that the slot's password
+    /// can't be used to unlock the new version of the file
+    ///
+    /// This method should only ever be called once a key has been supplied.
+    ///
+    /// ## Panics
+    ///
+    /// This method panics if `idx` is out of bounds, or if it's the only slot remaining.
+    pub fn remove_slot(&mut self, idx: usize) -> Result<(), DecryptError> {
+        assert!(
+            self.content.slots.len() + self.content.recipients.len() > 1,
+            "cannot remove the only way to unlock the file"
+        );
+
+        self.content.slots.remove(idx);
+        self.content.unlocked_slot = match self.content.unlocked_slot {
+            Some(i) if i == idx => None,
+            Some(i) if i > idx => Some(i - 1),
+            other => other,
+        };
+        if self.content.unlocked_slot.is_none() {
+            self.content.unlocked_secret = None;
+        }
+
+        self.rotate_data_key()
+    }
+
+    /// Adds the public key as a recipient, returning its index
+    ///
+    /// Returns `None` if the public key is invalid. This method should only ever be called once a
+    /// key has been supplied.
+    pub fn add_recipient(&mut self, name: String, public: Base64Vec) -> Option<usize> {
+        let data_key = self
+            .key
+            .as_ref()
+            .expect("`add_recipient` called without supplied key");
+
+        let wrapped = WrappedKey::wrap(data_key, public)?;
+        self.content.recipients.push(Recipient { name, wrapped });
+        self.unsaved = true;
+        Some(self.content.recipients.len() - 1)
+    }
+
+    /// Removes the recipient, rotating the data key so that its identity can no longer unlock the
+    /// file
+    ///
+    /// Like `remove_slot`, this panics if it would leave no way to unlock the file. This method
+    /// should only ever be called once a key has been supplied.
+    pub fn remove_recipient(&mut self, idx: usize) -> Result<(), DecryptError> {
+        assert!(
+            self.content.slots.len() + self.content.recipients.len() > 1,
+            "cannot remove the only way to unlock the file"
+        );
+
+        self.content.recipients.remove(idx);
+        self.rotate_data_key()
+    }
+
+    /// Replaces the data key with a new one, re-encrypting all protected values and TOTP secrets
+    /// and re-wrapping the key for every slot and recipient
+    ///
+    /// Entry metadata (including timestamps) is left unchanged. This method should only ever be
+    /// called once a key has been supplied. If any value fails to decrypt, an error is returned and
+    /// the content is left as it was.
+    pub fn rotate_data_key(&mut self) -> Result<(), DecryptError> {
+        let old_key = self
+            .key
+            .as_ref()
+            .expect("`rotate_data_key` called without supplied key");
+        let new_key = new_data_key();
+
+        // Re-encrypt everything before replacing any of it, so that we don't leave the content
+        // half-converted on error.
+        let mut new_values = Vec::new();
+        for field in self.content.inner.iter().flat_map(|e| &e.fields) {
+            match &field.value {
+                Value::Basic(_) => (),
+                Value::Protected(bs) | Value::Totp { secret: bs, .. } => {
+                    let value = decrypt(bs.as_ref(), old_key).ok_or(DecryptError::BadCrypt)?;
+                    new_values.push(Base64Vec(encrypt(&value, &new_key)));
+                }
+            }
+        }
+
+        let publics = (self.content.slots.iter().map(|s| &s.wrapped.public))
+            .chain(self.content.recipients.iter().map(|r| &r.wrapped.public));
+        let mut new_wrapped = Vec::new();
+        for public in publics {
+            let wrapped = WrappedKey::wrap(&new_key, public.clone());
+            new_wrapped.push(wrapped.ok_or(DecryptError::BadCrypt)?);
+        }
+
+        let mut new_values = new_values.into_iter();
+        for field in self.content.inner.iter_mut().flat_map(|e| &mut e.fields) {
+            match &mut field.value {
+                Value::Basic(_) => (),
+                Value::Protected(bs) | Value::Totp { secret: bs, .. } => {
+                    *bs = new_values.next().unwrap();
+                }
+            }
+        }
+
+        let wrapped_keys = (self.content.slots.iter_mut().map(|s| &mut s.wrapped))
+            .chain(self.content.recipients.iter_mut().map(|r| &mut r.wrapped));
+        for (wrapped, new) in wrapped_keys.zip(new_wrapped) {
+            *wrapped = new;
+        }
+
+        self.key = Some(new_key.into());
+        self.unsaved = true;
+        Ok(())
     }
 
     /// Produces the `PlaintextContent` corresponding to the data contained here
@@ -62,9 +246,8 @@ impl Keyed<FileContent> {
     /// This method should only ever be called once a key has been supplied. A return of `Err`
     /// indicates that the decryption key was incorrect.
     #[rustfmt::skip]
-    pub fn to_plaintext(self) -> Result<PlaintextContent, DecryptError> {
-        let key = self.key.as_ref().expect("`to_plaintext` called without supplied key");
-        let iv = self.content.iv.as_ref();
+    pub fn into_plaintext(self) -> Result<PlaintextContent, DecryptError> {
+        let key = self.key.as_ref().expect("`into_plaintext` called without supplied key");
 
         Ok(PlaintextContent {
             last_update: self.content.last_update,
@@ -77,14 +260,14 @@ impl Keyed<FileContent> {
                     name: f.name,
                     value: match f.value {
                         Value::Basic(s) => {
-                            PlaintextValue::Manual { value: s, protected: false }
+                            PlaintextValue::Manual { value: s.into(), protected: false }
                         }
                         Value::Protected(bs) => {
-                            let value = decrypt_string(bs.as_ref(), iv, key)?;
+                            let value = decrypt_string(bs.as_ref(), key)?;
                             PlaintextValue::Manual { value, protected: true }
                         }
                         Value::Totp { issuer, secret } => {
-                            let secret = decrypt_string(secret.as_ref(), iv, key)?;
+                            let secret = decrypt_string(secret.as_ref(), key)?;
                             PlaintextValue::Totp { issuer, secret }
                         }
                     }
@@ -93,3 +276,26 @@ impl Keyed<FileContent> {
         })
     }
 }
+
+impl KeySlot {
+    /// Creates a new key slot for the password (and keyfile, if given), wrapping the data key
+    pub fn new(
+        name: String,
+        pwd: &str,
+        keyfile: Option<&[u8]>,
+        kdf: KdfParams,
+        data_key: &[u8],
+    ) -> Result<Self, DecryptError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let secret = kdf.hash_key(salt.as_salt(), pwd, keyfile)?;
+        let public = WrappedKey::public_key(&secret);
+
+        Ok(KeySlot {
+            name,
+            salt: salt.as_str().to_owned(),
+            kdf,
+            keyfile_required: keyfile.is_some(),
+            wrapped: WrappedKey::wrap(data_key, public).expect("freshly-generated key is invalid"),
+        })
+    }
+}
@@ -1,14 +1,16 @@
 //! Abstraction over multiple versions of the file format allowed
 //!
 //! Because we want to continue to properly handle old config file formats - even when they'll no
-//! longer be generated by default. In the future, we might provide some kind of feature-gating for
-//! older versions, so that the dependencies associated only with them don't inflate compile times.
+//! longer be generated by default. Support for the oldest versions can be compiled out by disabling
+//! the `format-v0_2` and `format-v0_3` features (both on by default), so that the dependencies
+//! associated only with them don't inflate compile times -- and so that hardened builds can refuse
+//! deprecated formats entirely.
 //!
 //! All of the submodules here correspond to a particular version -- with the current one also
 //! included. Each submodule defines a `FileContent` struct that implements the [`FileContent`]
 //! trait in this module, so that we can cleanly abstract over the file format without changing the
-//! logic in `crate::app`. The `FileContent` from the latest version is re-exported in this module
-//! as [`CurrentFileContent`].
+//! logic in the app. The `FileContent` from the latest version is re-exported in this module as
+//! [`CurrentFileContent`].
 //!
 //! Each submodule additionally defines a couple items:
 //! ```ignore
@@ -16,35 +18,38 @@
 //! pub const WARNING: Option<Warning> = ...;
 //!
 //! // Given a file that we know starts with the correct version prefix, parse it
-//! //
-//! // If any errors are encountered, print them and exit.
-//! pub fn parse(file_content: String) -> FileContent { ... }
+//! pub fn parse(file_content: String) -> Result<FileContent, ParseError> { ... }
 //! ```
 //! Those are used by the `parse` function at the bottom of this file.
 
+use crate::secret::{LockedKey, SecretString};
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::fmt::{self, Display, Formatter};
 use std::fs::read_to_string;
 use std::ops::Range;
 use std::path::Path;
-use std::process::exit;
 use std::time::SystemTime;
 
 mod errors;
 mod latest;
+#[cfg(feature = "format-v0_2")]
 mod v0_2;
+#[cfg(feature = "format-v0_3")]
 mod v0_3;
 mod v0_4;
+mod v0_5;
 
 pub use errors::*;
+// Key derivation parameters, key slots and recipients are only available for the current version
+pub use v0_5::{Identity, KdfParams, KeySlot, Recipient};
 
 /// Helper struct for file contents with an attached key
 ///
 /// This is extracted into this module so that it's able to be used by multiple submodules without
 /// redefinition.
 pub struct Keyed<C> {
-    key: Option<Vec<u8>>,
+    key: Option<LockedKey>,
     unsaved: bool,
     content: C,
 }
@@ -70,7 +75,10 @@ pub trait FileContent {
     /// It's customary for this method to only convert to the next version internally, and instead
     /// rely upon that version's implementation of producing the current file content. This chain
     /// terminates with with the implementation for `CurrentFileContent`, which just returns itself.
-    fn to_current(self: Box<Self>, pwd: String) -> Result<Box<CurrentFileContent>, DecryptError>;
+    ///
+    /// If the contents have already been unlocked -- e.g. with `set_identity` -- the password is
+    /// ignored.
+    fn to_current(self: Box<Self>, pwd: &str) -> Result<Box<CurrentFileContent>, DecryptError>;
 
     /// Provides the string that the file content should be written as
     ///
@@ -78,9 +86,95 @@ pub trait FileContent {
     /// handling needs to be done within the implementation itself.
     fn write(&self) -> String;
 
+    /// Returns true if writing changes to the file requires that the key has been given
+    ///
+    /// This is the case for formats that store a MAC over the file's contents. The default
+    /// implementation returns false.
+    fn write_requires_key(&self) -> bool {
+        false
+    }
+
+    /// Provides the contents of the keyfile, to be used by later calls to `set_key`
+    ///
+    /// Returns `Err(DecryptError::KeyfileNotUsed)` if the file doesn't require a keyfile, which is
+    /// what the default implementation does.
+    fn set_keyfile(&mut self, _contents: Vec<u8>) -> Result<(), DecryptError> {
+        Err(DecryptError::KeyfileNotUsed)
+    }
+
+    /// Returns true if a keyfile is required to unlock the file, in addition to the password
+    ///
+    /// The default implementation returns false.
+    fn keyfile_required(&self) -> bool {
+        false
+    }
+
     /// Sets the key, returning `Err` if it was invalid
     fn set_key(&mut self, key: String) -> Result<(), DecryptError>;
 
+    /// Sets the key using an identity, returning `Err` if the identity isn't one of the file's
+    /// recipients
+    ///
+    /// The default implementation returns `Err(DecryptError::IdentitiesUnsupported)`.
+    fn set_identity(&mut self, _identity: &Identity) -> Result<(), DecryptError> {
+        Err(DecryptError::IdentitiesUnsupported)
+    }
+
+    /// Returns identifiers for each of the keys derived from a password that could unlock the
+    /// file, for looking up previously-derived keys
+    ///
+    /// Deriving the key from the password is deliberately slow, so this allows keys to be cached
+    /// (e.g. by the agent) and later given to `set_derived_key`. The default implementation
+    /// returns an empty list.
+    fn derived_key_ids(&self) -> Vec<&str> {
+        Vec::new()
+    }
+
+    /// Returns the identifier and value of the derived key that the file was unlocked with, if it
+    /// was unlocked with a password
+    ///
+    /// The default implementation returns `None`.
+    fn derived_key(&self) -> Option<(&str, &[u8])> {
+        None
+    }
+
+    /// Unlocks the file with a key previously returned by `derived_key`
+    ///
+    /// The default implementation returns `Err(DecryptError::DerivedKeysUnsupported)`.
+    fn set_derived_key(&mut self, _id: &str, _key: &[u8]) -> Result<(), DecryptError> {
+        Err(DecryptError::DerivedKeysUnsupported)
+    }
+
+    /// Forgets the key, so that protected values (and private metadata) can't be read until the
+    /// file is unlocked again
+    ///
+    /// Unsaved changes are kept, but can't be written until then.
+    fn lock(&mut self);
+
+    /// Changes the password, re-encrypting all protected values under the new key
+    ///
+    /// The contents must already have been decrypted. The change is registered as unsaved, like
+    /// any other. The default implementation returns `Err(ChangePasswordError::Unsupported)`.
+    fn change_password(&mut self, _new_pwd: &str) -> Result<(), ChangePasswordError> {
+        Err(ChangePasswordError::Unsupported)
+    }
+
+    /// Returns the key slots that can be used to unlock the file, or `None` if this version doesn't
+    /// support them
+    ///
+    /// The default implementation returns `None`.
+    fn key_slots(&self) -> Option<&[KeySlot]> {
+        None
+    }
+
+    /// Returns the public keys that can be used to unlock the file, or `None` if this version
+    /// doesn't support them
+    ///
+    /// The default implementation returns `None`.
+    fn recipients(&self) -> Option<&[Recipient]> {
+        None
+    }
+
     /// Returns true if there have been changes made to the file without saving
     ///
     /// Changes should be registered as unsaved until a call to `saved` is made
@@ -92,6 +186,15 @@ pub trait FileContent {
     /// Returns whether the entries have been decrypted -- true after `set_key` returns `Ok`
     fn decrypted(&self) -> bool;
 
+    /// Returns true if the names, tags, and timestamps of entries are encrypted and not yet
+    /// available
+    ///
+    /// While this is the case, entries are still accessible, but their metadata will just be
+    /// placeholder values. The default implementation returns false.
+    fn metadata_locked(&self) -> bool {
+        false
+    }
+
     /// Produces the number of entries in the file
     fn num_entries(&self) -> usize;
 
@@ -195,7 +298,7 @@ pub trait FieldRef {
     ///
     /// For TOTP fields, this is expected to perform the necessary calculations and return the
     /// current OTP.
-    fn value(&self) -> Result<String, GetValueError>;
+    fn value(&self) -> Result<SecretString, GetValueError>;
 
     /// Returns the "plaintext" value of the field
     ///
@@ -251,46 +354,52 @@ pub trait FieldBuilder: Any {
 }
 
 /// The latest version of the file content -- the most recent implementor of [`FileContent`]
-pub type CurrentFileContent = Keyed<v0_4::FileContent>;
+pub type CurrentFileContent = Keyed<v0_5::FileContent>;
 
 /// A warning given after opening a file with a particular format version
 pub struct Warning {
     pub reason: &'static str,
 }
 
-/// Parses a `FileContent` from the provided file, exiting the program on failure
-pub fn parse(file: &Path) -> (Box<dyn FileContent>, Option<Warning>) {
-    let content = match read_to_string(file) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("failed to read file {:?}: {}", file.to_string_lossy(), e);
-            exit(1);
-        }
-    };
+/// Parses a `FileContent` from the provided file
+pub fn parse(file: &Path) -> Result<(Box<dyn FileContent>, Option<Warning>), ParseError> {
+    let content = read_to_string(file).map_err(|err| ParseError::Read {
+        path: file.to_owned(),
+        err,
+    })?;
+
+    parse_str(content)
+}
 
+/// Parses a `FileContent` from the contents of a file
+pub fn parse_str(content: String) -> Result<(Box<dyn FileContent>, Option<Warning>), ParseError> {
+    // Each arm may have attributes, so that versions can be conditionally compiled
     macro_rules! prefix_match {
-        ($val:expr => { $($str:literal => $arm:expr,)* _ => $else_arm:expr, }) => {{
+        ($val:expr => { $($(#[$attr:meta])* $str:literal => $arm:expr,)* _ => $else_arm:expr, }) => {{
             let v = $val;
-            $(if v.starts_with($str) {
-                $arm
-            } else)* {
-                $else_arm
-            }
+            $($(#[$attr])* if v.starts_with($str) {
+                return $arm;
+            })*
+            $else_arm
         }};
     }
 
     prefix_match!(content.as_str() => {
-        "---\nversion: v0.2\n" => (Box::new(v0_2::parse(content)), v0_2::WARNING),
-        "---\nversion: v0.3\n" => (Box::new(v0_3::parse(content)), v0_3::WARNING),
-        "---\nversion: v0.4\n" => (Box::new(v0_4::parse(content)), v0_4::WARNING),
-        _ => {
-            eprintln!("unrecognized file version, should be one of: ['v0.2', 'v0.3', 'v0.4']");
-            exit(1)
-        },
+        #[cfg(feature = "format-v0_2")]
+        "---\nversion: v0.2\n" => Ok((Box::new(v0_2::parse(content)?), v0_2::WARNING)),
+        #[cfg(not(feature = "format-v0_2"))]
+        "---\nversion: v0.2\n" => Err(ParseError::FeatureDisabled { version: "v0.2", feature: "format-v0_2" }),
+        #[cfg(feature = "format-v0_3")]
+        "---\nversion: v0.3\n" => Ok((Box::new(v0_3::parse(content)?), v0_3::WARNING)),
+        #[cfg(not(feature = "format-v0_3"))]
+        "---\nversion: v0.3\n" => Err(ParseError::FeatureDisabled { version: "v0.3", feature: "format-v0_3" }),
+        "---\nversion: v0.4\n" => Ok((Box::new(v0_4::parse(content)?), v0_4::WARNING)),
+        "---\nversion: v0.5\n" => Ok((Box::new(v0_5::parse(content)?), v0_5::WARNING)),
+        _ => Err(ParseError::UnknownVersion),
     })
 }
 
-/// Return type for [`CurrentFileContent::to_plaintext`]
+/// Return type for [`CurrentFileContent::into_plaintext`]
 ///
 /// This is used both to convert between `FileContent` versions *and* to within the
 /// `emit-plaintext` and `from-plaintext` subcommands.
@@ -317,8 +426,14 @@ struct PlaintextField {
 
 #[derive(Serialize, Deserialize)]
 pub enum PlaintextValue {
-    Manual { value: String, protected: bool },
-    Totp { secret: String, issuer: String },
+    Manual {
+        value: SecretString,
+        protected: bool,
+    },
+    Totp {
+        secret: SecretString,
+        issuer: String,
+    },
 }
 
 impl PlaintextContent {
@@ -1,10 +1,11 @@
 //! Version 0.2 of the file format
 
 use super::{
-    CurrentFileContent, DecryptError, GetValueError, Keyed, PlaintextContent, PlaintextEntry,
-    PlaintextField, PlaintextValue, SetFieldError, SwapEncryptionError, UnsupportedFeature,
-    ValueKind, Warning,
+    CurrentFileContent, DecryptError, GetValueError, Keyed, ParseError, PlaintextContent,
+    PlaintextEntry, PlaintextField, PlaintextValue, SetFieldError, SwapEncryptionError,
+    UnsupportedFeature, ValueKind, Warning,
 };
+use crate::secret::{self, SecretBytes, SecretString};
 use crate::utils::Base64Vec;
 use aes::Aes256;
 use block_modes::block_padding::Pkcs7;
@@ -13,8 +14,8 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::any::Any;
 use std::mem::take;
-use std::process::exit;
 use std::time::SystemTime;
+use zeroize::Zeroizing;
 
 pub const WARNING: Option<Warning> = Some(Warning {
     // There's actually multiple reasons -- primarily that individually-encrypted entries aren't
@@ -24,23 +25,18 @@ pub const WARNING: Option<Warning> = Some(Warning {
 
 static VERSION_STR: &str = "v0.2";
 
-pub fn parse(file_content: String) -> Keyed<FileContent> {
-    match serde_yaml::from_str::<FileContent>(&file_content) {
-        Ok(c) => {
-            assert!(c.version == VERSION_STR);
-            Keyed::new(c)
-        }
-        Err(e) => {
-            eprintln!("failed to parse file: {}", e);
-            exit(1);
-        }
+pub fn parse(file_content: String) -> Result<Keyed<FileContent>, ParseError> {
+    let c = serde_yaml::from_str::<FileContent>(&file_content)?;
+    if c.version != VERSION_STR {
+        return Err(ParseError::UnknownVersion);
     }
+    Ok(Keyed::new(c))
 }
 
-fn hash_key(key: &str) -> [u8; 32] {
+fn hash_key(key: &str) -> SecretBytes {
     let mut hasher = Sha256::new();
     hasher.update(key.as_bytes());
-    hasher.finalize().into()
+    Zeroizing::new(hasher.finalize().to_vec())
 }
 
 fn encrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
@@ -48,14 +44,14 @@ fn encrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
     cipher.encrypt_vec(val)
 }
 
-fn decrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Option<Vec<u8>> {
-    let cipher = <Cbc<Aes256, Pkcs7>>::new_from_slices(&key, iv).unwrap();
-    cipher.decrypt_vec(val).ok()
+fn decrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Option<SecretBytes> {
+    let cipher = <Cbc<Aes256, Pkcs7>>::new_from_slices(key, iv).unwrap();
+    cipher.decrypt_vec(val).ok().map(Zeroizing::new)
 }
 
-fn decrypt_string(val: &[u8], iv: &[u8], key: &[u8]) -> Result<String, DecryptError> {
+fn decrypt_string(val: &[u8], iv: &[u8], key: &[u8]) -> Result<SecretString, DecryptError> {
     let bytes = decrypt(val, iv, key).ok_or(DecryptError::BadCrypt)?;
-    String::from_utf8(bytes).map_err(|_| DecryptError::BadUtf8)
+    secret::into_string(bytes).ok_or(DecryptError::BadUtf8)
 }
 
 static ENCRYPT_TOKEN: &[u8] = "encryption token ☺".as_bytes();
@@ -91,12 +87,9 @@ enum Value {
 }
 
 impl super::FileContent for Keyed<FileContent> {
-    fn to_current(
-        mut self: Box<Self>,
-        pwd: String,
-    ) -> Result<Box<CurrentFileContent>, DecryptError> {
+    fn to_current(mut self: Box<Self>, pwd: &str) -> Result<Box<CurrentFileContent>, DecryptError> {
         if !self.decrypted() {
-            self.set_key(pwd.clone())?;
+            self.set_key(pwd.to_owned())?;
         }
 
         let key = self.key.as_ref().unwrap();
@@ -114,7 +107,7 @@ impl super::FileContent for Keyed<FileContent> {
                     name: f.name,
                     value: match f.value {
                         Value::Basic(s) => {
-                            PlaintextValue::Manual { value: s, protected: false }
+                            PlaintextValue::Manual { value: s.into(), protected: false }
                         }
                         Value::Protected(bs) => {
                             let value = decrypt_string(bs.as_ref(), iv, key)?;
@@ -125,7 +118,9 @@ impl super::FileContent for Keyed<FileContent> {
             })).collect::<Result<_, _>>()?,
         };
 
-        Ok(Box::new(CurrentFileContent::from_plaintext(pwd, content)))
+        Ok(Box::new(CurrentFileContent::from_plaintext(
+            pwd, None, content,
+        )))
     }
 
     fn write(&self) -> String {
@@ -134,6 +129,7 @@ impl super::FileContent for Keyed<FileContent> {
     }
 
     fn set_key(&mut self, key: String) -> Result<(), DecryptError> {
+        let key = Zeroizing::new(key);
         let hashed = hash_key(&key);
 
         // Check that decrypting the token produces the correct value
@@ -151,6 +147,10 @@ impl super::FileContent for Keyed<FileContent> {
         }
     }
 
+    fn lock(&mut self) {
+        self.key = None;
+    }
+
     fn unsaved(&self) -> bool {
         self.unsaved
     }
@@ -172,7 +172,7 @@ impl super::FileContent for Keyed<FileContent> {
             entry: &self.content.inner[idx],
             crypt: CryptStateRef {
                 iv: self.content.iv.as_ref(),
-                key: self.key.as_ref().map(|vec| vec.as_slice()),
+                key: self.key.as_deref(),
             },
         })
     }
@@ -182,7 +182,7 @@ impl super::FileContent for Keyed<FileContent> {
             entry: &mut self.content.inner[idx],
             crypt: CryptStateRef {
                 iv: self.content.iv.as_ref(),
-                key: self.key.as_ref().map(|vec| vec.as_slice()),
+                key: self.key.as_deref(),
             },
             unsaved: &mut self.unsaved,
             global_update: &mut self.content.last_update,
@@ -320,7 +320,7 @@ impl<'a> super::EntryMut for EntryMut<'a> {
         let is_protected = b.is_protected.expect("no is_protected set in builder");
 
         let value = match (is_protected, self.crypt.key) {
-            (true, _) => Value::Basic(value),
+            (true, _) => Value::Basic(value.as_str().to_owned()),
             (false, Some(k)) => {
                 let encrypted = encrypt(value.as_bytes(), self.crypt.iv, k);
                 Value::Protected(Base64Vec(encrypted))
@@ -372,9 +372,9 @@ macro_rules! impl_field_ref {
                 }
             }
 
-            fn value(&self) -> Result<String, GetValueError> {
+            fn value(&self) -> Result<SecretString, GetValueError> {
                 match (&self.field.value, self.crypt.key) {
-                    (Value::Basic(s), _) => Ok(s.clone()),
+                    (Value::Basic(s), _) => Ok(s.clone().into()),
                     (Value::Protected(_), None) => Err(GetValueError::ContentsNotUnlocked),
                     (Value::Protected(bs), Some(k)) => {
                         Ok(decrypt_string(bs.as_ref(), self.crypt.iv, k)?)
@@ -420,7 +420,10 @@ impl<'a> super::FieldMut for FieldMut<'a> {
                 let bs = encrypt(s.as_bytes(), self.crypt.iv, key);
                 Value::Protected(Base64Vec(bs))
             }
-            Value::Protected(bs) => Value::Basic(decrypt_string(bs.as_ref(), self.crypt.iv, key)?),
+            Value::Protected(bs) => {
+                let value = decrypt_string(bs.as_ref(), self.crypt.iv, key)?;
+                Value::Basic(value.as_str().to_owned())
+            }
         };
 
         self.field.value = new_val;
@@ -431,7 +434,7 @@ impl<'a> super::FieldMut for FieldMut<'a> {
 
 struct FieldBuilder {
     name: Option<String>,
-    value: Option<String>,
+    value: Option<SecretString>,
     is_protected: Option<bool>,
 }
 
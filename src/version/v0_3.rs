@@ -1,133 +1,32 @@
 //! Version 0.3 of the file format
 
 use super::{
-    CurrentFileContent, DecryptError, GetValueError, Keyed, PlaintextValue, SetFieldError,
-    SwapEncryptionError, UnsupportedFeature, ValueKind, Warning,
+    CurrentFileContent, DecryptError, GetValueError, Keyed, ParseError, PlaintextValue,
+    SetFieldError, SwapEncryptionError, UnsupportedFeature, ValueKind, Warning,
 };
+use crate::secret::SecretString;
 use crate::utils::Base64Vec;
-use aes::Aes256;
 use argon2::password_hash::Salt;
-use argon2::{Argon2, PasswordHasher};
-use block_modes::block_padding::Pkcs7;
-use block_modes::{BlockMode, Cbc};
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::mem::take;
-use std::process::exit;
 use std::time::SystemTime;
+use zeroize::Zeroizing;
 
 pub const WARNING: Option<Warning> = None;
 
 pub static VERSION_STR: &str = "v0.3";
 
-pub fn parse(file_content: String) -> Keyed<FileContent> {
-    match serde_yaml::from_str::<FileContent>(&file_content) {
-        Ok(c) => {
-            assert!(c.version == VERSION_STR);
-            Keyed::new(c)
-        }
-        Err(e) => {
-            eprintln!("failed to parse file: {}", e);
-            exit(1);
-        }
-    }
-}
-
-// Returns the parameters we use for the hasher
-fn argon_params() -> argon2::Params {
-    // Number of passes. 5 passes for now - can be adjusted later
-    const T_COST: u32 = 5;
-    // Memory cost, in KBytes. ~1GB
-    const M_COST: u32 = 1_000_000;
-    // Number of parallel lanes to use. This version of the argon2 library (0.2) doesn't actually
-    // implement the speed increase from parallel lanes.
-    const PARALLEL: u32 = 1;
-
-    let mut builder = argon2::ParamsBuilder::new();
-    builder.t_cost(T_COST).unwrap();
-    builder.m_cost(M_COST).unwrap();
-    builder.p_cost(PARALLEL).unwrap();
-    builder.params().unwrap()
-}
-
-pub fn hash_key(salt: Salt, key: &str) -> Vec<u8> {
-    let hasher = Argon2::new(
-        argon2::Algorithm::Argon2id,
-        argon2::Version::V0x13,
-        argon_params(),
-    );
-
-    let hash = hasher
-        .hash_password(key.as_bytes(), &salt)
-        .unwrap()
-        .hash
-        .unwrap();
-
-    hash.as_bytes().to_vec()
-}
-
-// The bounds on salt length in "protected" fields
-//
-// We can encode the length of the salt in the first four bits of the contents, by adding 16 to it
-// afterwards.
-const SALT_MIN_LENGTH: usize = 17;
-const SALT_MAX_LENGTH: usize = 32;
-
-pub static ENCRYPT_TOKEN: &[u8] = "encryption token ☺".as_bytes();
-
-pub fn encrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
-    // Use a random length salt before the value. If the value is too short (i.e. < 17 bytes),
-    // we'll increase the minimum length of the salt so that we always get outputs ≥ 32 bytes.
-    //
-    // This is to make it harder for an outside observer to find short passwords.
-
-    let min_salt_len = SALT_MAX_LENGTH
-        .saturating_sub(val.len())
-        .max(SALT_MIN_LENGTH);
-
-    let mut rng = thread_rng();
-
-    // Easiest to just generate with constants and maybe not use all of it.
-    let mut max_len_salt: [u8; SALT_MAX_LENGTH] = rng.gen();
-
-    let salt_len = rng.gen_range(min_salt_len..=SALT_MAX_LENGTH);
-    let salt = &mut max_len_salt[..salt_len];
-
-    encrypt_with_salt(val, salt, iv, key)
-}
+// The password hash & encryption are the same as in v0.4, so they're defined there and imported
+// here:
+use super::v0_4::{decrypt, decrypt_string, encrypt, hash_key, ENCRYPT_TOKEN};
 
-pub fn encrypt_with_salt(val: &[u8], salt: &mut [u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
-    // Encode the length of the salt into its first bit:
-    debug_assert!(SALT_MAX_LENGTH - SALT_MIN_LENGTH == 15);
-    assert!(SALT_MIN_LENGTH <= salt.len() && salt.len() <= SALT_MAX_LENGTH);
-    let len_byte = (salt.len() - SALT_MIN_LENGTH) as u8;
-    salt[0] = salt[0] & 0xF0 | len_byte;
-
-    // Collect the salt + value into the vector to encrypt
-    let mut full = Vec::with_capacity(salt.len() + val.len());
-    full.extend_from_slice(salt);
-    full.extend_from_slice(val);
-
-    let cipher = <Cbc<Aes256, Pkcs7>>::new_from_slices(key, iv).unwrap();
-    cipher.encrypt_vec(&full)
-}
-
-pub fn decrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Option<Vec<u8>> {
-    let cipher = <Cbc<Aes256, Pkcs7>>::new_from_slices(key, iv).unwrap();
-    let mut decrypted = cipher.decrypt_vec(val).ok()?;
-
-    // Refer to the construction in `encrypt`
-    let salt_len = (decrypted[0] & 0x0F) as usize + SALT_MIN_LENGTH;
-    assert!(SALT_MIN_LENGTH <= salt_len && salt_len <= SALT_MAX_LENGTH);
-
-    decrypted.drain(..salt_len);
-    Some(decrypted)
-}
-
-pub fn decrypt_string(val: &[u8], iv: &[u8], key: &[u8]) -> Result<String, DecryptError> {
-    let bytes = decrypt(val, iv, key).ok_or(DecryptError::BadCrypt)?;
-    String::from_utf8(bytes).map_err(|_| DecryptError::BadUtf8)
+pub fn parse(file_content: String) -> Result<Keyed<FileContent>, ParseError> {
+    let c = serde_yaml::from_str::<FileContent>(&file_content)?;
+    if c.version != VERSION_STR {
+        return Err(ParseError::UnknownVersion);
+    }
+    Ok(Keyed::new(c))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -162,7 +61,7 @@ pub enum Value {
 }
 
 impl super::FileContent for Keyed<FileContent> {
-    fn to_current(self: Box<Self>, pwd: String) -> Result<Box<CurrentFileContent>, DecryptError> {
+    fn to_current(self: Box<Self>, pwd: &str) -> Result<Box<CurrentFileContent>, DecryptError> {
         // Because v0.3 uses the same password hash & encryption as v0.4, we can go directly
         // instead of passing through plaintext first:
         use super::v0_4;
@@ -201,6 +100,7 @@ impl super::FileContent for Keyed<FileContent> {
     }
 
     fn set_key(&mut self, key: String) -> Result<(), DecryptError> {
+        let key = Zeroizing::new(key);
         let hashed = hash_key(Salt::new(&self.content.salt).unwrap(), &key);
 
         let decrypted_token = decrypt(
@@ -217,6 +117,10 @@ impl super::FileContent for Keyed<FileContent> {
         }
     }
 
+    fn lock(&mut self) {
+        self.key = None;
+    }
+
     fn unsaved(&self) -> bool {
         self.unsaved
     }
@@ -238,7 +142,7 @@ impl super::FileContent for Keyed<FileContent> {
             entry: &self.content.inner[idx],
             crypt: CryptStateRef {
                 iv: self.content.iv.as_ref(),
-                key: self.key.as_ref().map(|vec| vec.as_slice()),
+                key: self.key.as_deref(),
             },
         })
     }
@@ -248,7 +152,7 @@ impl super::FileContent for Keyed<FileContent> {
             entry: &mut self.content.inner[idx],
             crypt: CryptStateRef {
                 iv: self.content.iv.as_ref(),
-                key: self.key.as_ref().map(|vec| vec.as_slice()),
+                key: self.key.as_deref(),
             },
             unsaved: &mut self.unsaved,
             global_update: &mut self.content.last_update,
@@ -386,7 +290,7 @@ impl<'a> super::EntryMut for EntryMut<'a> {
         let is_protected = b.is_protected.expect("no is_protected set in builder");
 
         let value = match (is_protected, self.crypt.key) {
-            (true, _) => Value::Basic(value),
+            (true, _) => Value::Basic(value.as_str().to_owned()),
             (false, Some(k)) => {
                 let encrypted = encrypt(value.as_bytes(), self.crypt.iv, k);
                 Value::Protected(Base64Vec(encrypted))
@@ -438,9 +342,9 @@ macro_rules! impl_field_ref {
                 }
             }
 
-            fn value(&self) -> Result<String, GetValueError> {
+            fn value(&self) -> Result<SecretString, GetValueError> {
                 match (&self.field.value, self.crypt.key) {
-                    (Value::Basic(s), _) => Ok(s.clone()),
+                    (Value::Basic(s), _) => Ok(s.clone().into()),
                     (Value::Protected(_), None) => Err(GetValueError::ContentsNotUnlocked),
                     (Value::Protected(bs), Some(k)) => {
                         Ok(decrypt_string(bs.as_ref(), self.crypt.iv, k)?)
@@ -486,7 +390,10 @@ impl<'a> super::FieldMut for FieldMut<'a> {
                 let bs = encrypt(s.as_bytes(), self.crypt.iv, key);
                 Value::Protected(Base64Vec(bs))
             }
-            Value::Protected(bs) => Value::Basic(decrypt_string(bs.as_ref(), self.crypt.iv, key)?),
+            Value::Protected(bs) => {
+                let value = decrypt_string(bs.as_ref(), self.crypt.iv, key)?;
+                Value::Basic(value.as_str().to_owned())
+            }
         };
 
         self.field.value = new_val;
@@ -497,7 +404,7 @@ impl<'a> super::FieldMut for FieldMut<'a> {
 
 struct FieldBuilder {
     name: Option<String>,
-    value: Option<String>,
+    value: Option<SecretString>,
     is_protected: Option<bool>,
 }
 
@@ -526,57 +433,3 @@ impl super::FieldBuilder for FieldBuilder {
         }
     }
 }
-
-// Small collection of tests for encrypting and decrypting
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fmt::Display;
-
-    fn check_single(val: &[u8], salt: &mut [u8], iv: &[u8], key: &[u8], ctx: impl Display) {
-        let encrypted = encrypt_with_salt(val, salt, iv, key);
-        let decrypted = decrypt(&encrypted, iv, key).unwrap();
-        assert_eq!(val, decrypted, "{}", ctx);
-    }
-
-    #[test]
-    fn run_all() {
-        let cases = &[
-            // Longest salt
-            ("", 32),
-            // Shortest salt
-            ("longer password so that we have minimum length", 17),
-            // Other, random combinations
-            ("foobarbaz", 24),
-            ("foobarbaz", 25),
-            ("foobarbaz", 26),
-            ("foobarbaz", 27),
-            ("foobarbaz", 28),
-            ("foobarbaz", 29),
-            ("foobarbaz", 30),
-            ("foobarbaz", 31),
-            ("foobarbaz", 32),
-        ];
-
-        // 32 totally random bytes.
-        let base_salt = b"\x6e\x6f\x20\x73\x65\x72\x69\x6f\x75\x73\x6c\x79\x20\x69\x74\x27\x73\x20\x6a\x75\x73\x74\x20\x72\x61\x6e\x64\x6f\x6d\x20\x3a\x50";
-        assert!(base_salt.len() == 32);
-
-        let key_salt = Salt::new("randomsaltstring").unwrap();
-
-        let key = "a temporary key for testing";
-        let hashed_key = hash_key(key_salt, key);
-
-        // 16 totally random bytes.
-        let iv = b"\x74\x68\x69\x73\x20\x69\x73\x20\x74\x68\x65\x20\x69\x76\x21\x21";
-        assert!(iv.len() == 16);
-
-        for (val, salt_len) in cases.iter().cloned() {
-            assert!(val.len() + salt_len >= SALT_MAX_LENGTH);
-
-            let mut salt = base_salt[..salt_len].to_vec();
-            let ctx = format!("val: {}, salt_len: {}", val, salt_len);
-            check_single(val.as_bytes(), &mut salt, iv, &hashed_key, ctx);
-        }
-    }
-}
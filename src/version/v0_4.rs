@@ -1,36 +1,133 @@
 //! Version 0.4 of the file format
 
 use super::{
-    CurrentFileContent, DecryptError, GetValueError, Keyed, PlaintextValue, SetFieldError,
-    SwapEncryptionError, UnsupportedFeature, ValueKind, Warning,
+    CurrentFileContent, DecryptError, GetValueError, Keyed, ParseError, PlaintextValue,
+    SetFieldError, SwapEncryptionError, UnsupportedFeature, ValueKind, Warning,
 };
+use crate::secret::{self, SecretBytes, SecretString};
 use crate::utils::Base64Vec;
+use aes::Aes256;
 use argon2::password_hash::Salt;
+use argon2::{Argon2, PasswordHasher};
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
 use google_authenticator::GA_AUTH;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::mem::take;
-use std::process::exit;
 use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
 
 pub const WARNING: Option<Warning> = None;
 
 pub static VERSION_STR: &str = "v0.4";
 
-// Some pieces of this file format are taken directly from v0.3; we'll import them here:
-pub use super::v0_3::{decrypt, decrypt_string, encrypt, hash_key, ENCRYPT_TOKEN};
-
-pub fn parse(file_content: String) -> Keyed<FileContent> {
-    match serde_yaml::from_str::<FileContent>(&file_content) {
-        Ok(c) => {
-            assert!(c.version == VERSION_STR);
-            Keyed::new(c)
-        }
-        Err(e) => {
-            eprintln!("failed to parse file: {}", e);
-            exit(1);
-        }
+pub fn parse(file_content: String) -> Result<Keyed<FileContent>, ParseError> {
+    let c = serde_yaml::from_str::<FileContent>(&file_content)?;
+    if c.version != VERSION_STR {
+        return Err(ParseError::UnknownVersion);
     }
+    Ok(Keyed::new(c))
+}
+
+// The password hash & encryption below were introduced in v0.3. They're defined here so that v0.4
+// files can still be read when v0.3 support is compiled out; v0.3 imports them from here.
+
+// Returns the parameters we use for the hasher
+fn argon_params() -> argon2::Params {
+    // Number of passes. 5 passes for now - can be adjusted later
+    const T_COST: u32 = 5;
+    // Memory cost, in KBytes. ~1GB
+    const M_COST: u32 = 1_000_000;
+    // Number of parallel lanes to use. This version of the argon2 library (0.2) doesn't actually
+    // implement the speed increase from parallel lanes.
+    const PARALLEL: u32 = 1;
+
+    let mut builder = argon2::ParamsBuilder::new();
+    builder.t_cost(T_COST).unwrap();
+    builder.m_cost(M_COST).unwrap();
+    builder.p_cost(PARALLEL).unwrap();
+    builder.params().unwrap()
+}
+
+pub fn hash_key(salt: Salt, key: &str) -> SecretBytes {
+    let hasher = Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon_params(),
+    );
+
+    let hash = hasher
+        .hash_password(key.as_bytes(), &salt)
+        .unwrap()
+        .hash
+        .unwrap();
+
+    Zeroizing::new(hash.as_bytes().to_vec())
+}
+
+// The bounds on salt length in "protected" fields
+//
+// We can encode the length of the salt in the first four bits of the contents, by adding 16 to it
+// afterwards.
+const SALT_MIN_LENGTH: usize = 17;
+const SALT_MAX_LENGTH: usize = 32;
+
+pub static ENCRYPT_TOKEN: &[u8] = "encryption token ☺".as_bytes();
+
+pub fn encrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    // Use a random length salt before the value. If the value is too short (i.e. < 17 bytes),
+    // we'll increase the minimum length of the salt so that we always get outputs ≥ 32 bytes.
+    //
+    // This is to make it harder for an outside observer to find short passwords.
+
+    let min_salt_len = SALT_MAX_LENGTH
+        .saturating_sub(val.len())
+        .max(SALT_MIN_LENGTH);
+
+    let mut rng = thread_rng();
+
+    // Easiest to just generate with constants and maybe not use all of it.
+    let mut max_len_salt: [u8; SALT_MAX_LENGTH] = rng.gen();
+
+    let salt_len = rng.gen_range(min_salt_len..=SALT_MAX_LENGTH);
+    let salt = &mut max_len_salt[..salt_len];
+
+    encrypt_with_salt(val, salt, iv, key)
+}
+
+pub fn encrypt_with_salt(val: &[u8], salt: &mut [u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    // Encode the length of the salt into its first bit:
+    const { assert!(SALT_MAX_LENGTH - SALT_MIN_LENGTH == 15) };
+    assert!(SALT_MIN_LENGTH <= salt.len() && salt.len() <= SALT_MAX_LENGTH);
+    let len_byte = (salt.len() - SALT_MIN_LENGTH) as u8;
+    salt[0] = salt[0] & 0xF0 | len_byte;
+
+    // Collect the salt + value into the vector to encrypt
+    let mut full = Zeroizing::new(Vec::with_capacity(salt.len() + val.len()));
+    full.extend_from_slice(salt);
+    full.extend_from_slice(val);
+
+    let cipher = <Cbc<Aes256, Pkcs7>>::new_from_slices(key, iv).unwrap();
+    cipher.encrypt_vec(&full)
+}
+
+pub fn decrypt(val: &[u8], iv: &[u8], key: &[u8]) -> Option<SecretBytes> {
+    let cipher = <Cbc<Aes256, Pkcs7>>::new_from_slices(key, iv).unwrap();
+    let mut decrypted = Zeroizing::new(cipher.decrypt_vec(val).ok()?);
+
+    // Refer to the construction in `encrypt`
+    let salt_len = (decrypted[0] & 0x0F) as usize + SALT_MIN_LENGTH;
+    assert!((SALT_MIN_LENGTH..=SALT_MAX_LENGTH).contains(&salt_len));
+
+    decrypted.drain(..salt_len);
+    Some(decrypted)
+}
+
+pub fn decrypt_string(val: &[u8], iv: &[u8], key: &[u8]) -> Result<SecretString, DecryptError> {
+    let bytes = decrypt(val, iv, key).ok_or(DecryptError::BadCrypt)?;
+    secret::into_string(bytes).ok_or(DecryptError::BadUtf8)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -69,12 +166,70 @@ pub enum Value {
 }
 
 impl super::FileContent for Keyed<FileContent> {
-    fn to_current(
-        mut self: Box<Self>,
-        pwd: String,
-    ) -> Result<Box<CurrentFileContent>, DecryptError> {
-        self.set_key(pwd)?;
-        Ok(self)
+    fn to_current(mut self: Box<Self>, pwd: &str) -> Result<Box<CurrentFileContent>, DecryptError> {
+        // v0.5 derives the key for each slot in the same way as v0.4, so we can keep the salt and
+        // hashed key for the file's only slot. The values themselves are re-encrypted under a new
+        // data key.
+        use super::v0_5;
+
+        if self.key.is_none() {
+            self.set_key(pwd.to_owned())?;
+        }
+
+        let key = self.key.take().unwrap();
+        let iv = self.content.iv.as_ref();
+        let data_key = v0_5::new_data_key();
+
+        let reencrypt = |bs: Base64Vec| -> Result<Base64Vec, DecryptError> {
+            let value = decrypt(bs.as_ref(), iv, &key).ok_or(DecryptError::BadCrypt)?;
+            Ok(Base64Vec(v0_5::encrypt(&value, &data_key)))
+        };
+
+        let slot = v0_5::KeySlot {
+            name: String::new(),
+            salt: self.content.salt,
+            // The key was derived with the parameters hard-coded in v0.3 & v0.4
+            kdf: v0_5::KdfParams::DEFAULT,
+            keyfile_required: false,
+            wrapped: v0_5::WrappedKey::wrap(&data_key, v0_5::WrappedKey::public_key(&key))
+                .expect("freshly-generated key is invalid"),
+        };
+
+        #[rustfmt::skip]
+        let content_v0_5 = v0_5::FileContent {
+            version: v0_5::VERSION_STR.to_owned(),
+            slots: vec![slot],
+            recipients: Vec::new(),
+            private_metadata: false,
+            last_update: self.content.last_update,
+            inner: self.content.inner.into_iter().map(|e| Ok(v0_5::Entry {
+                name: e.name,
+                tags: e.tags,
+                first_added: e.first_added,
+                last_update: e.last_update,
+                meta: None,
+                fields: e.fields.into_iter().map(|f| Ok(v0_5::Field {
+                    name: f.name,
+                    value: match f.value {
+                        Value::Basic(s) => v0_5::Value::Basic(s),
+                        Value::Protected(bs) => v0_5::Value::Protected(reencrypt(bs)?),
+                        Value::Totp { issuer, secret } => {
+                            v0_5::Value::Totp { issuer, secret: reencrypt(secret)? }
+                        }
+                    },
+                })).collect::<Result<_, _>>()?,
+            })).collect::<Result<_, _>>()?,
+            // The MAC is always recomputed on write, so we don't need to set it here
+            mac: Base64Vec(Vec::new()),
+            saved_bytes: None,
+            keyfile: None,
+            unlocked_slot: Some(0),
+            unlocked_secret: None,
+        };
+
+        let mut new = Keyed::new(content_v0_5);
+        new.key = Some(data_key.into());
+        Ok(Box::new(new))
     }
 
     fn write(&self) -> String {
@@ -83,6 +238,7 @@ impl super::FileContent for Keyed<FileContent> {
     }
 
     fn set_key(&mut self, key: String) -> Result<(), DecryptError> {
+        let key = Zeroizing::new(key);
         let hashed = hash_key(Salt::new(&self.content.salt).unwrap(), &key);
 
         let decrypted_token = decrypt(
@@ -99,6 +255,10 @@ impl super::FileContent for Keyed<FileContent> {
         }
     }
 
+    fn lock(&mut self) {
+        self.key = None;
+    }
+
     fn unsaved(&self) -> bool {
         self.unsaved
     }
@@ -120,7 +280,7 @@ impl super::FileContent for Keyed<FileContent> {
             entry: &self.content.inner[idx],
             crypt: CryptStateRef {
                 iv: self.content.iv.as_ref(),
-                key: self.key.as_ref().map(|vec| vec.as_slice()),
+                key: self.key.as_deref(),
             },
         })
     }
@@ -130,7 +290,7 @@ impl super::FileContent for Keyed<FileContent> {
             entry: &mut self.content.inner[idx],
             crypt: CryptStateRef {
                 iv: self.content.iv.as_ref(),
-                key: self.key.as_ref().map(|vec| vec.as_slice()),
+                key: self.key.as_deref(),
             },
             unsaved: &mut self.unsaved,
             global_update: &mut self.content.last_update,
@@ -266,7 +426,7 @@ impl<'a> super::EntryMut for EntryMut<'a> {
         #[rustfmt::skip]
         let value = match take(&mut b.value).expect("no value set in builder") {
             PlaintextValue::Manual { value, protected: false } => {
-                Value::Basic(value.clone())
+                Value::Basic(value.as_str().to_owned())
             },
             PlaintextValue::Manual { value, protected: true } => {
                 let k = self.crypt.key
@@ -334,9 +494,9 @@ macro_rules! impl_field_ref {
                 }
             }
 
-            fn value(&self) -> Result<String, GetValueError> {
+            fn value(&self) -> Result<SecretString, GetValueError> {
                 match (&self.field.value, self.crypt.key) {
-                    (Value::Basic(s), _) => Ok(s.clone()),
+                    (Value::Basic(s), _) => Ok(s.clone().into()),
                     (Value::Protected(bs), Some(k)) => {
                         Ok(decrypt_string(bs.as_ref(), self.crypt.iv, k)?)
                     }
@@ -347,9 +507,7 @@ macro_rules! impl_field_ref {
                         let time_slice = unix_time / 30;
                         let code = GA_AUTH.get_code(&secret_plaintext, time_slice)
                             .map_err(|_| GetValueError::BadTotpSecret)?;
-                        let secs_remaining = 30 - unix_time % 30;
-                        crate::utils::send_refresh_tick_after_1_second();
-                        Ok(format!("{code}  (00:{secs_remaining:02} remaining)"))
+                        Ok(code.into())
                     }
                     (_, None) => Err(GetValueError::ContentsNotUnlocked),
                 }
@@ -358,7 +516,7 @@ macro_rules! impl_field_ref {
             fn plaintext_value(&self) -> Result<PlaintextValue, GetValueError> {
                 match (&self.field.value, self.crypt.key) {
                     (Value::Basic(s), _) => {
-                        Ok(PlaintextValue::Manual { value: s.clone(), protected: false })
+                        Ok(PlaintextValue::Manual { value: s.clone().into(), protected: false })
                     }
                     (Value::Protected(bs), Some(k)) => {
                         let value = decrypt_string(bs.as_ref(), self.crypt.iv, k)?;
@@ -400,7 +558,10 @@ impl<'a> super::FieldMut for FieldMut<'a> {
                 let bs = encrypt(s.as_bytes(), self.crypt.iv, key);
                 Value::Protected(Base64Vec(bs))
             }
-            Value::Protected(bs) => Value::Basic(decrypt_string(bs.as_ref(), self.crypt.iv, key)?),
+            Value::Protected(bs) => {
+                let value = decrypt_string(bs.as_ref(), self.crypt.iv, key)?;
+                Value::Basic(value.as_str().to_owned())
+            }
             Value::Totp { .. } => return Err(SwapEncryptionError::IsTotp),
         };
 
@@ -434,3 +595,139 @@ impl super::FieldBuilder for FieldBuilder {
         self.value = Some(value);
     }
 }
+
+// Small collection of tests for encrypting and decrypting
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Display;
+
+    fn check_single(val: &[u8], salt: &mut [u8], iv: &[u8], key: &[u8], ctx: impl Display) {
+        let encrypted = encrypt_with_salt(val, salt, iv, key);
+        let decrypted = decrypt(&encrypted, iv, key).unwrap();
+        assert_eq!(val, decrypted.as_slice(), "{}", ctx);
+    }
+
+    #[test]
+    fn run_all() {
+        let cases = &[
+            // Longest salt
+            ("", 32),
+            // Shortest salt
+            ("longer password so that we have minimum length", 17),
+            // Other, random combinations
+            ("foobarbaz", 24),
+            ("foobarbaz", 25),
+            ("foobarbaz", 26),
+            ("foobarbaz", 27),
+            ("foobarbaz", 28),
+            ("foobarbaz", 29),
+            ("foobarbaz", 30),
+            ("foobarbaz", 31),
+            ("foobarbaz", 32),
+        ];
+
+        // 32 totally random bytes.
+        let base_salt = b"\x6e\x6f\x20\x73\x65\x72\x69\x6f\x75\x73\x6c\x79\x20\x69\x74\x27\x73\x20\x6a\x75\x73\x74\x20\x72\x61\x6e\x64\x6f\x6d\x20\x3a\x50";
+        assert!(base_salt.len() == 32);
+
+        let key_salt = Salt::new("randomsaltstring").unwrap();
+
+        let key = "a temporary key for testing";
+        let hashed_key = hash_key(key_salt, key);
+
+        // 16 totally random bytes.
+        let iv = b"\x74\x68\x69\x73\x20\x69\x73\x20\x74\x68\x65\x20\x69\x76\x21\x21";
+        assert!(iv.len() == 16);
+
+        for (val, salt_len) in cases.iter().cloned() {
+            assert!(val.len() + salt_len >= SALT_MAX_LENGTH);
+
+            let mut salt = base_salt[..salt_len].to_vec();
+            let ctx = format!("val: {}, salt_len: {}", val, salt_len);
+            check_single(val.as_bytes(), &mut salt, iv, &hashed_key, ctx);
+        }
+    }
+
+    #[test]
+    fn kdf_matches_v0_5() {
+        // Upgrading keeps the derived key, so v0.5 has to describe it with the same parameters
+        let params = argon_params();
+        let default = super::super::v0_5::KdfParams::DEFAULT;
+        assert_eq!(
+            (params.t_cost(), params.m_cost(), params.p_cost()),
+            (default.t_cost, default.m_cost, default.p_cost)
+        );
+    }
+
+    #[test]
+    fn upgrade() {
+        use super::super::{v0_5, FileContent as _};
+        use crate::secret::LockedKey;
+
+        // Stands in for the key derived from the password, which would take too long to derive
+        let key = [7_u8; 32];
+        let iv = b"\x74\x68\x69\x73\x20\x69\x73\x20\x74\x68\x65\x20\x69\x76\x21\x21";
+        let now = SystemTime::now();
+
+        let mut content = Keyed::new(FileContent {
+            version: VERSION_STR.to_owned(),
+            token: Base64Vec(encrypt(ENCRYPT_TOKEN, iv, &key)),
+            iv: Base64Vec(iv.to_vec()),
+            salt: "randomsaltstring".to_owned(),
+            last_update: now,
+            inner: vec![Entry {
+                name: "foo".to_owned(),
+                tags: vec!["bar".to_owned()],
+                fields: vec![
+                    Field {
+                        name: "username".to_owned(),
+                        value: Value::Basic("baz".to_owned()),
+                    },
+                    Field {
+                        name: "password".to_owned(),
+                        value: Value::Protected(Base64Vec(encrypt(b"hunter2", iv, &key))),
+                    },
+                    Field {
+                        name: "totp".to_owned(),
+                        value: Value::Totp {
+                            issuer: "issuer".to_owned(),
+                            secret: Base64Vec(encrypt(b"JBSWY3DPEHPK3PXP", iv, &key)),
+                        },
+                    },
+                ],
+                first_added: now,
+                last_update: now,
+            }],
+        });
+        content.key = Some(LockedKey::new(&key));
+
+        // The file is already unlocked, so the password isn't used
+        let current = Box::new(content).to_current("unused").unwrap();
+        let check = |c: &CurrentFileContent| {
+            let entry = c.entry(0);
+            assert_eq!((entry.name(), entry.tags()), ("foo", vec!["bar"]));
+            assert_eq!(entry.field(0).value().unwrap().as_str(), "baz");
+            assert_eq!(entry.field(1).value().unwrap().as_str(), "hunter2");
+            assert!(matches!(
+                entry.field(2).plaintext_value(),
+                Ok(PlaintextValue::Totp { issuer, secret })
+                    if issuer == "issuer" && secret.as_str() == "JBSWY3DPEHPK3PXP"
+            ));
+        };
+        check(&current);
+
+        // The file's only slot keeps the salt, and wraps the new data key to the old key
+        let slot = &current.content.slots[0];
+        assert_eq!(slot.salt, "randomsaltstring");
+        assert_eq!(slot.kdf, v0_5::KdfParams::DEFAULT);
+        assert_eq!(
+            slot.wrapped.unwrap(&key).as_deref().map(|k| k.as_slice()),
+            current.key.as_deref()
+        );
+
+        let mut parsed = v0_5::parse(current.write()).unwrap();
+        parsed.set_derived_key("randomsaltstring", &key).unwrap();
+        check(&parsed);
+    }
+}
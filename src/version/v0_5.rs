@@ -0,0 +1,1636 @@
+//! Version 0.5 of the file format
+//!
+//! The main change from v0.4 is that all encrypted values now use authenticated encryption
+//! (XChaCha20-Poly1305), each with its own random nonce -- instead of AES-256-CBC under a single
+//! file-wide IV. Tampering with an encrypted value is now always detected on decryption.
+//!
+//! The rest of the file -- entry names, tags, timestamps and basic values -- is covered by a keyed
+//! MAC, which is checked when the file is unlocked.
+//!
+//! Files may optionally be created with "private metadata", where each entry's name, tags and
+//! timestamps are stored encrypted as well. Until the file is unlocked, these are unavailable.
+//!
+//! Everything is encrypted with a random "data key", which is then wrapped separately for each of
+//! the file's key slots. Each slot has its own password, and so any number of people can unlock
+//! the same file without sharing a password. Slots store the parameters for deriving the key from
+//! their password, so that they can be tuned to the machine (see the `tune-kdf` subcommand), and
+//! may additionally require a keyfile, whose contents are mixed into the key after it's derived.
+//!
+//! The data key can also be wrapped to the public keys of "recipients", who can then unlock the
+//! file with their identity file instead of a password (see the `keygen` and `recipients`
+//! subcommands). Recipients are listed in the clear, so that anyone can see who has access.
+
+use super::{
+    ChangePasswordError, CurrentFileContent, DecryptError, GetValueError, Keyed, ParseError,
+    PlaintextValue, SetFieldError, SwapEncryptionError, UnsupportedFeature, ValueKind, Warning,
+};
+use crate::secret::{self, SecretBytes, SecretString};
+use crate::utils::Base64Vec;
+use argon2::password_hash::{PasswordHasher, Salt};
+use argon2::Argon2;
+use blake2::crypto_mac::Mac;
+use blake2::Blake2b;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use google_authenticator::GA_AUTH;
+use rand::{thread_rng, Rng};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fmt::{self, Display, Formatter};
+use std::mem::take;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+pub const WARNING: Option<Warning> = None;
+
+pub static VERSION_STR: &str = "v0.5";
+
+pub fn parse(file_content: String) -> Result<Keyed<FileContent>, ParseError> {
+    let mut c = serde_yaml::from_str::<FileContent>(&file_content)?;
+    if c.version != VERSION_STR {
+        return Err(ParseError::UnknownVersion);
+    }
+    c.saved_bytes = Some(c.authenticated(None).to_bytes());
+    Ok(Keyed::new(c))
+}
+
+// The length of the nonce prefixed to each encrypted value
+const NONCE_LENGTH: usize = 24;
+
+// The minimum length of the padded plaintext. Shorter values are padded up to this length, so
+// that an outside observer can't pick out short passwords.
+const PADDED_MIN_LENGTH: usize = 32;
+// The maximum amount of extra random padding added on top of the minimum
+const PADDING_MAX_EXTRA: usize = 15;
+
+pub fn encrypt(val: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut rng = thread_rng();
+
+    // Before encrypting, the value is prefixed by a single byte giving the length of the padding,
+    // followed by the padding itself. Like v0.3, the padding is of random length, but guaranteed
+    // to bring the total up to at least `PADDED_MIN_LENGTH` bytes.
+    let min_pad_len = PADDED_MIN_LENGTH.saturating_sub(val.len() + 1);
+    let pad_len = rng.gen_range(min_pad_len..=min_pad_len + PADDING_MAX_EXTRA);
+
+    let mut full = Zeroizing::new(Vec::with_capacity(1 + pad_len + val.len()));
+    full.push(pad_len as u8);
+    full.extend((0..pad_len).map(|_| rng.gen::<u8>()));
+    full.extend_from_slice(val);
+
+    let nonce = rng.gen::<[u8; NONCE_LENGTH]>();
+    encrypt_with_nonce(&full, &nonce, key)
+}
+
+pub fn encrypt_with_nonce(val: &[u8], nonce: &[u8; NONCE_LENGTH], key: &[u8]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let encrypted = cipher
+        .encrypt(XNonce::from_slice(nonce), val)
+        .expect("unrecoverable error: failed to encrypt value");
+
+    let mut output = Vec::with_capacity(NONCE_LENGTH + encrypted.len());
+    output.extend_from_slice(nonce);
+    output.extend_from_slice(&encrypted);
+    output
+}
+
+pub fn decrypt(val: &[u8], key: &[u8]) -> Option<SecretBytes> {
+    if val.len() < NONCE_LENGTH {
+        return None;
+    }
+
+    let (nonce, encrypted) = val.split_at(NONCE_LENGTH);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let mut decrypted = Zeroizing::new(cipher.decrypt(XNonce::from_slice(nonce), encrypted).ok()?);
+
+    // Refer to the construction in `encrypt`
+    let pad_len = *decrypted.first()? as usize;
+    if 1 + pad_len > decrypted.len() {
+        return None;
+    }
+
+    decrypted.drain(..1 + pad_len);
+    Some(decrypted)
+}
+
+pub fn decrypt_string(val: &[u8], key: &[u8]) -> Result<SecretString, DecryptError> {
+    let bytes = decrypt(val, key).ok_or(DecryptError::BadCrypt)?;
+    secret::into_string(bytes).ok_or(DecryptError::BadUtf8)
+}
+
+// Personalization for deriving the nonces in `encrypt_deterministic`
+static NONCE_PERSONA: &[u8; 16] = b"passman v0.5 nce";
+
+/// Like `encrypt`, but always produces the same output for the same value and key
+///
+/// The nonce is derived from a keyed hash of the value, so this only reveals whether two values
+/// are equal. We use it for entry metadata, so that writing the file twice without changes gives
+/// the same result -- which the MAC relies on.
+pub fn encrypt_deterministic(val: &[u8], key: &[u8]) -> Vec<u8> {
+    // Pad to a multiple of 32 bytes (in the same format as `encrypt`), with zeros instead of
+    // random bytes
+    let pad_len = (32 - (val.len() + 1) % 32) % 32;
+
+    let mut full = Zeroizing::new(Vec::with_capacity(1 + pad_len + val.len()));
+    full.push(pad_len as u8);
+    full.resize(1 + pad_len, 0);
+    full.extend_from_slice(val);
+
+    let mut hasher = Blake2b::with_params(key, &[], NONCE_PERSONA);
+    Mac::update(&mut hasher, &full);
+    let hash = hasher.finalize().into_bytes();
+
+    let mut nonce = [0_u8; NONCE_LENGTH];
+    nonce.copy_from_slice(&hash[..NONCE_LENGTH]);
+    encrypt_with_nonce(&full, &nonce, key)
+}
+
+/// The algorithm used to derive the key from the password
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KdfAlgorithm {
+    #[serde(rename = "argon2id")]
+    Argon2id,
+}
+
+/// Parameters for deriving the key from the password, stored in the file next to the salt
+///
+/// The parameters are checked against `MAX_T_COST`, `MAX_M_COST` and `MAX_P_COST` when the file is
+/// parsed. They're read before the MAC can be checked, so otherwise anyone able to write to the
+/// file could make every attempt to unlock it run out of memory or never finish.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedKdfParams")]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    // Number of passes
+    pub t_cost: u32,
+    // Memory cost, in KiB
+    pub m_cost: u32,
+    // Number of parallel lanes
+    pub p_cost: u32,
+}
+
+/// `KdfParams`, as they're deserialized before checking them
+#[derive(Deserialize)]
+struct UncheckedKdfParams {
+    algorithm: KdfAlgorithm,
+    t_cost: u32,
+    m_cost: u32,
+    p_cost: u32,
+}
+
+impl TryFrom<UncheckedKdfParams> for KdfParams {
+    type Error = DecryptError;
+
+    fn try_from(p: UncheckedKdfParams) -> Result<Self, DecryptError> {
+        let params = KdfParams {
+            algorithm: p.algorithm,
+            t_cost: p.t_cost,
+            m_cost: p.m_cost,
+            p_cost: p.p_cost,
+        };
+        params.check()?;
+        Ok(params)
+    }
+}
+
+impl KdfParams {
+    /// The parameters hard-coded in v0.3 and v0.4, which we continue to use by default
+    pub const DEFAULT: Self = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        t_cost: 5,
+        m_cost: 1_000_000,
+        p_cost: 1,
+    };
+
+    // The largest parameters we'll accept: 100 passes, 4 GiB of memory, and 16 lanes
+    pub const MAX_T_COST: u32 = 100;
+    pub const MAX_M_COST: u32 = 4 * 1024 * 1024;
+    pub const MAX_P_COST: u32 = 16;
+
+    /// Returns `Err` if any of the parameters are larger than we're willing to use
+    pub fn check(&self) -> Result<(), DecryptError> {
+        match self.t_cost <= Self::MAX_T_COST
+            && self.m_cost <= Self::MAX_M_COST
+            && self.p_cost <= Self::MAX_P_COST
+        {
+            true => Ok(()),
+            false => Err(DecryptError::BadKdfParams),
+        }
+    }
+
+    /// Derives the key from the password and the contents of the keyfile (if there is one),
+    /// returning `Err` if the parameters are invalid
+    pub fn hash_key(
+        &self,
+        salt: Salt,
+        key: &str,
+        keyfile: Option<&[u8]>,
+    ) -> Result<SecretBytes, DecryptError> {
+        self.check()?;
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2id => argon2::Algorithm::Argon2id,
+        };
+        let params = argon2::Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|_| DecryptError::BadKdfParams)?;
+        let hasher = Argon2::new(algorithm, argon2::Version::V0x13, params);
+
+        let hash = hasher
+            .hash_password(key.as_bytes(), &salt)
+            .map_err(|_| DecryptError::BadKdfParams)?
+            .hash
+            .unwrap();
+
+        let keyfile = match keyfile {
+            None => return Ok(Zeroizing::new(hash.as_bytes().to_vec())),
+            Some(k) => k,
+        };
+
+        // The keyfile doesn't need any stretching of its own, so we just use the password-derived
+        // key to MAC its contents
+        let mut mixed = Blake2b::with_params(hash.as_bytes(), &[], KEYFILE_PERSONA);
+        Mac::update(&mut mixed, keyfile);
+        Ok(Zeroizing::new(
+            mixed.finalize().into_bytes()[..hash.len()].to_vec(),
+        ))
+    }
+}
+
+// Personalization for mixing the keyfile into the key. BLAKE2b requires this to be exactly 16 bytes.
+static KEYFILE_PERSONA: &[u8; 16] = b"passman v0.5 kyf";
+
+impl Display for KdfParams {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2id => "argon2id",
+        };
+
+        write!(
+            f,
+            "{}, {} passes, {} MiB memory, {} lanes",
+            algorithm,
+            self.t_cost,
+            self.m_cost / 1024,
+            self.p_cost
+        )
+    }
+}
+
+// The length of the data key, and of the keys derived from each slot's password
+pub const KEY_LENGTH: usize = 32;
+
+/// Generates a new random data key
+pub fn new_data_key() -> SecretBytes {
+    let mut key = Zeroizing::new(vec![0_u8; KEY_LENGTH]);
+    OsRng.fill_bytes(&mut key); // Have to use OsRng here for CSPRNG
+    key
+}
+
+// Personalization for deriving the key that wraps the data key. BLAKE2b requires this to be
+// exactly 16 bytes.
+static WRAP_PERSONA: &[u8; 16] = b"passman v0.5 wrp";
+
+/// The data key, wrapped to an X25519 public key
+///
+/// This is the usual ephemeral-static Diffie-Hellman: the data key is encrypted with a key derived
+/// from the shared secret between a freshly-generated ephemeral key and the public key. Wrapping
+/// doesn't require the secret key, so we can re-wrap a new data key for every slot without knowing
+/// their passwords.
+#[derive(Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub public: Base64Vec,
+    pub ephemeral: Base64Vec,
+    pub key: Base64Vec,
+}
+
+fn to_key_array(bs: &[u8]) -> Option<[u8; KEY_LENGTH]> {
+    bs.try_into().ok()
+}
+
+impl WrappedKey {
+    /// Returns the public key corresponding to the secret
+    pub fn public_key(secret: &[u8]) -> Base64Vec {
+        let secret = StaticSecret::from(to_key_array(secret).expect("secret key has bad length"));
+        Base64Vec(PublicKey::from(&secret).as_bytes().to_vec())
+    }
+
+    /// Wraps the data key to the public key, returning `None` if the public key is invalid
+    pub fn wrap(data_key: &[u8], public: Base64Vec) -> Option<Self> {
+        let public_key = PublicKey::from(to_key_array(public.as_ref())?);
+        let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&ephemeral_secret).as_bytes().to_vec();
+
+        let shared = ephemeral_secret.diffie_hellman(&public_key);
+        let wrapping_key = Self::wrapping_key(shared.as_bytes(), &ephemeral, public.as_ref());
+
+        Some(WrappedKey {
+            key: Base64Vec(encrypt(data_key, &wrapping_key)),
+            public,
+            ephemeral: Base64Vec(ephemeral),
+        })
+    }
+
+    /// Unwraps the data key with the secret key, returning `None` if it was the wrong one
+    pub fn unwrap(&self, secret: &[u8]) -> Option<SecretBytes> {
+        let secret = StaticSecret::from(to_key_array(secret)?);
+        let ephemeral = PublicKey::from(to_key_array(self.ephemeral.as_ref())?);
+
+        let shared = secret.diffie_hellman(&ephemeral);
+        let wrapping_key = Self::wrapping_key(
+            shared.as_bytes(),
+            self.ephemeral.as_ref(),
+            self.public.as_ref(),
+        );
+        decrypt(self.key.as_ref(), &wrapping_key)
+    }
+
+    fn wrapping_key(shared: &[u8], ephemeral: &[u8], public: &[u8]) -> SecretBytes {
+        let mut hasher = Blake2b::with_params(shared, &[], WRAP_PERSONA);
+        Mac::update(&mut hasher, ephemeral);
+        Mac::update(&mut hasher, public);
+        Zeroizing::new(hasher.finalize().into_bytes()[..KEY_LENGTH].to_vec())
+    }
+}
+
+/// A single password that can unlock the file
+///
+/// The password (and keyfile, if required) is stretched into an X25519 secret key, which the data
+/// key is wrapped to.
+#[derive(Serialize, Deserialize)]
+pub struct KeySlot {
+    // An optional label, to help tell slots apart
+    #[serde(default)]
+    pub name: String,
+    pub salt: String, // Salt for the password
+    pub kdf: KdfParams,
+    // Whether a keyfile is required, in addition to the password
+    #[serde(default)]
+    pub keyfile_required: bool,
+    pub wrapped: WrappedKey,
+}
+
+impl KeySlot {
+    /// Derives the secret key for this slot from the password and keyfile
+    pub fn derive_secret(
+        &self,
+        pwd: &str,
+        keyfile: Option<&[u8]>,
+    ) -> Result<SecretBytes, DecryptError> {
+        let salt = Salt::new(&self.salt).map_err(|_| DecryptError::BadKdfParams)?;
+        self.kdf.hash_key(salt, pwd, keyfile)
+    }
+}
+
+/// A public key that can unlock the file, with the matching identity
+#[derive(Serialize, Deserialize)]
+pub struct Recipient {
+    // An optional label, to help tell recipients apart
+    #[serde(default)]
+    pub name: String,
+    pub wrapped: WrappedKey,
+}
+
+impl Recipient {
+    /// Returns the recipient's public key
+    pub fn public_key(&self) -> &Base64Vec {
+        &self.wrapped.public
+    }
+}
+
+// The header line of identity files, so that they're recognizable
+static IDENTITY_HEADER: &str = "# passman identity";
+
+/// An X25519 secret key, which can unlock any file that its public key is a recipient of
+pub struct Identity {
+    secret: SecretBytes,
+}
+
+impl Identity {
+    /// Generates a new random identity
+    pub fn generate() -> Self {
+        Identity {
+            secret: new_data_key(),
+        }
+    }
+
+    /// Returns the public key for the identity, which is what's added as a recipient
+    pub fn public_key(&self) -> Base64Vec {
+        WrappedKey::public_key(&self.secret)
+    }
+
+    /// Parses the contents of an identity file, returning `None` if they're invalid
+    ///
+    /// Blank lines and comments (starting with '#') are ignored; there should be exactly one line
+    /// remaining, with the base64-encoded secret key.
+    pub fn parse(contents: &str) -> Option<Self> {
+        let mut lines = (contents.lines())
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
+
+        let secret = Zeroizing::new(base64::decode(lines.next()?).ok()?);
+        if lines.next().is_some() || secret.len() != KEY_LENGTH {
+            return None;
+        }
+
+        Some(Identity { secret })
+    }
+
+    /// Produces the contents of the identity file, including the public key as a comment
+    pub fn to_file_string(&self) -> String {
+        format!(
+            "{}\n# public key: {}\n{}\n",
+            IDENTITY_HEADER,
+            base64::encode(self.public_key()),
+            base64::encode(&self.secret)
+        )
+    }
+}
+
+// Personalization for the MAC, so that it's separated from any other use of the key. BLAKE2b
+// requires this to be exactly 16 bytes.
+static MAC_PERSONA: &[u8; 16] = b"passman v0.5 mac";
+
+// The name displayed for entries with private metadata, before the file has been unlocked
+pub static LOCKED_NAME: &str = "<Locked>";
+
+#[derive(Serialize, Deserialize)]
+pub struct FileContent {
+    pub version: String, // Should always be v0.5
+    // The key slots, any of which can be used to unlock the file
+    pub slots: Vec<KeySlot>,
+    // The public keys that can also unlock the file, with their identities
+    #[serde(default)]
+    pub recipients: Vec<Recipient>,
+    // Whether the names, tags and timestamps of entries are encrypted
+    #[serde(default)]
+    pub private_metadata: bool,
+    pub last_update: SystemTime,
+    pub inner: Vec<Entry>,
+    // MAC over the rest of the content, as given by `authenticated`
+    pub mac: Base64Vec,
+
+    // The serialized output of `authenticated` when the file was last read or written, so that
+    // we can still check the MAC if there's been changes made before the key was given
+    #[serde(skip)]
+    pub saved_bytes: Option<Vec<u8>>,
+    // The contents of the keyfile, if one has been given
+    #[serde(skip)]
+    pub keyfile: Option<SecretBytes>,
+    // The index of the slot that the file was unlocked with, if it was unlocked with a password
+    #[serde(skip)]
+    pub unlocked_slot: Option<usize>,
+    // The key derived from that slot's password, if we have it, so that it can be cached
+    #[serde(skip)]
+    pub unlocked_secret: Option<SecretBytes>,
+}
+
+/// The parts of the `FileContent` covered by the MAC -- i.e. everything but the MAC itself
+///
+/// This is also what's written to the file, alongside the MAC. Any fields added to `FileContent`
+/// must be added here as well.
+#[derive(Serialize)]
+struct Authenticated<'a> {
+    version: &'a str,
+    slots: &'a [KeySlot],
+    recipients: &'a [Recipient],
+    private_metadata: bool,
+    last_update: &'a SystemTime,
+    inner: Vec<StoredEntry<'a>>,
+}
+
+/// The form an `Entry` is written as, depending on whether its metadata is private
+#[derive(Serialize)]
+#[serde(untagged)]
+enum StoredEntry<'a> {
+    Public(&'a Entry),
+    Private {
+        meta: Base64Vec,
+        fields: &'a [Field],
+    },
+}
+
+/// Helper struct for writing the `FileContent` with a freshly-computed MAC
+#[derive(Serialize)]
+struct WithMac<'a> {
+    #[serde(flatten)]
+    content: Authenticated<'a>,
+    mac: &'a Base64Vec,
+}
+
+impl FileContent {
+    /// Produces the content as it should be written to the file
+    ///
+    /// With private metadata, the key is required to write any entries that have been unlocked.
+    fn authenticated(&self, key: Option<&[u8]>) -> Authenticated<'_> {
+        let store = |e: &'_ Entry| -> Base64Vec {
+            match (&e.meta, key) {
+                (_, Some(k)) => e.seal_metadata(k),
+                (Some(sealed), None) => sealed.clone(),
+                (None, None) => panic!("cannot write private entry metadata without the key"),
+            }
+        };
+
+        let inner = match self.private_metadata {
+            false => self.inner.iter().map(StoredEntry::Public).collect(),
+            true => (self.inner.iter())
+                .map(|e| StoredEntry::Private {
+                    meta: store(e),
+                    fields: &e.fields,
+                })
+                .collect(),
+        };
+
+        Authenticated {
+            version: &self.version,
+            slots: &self.slots,
+            recipients: &self.recipients,
+            private_metadata: self.private_metadata,
+            last_update: &self.last_update,
+            inner,
+        }
+    }
+
+    fn mac_state(bytes: &[u8], key: &[u8]) -> Blake2b {
+        let mut mac = Blake2b::with_params(key, &[], MAC_PERSONA);
+        Mac::update(&mut mac, bytes);
+        mac
+    }
+
+    /// Computes the MAC for the current content
+    pub fn compute_mac(&self, key: &[u8]) -> Vec<u8> {
+        let bytes = self.authenticated(Some(key)).to_bytes();
+        Self::mac_state(&bytes, key)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    /// Checks the stored MAC against the content as it was last saved, returning whether it's
+    /// valid
+    fn verify_mac(&self, key: &[u8]) -> bool {
+        let current_bytes;
+        let bytes = match self.saved_bytes.as_ref() {
+            Some(bs) => bs,
+            None => {
+                current_bytes = self.authenticated(Some(key)).to_bytes();
+                &current_bytes
+            }
+        };
+
+        Self::mac_state(bytes, key)
+            .verify(self.mac.as_ref())
+            .is_ok()
+    }
+
+    /// Decrypts the metadata of all entries that have it stored privately
+    fn unseal_metadata(&mut self, key: &[u8]) -> Result<(), DecryptError> {
+        for entry in self.inner.iter_mut() {
+            let sealed = match entry.meta.as_ref() {
+                Some(m) => m,
+                None => continue,
+            };
+
+            let bytes = decrypt(sealed.as_ref(), key).ok_or(DecryptError::BadCrypt)?;
+            let meta: Metadata =
+                serde_yaml::from_slice(&bytes).map_err(|_| DecryptError::BadCrypt)?;
+
+            entry.name = meta.name;
+            entry.tags = meta.tags;
+            entry.first_added = meta.first_added;
+            entry.last_update = meta.last_update;
+            entry.meta = None;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Authenticated<'a> {
+    /// Produces the canonical serialized form of the content, which the MAC is computed over
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_yaml::to_string(self)
+            .expect("unrecoverable error: failed to serialize the file content")
+            .into_bytes()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    // With private metadata, the name, tags, and timestamps are stored in `meta` instead.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub fields: Vec<Field>,
+    #[serde(default = "unknown_time")]
+    pub first_added: SystemTime,
+    #[serde(default = "unknown_time")]
+    pub last_update: SystemTime,
+
+    // The encrypted metadata, for entries that haven't yet been unlocked
+    #[serde(default, skip_serializing)]
+    pub meta: Option<Base64Vec>,
+}
+
+fn unknown_time() -> SystemTime {
+    UNIX_EPOCH
+}
+
+/// The metadata of an entry, which is encrypted for files with private metadata
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    name: String,
+    tags: Vec<String>,
+    first_added: SystemTime,
+    last_update: SystemTime,
+}
+
+impl Entry {
+    fn seal_metadata(&self, key: &[u8]) -> Base64Vec {
+        let meta = Metadata {
+            name: self.name.clone(),
+            tags: self.tags.clone(),
+            first_added: self.first_added,
+            last_update: self.last_update,
+        };
+
+        let bytes = serde_yaml::to_string(&meta)
+            .expect("unrecoverable error: failed to serialize entry metadata");
+        Base64Vec(encrypt_deterministic(bytes.as_bytes(), key))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub value: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Value {
+    #[serde(rename = "basic")]
+    Basic(String),
+    #[serde(rename = "protected")]
+    Protected(Base64Vec),
+    #[serde(rename = "totp")]
+    Totp { issuer: String, secret: Base64Vec },
+}
+
+impl Keyed<FileContent> {
+    /// Finishes unlocking the file with the unwrapped data key, checking the MAC and decrypting
+    /// any private metadata
+    fn unlock_with(&mut self, data_key: SecretBytes) -> Result<(), DecryptError> {
+        if !self.content.verify_mac(&data_key) {
+            return Err(DecryptError::BadMac);
+        }
+
+        self.content.unseal_metadata(&data_key)?;
+        self.key = Some(data_key.into());
+        Ok(())
+    }
+}
+
+impl super::FileContent for Keyed<FileContent> {
+    fn to_current(mut self: Box<Self>, pwd: &str) -> Result<Box<CurrentFileContent>, DecryptError> {
+        if self.key.is_none() {
+            self.set_key(pwd.to_owned())?;
+        }
+        Ok(self)
+    }
+
+    fn write(&self) -> String {
+        // Without the key, we can't produce a new MAC. This is only ok if there haven't been any
+        // changes; refer to `write_requires_key`.
+        let content = self.content.authenticated(self.key.as_deref());
+        let mac = match self.key.as_ref() {
+            Some(k) => Base64Vec(
+                FileContent::mac_state(&content.to_bytes(), k)
+                    .finalize()
+                    .into_bytes()
+                    .to_vec(),
+            ),
+            None => self.content.mac.clone(),
+        };
+
+        let with_mac = WithMac { content, mac: &mac };
+
+        serde_yaml::to_string(&with_mac)
+            .expect("unrecoverable error: failed to serialize the file content")
+    }
+
+    fn write_requires_key(&self) -> bool {
+        true
+    }
+
+    fn set_keyfile(&mut self, contents: Vec<u8>) -> Result<(), DecryptError> {
+        if !self.content.slots.iter().any(|s| s.keyfile_required) {
+            return Err(DecryptError::KeyfileNotUsed);
+        }
+
+        self.content.keyfile = Some(Zeroizing::new(contents));
+        Ok(())
+    }
+
+    fn keyfile_required(&self) -> bool {
+        let slots = &self.content.slots;
+        !slots.is_empty() && slots.iter().all(|s| s.keyfile_required)
+    }
+
+    fn set_key(&mut self, key: String) -> Result<(), DecryptError> {
+        let key = Zeroizing::new(key);
+        let keyfile = self.content.keyfile.as_ref().map(|k| k.as_slice());
+
+        // Try each slot in turn. Slots that require a keyfile are skipped if we don't have one.
+        // Slots with invalid parameters are skipped too, so that one bad slot doesn't lock out
+        // everyone else -- their error is only returned if there weren't any others to try.
+        let mut tried_any = false;
+        let mut slot_err = None;
+        let mut found = None;
+        for (idx, slot) in self.content.slots.iter().enumerate() {
+            let keyfile = match (slot.keyfile_required, keyfile) {
+                (false, _) => None,
+                (true, Some(k)) => Some(k),
+                (true, None) => continue,
+            };
+
+            let secret = match slot.derive_secret(&key, keyfile) {
+                Ok(s) => s,
+                Err(e) => {
+                    slot_err.get_or_insert(e);
+                    continue;
+                }
+            };
+            tried_any = true;
+            if let Some(data_key) = slot.wrapped.unwrap(&secret) {
+                found = Some((idx, secret, data_key));
+                break;
+            }
+        }
+
+        let (idx, secret, data_key) = match (found, slot_err) {
+            (Some(f), _) => f,
+            (None, Some(e)) if !tried_any => return Err(e),
+            (None, None) if !tried_any && !self.content.slots.is_empty() => {
+                return Err(DecryptError::KeyfileRequired)
+            }
+            (None, _) => return Err(DecryptError::BadCrypt),
+        };
+
+        self.unlock_with(data_key)?;
+        self.content.unlocked_slot = Some(idx);
+        self.content.unlocked_secret = Some(secret);
+        Ok(())
+    }
+
+    fn derived_key_ids(&self) -> Vec<&str> {
+        self.content.slots.iter().map(|s| s.salt.as_str()).collect()
+    }
+
+    fn derived_key(&self) -> Option<(&str, &[u8])> {
+        let idx = self.content.unlocked_slot?;
+        let secret = self.content.unlocked_secret.as_deref()?;
+        Some((&self.content.slots[idx].salt, secret))
+    }
+
+    fn set_derived_key(&mut self, id: &str, key: &[u8]) -> Result<(), DecryptError> {
+        let (idx, slot) = (self.content.slots.iter().enumerate())
+            .find(|(_, s)| s.salt == id)
+            .ok_or(DecryptError::BadCrypt)?;
+        let data_key = slot.wrapped.unwrap(key).ok_or(DecryptError::BadCrypt)?;
+
+        self.unlock_with(data_key)?;
+        self.content.unlocked_slot = Some(idx);
+        self.content.unlocked_secret = Some(Zeroizing::new(key.to_vec()));
+        Ok(())
+    }
+
+    fn lock(&mut self) {
+        let key = match self.key.take() {
+            Some(k) => k,
+            None => return,
+        };
+
+        // The stored MAC is for the content as it was last saved, under the data key at the time.
+        // Both may have changed since (the data key by rotating it), so the MAC has to be brought
+        // up to date while we still have the key -- otherwise unlocking again would fail the check.
+        self.content.mac = Base64Vec(self.content.compute_mac(&key));
+        self.content.saved_bytes = Some(self.content.authenticated(Some(&key)).to_bytes());
+
+        // The metadata was only decrypted with the key, so it has to be hidden again without it
+        if self.content.private_metadata {
+            for entry in self.content.inner.iter_mut() {
+                entry.meta = Some(entry.seal_metadata(&key));
+                entry.name = String::new();
+                entry.tags = Vec::new();
+                entry.first_added = unknown_time();
+                entry.last_update = unknown_time();
+            }
+        }
+
+        self.content.unlocked_slot = None;
+        self.content.unlocked_secret = None;
+    }
+
+    fn set_identity(&mut self, identity: &Identity) -> Result<(), DecryptError> {
+        let public = identity.public_key();
+        let data_key = (self.content.recipients.iter())
+            .find(|r| r.public_key().as_ref() == public.as_ref())
+            .ok_or(DecryptError::NotARecipient)?
+            .wrapped
+            .unwrap(&identity.secret)
+            .ok_or(DecryptError::BadCrypt)?;
+
+        self.unlock_with(data_key)?;
+        self.content.unlocked_slot = None;
+        self.content.unlocked_secret = None;
+        Ok(())
+    }
+
+    fn recipients(&self) -> Option<&[Recipient]> {
+        Some(&self.content.recipients)
+    }
+
+    fn change_password(&mut self, new_pwd: &str) -> Result<(), ChangePasswordError> {
+        let kdf = match (&self.key, self.content.unlocked_slot) {
+            (Some(_), Some(i)) => self.content.slots[i].kdf,
+            (Some(_), None) => return Err(ChangePasswordError::NoPassword),
+            (None, _) => return Err(ChangePasswordError::ContentsNotUnlocked),
+        };
+
+        self.rekey(new_pwd, kdf)
+    }
+
+    fn key_slots(&self) -> Option<&[KeySlot]> {
+        Some(&self.content.slots)
+    }
+
+    fn unsaved(&self) -> bool {
+        self.unsaved
+    }
+
+    fn mark_saved(&mut self) {
+        self.unsaved = false;
+
+        // Keep the stored MAC in sync with what was written, so that a later call to `set_key`
+        // checks against the right thing
+        if let Some(k) = self.key.as_ref() {
+            self.content.mac = Base64Vec(self.content.compute_mac(k));
+            self.content.saved_bytes = Some(self.content.authenticated(Some(k)).to_bytes());
+        }
+    }
+
+    fn decrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    fn metadata_locked(&self) -> bool {
+        self.content.private_metadata && self.key.is_none()
+    }
+
+    fn num_entries(&self) -> usize {
+        self.content.inner.len()
+    }
+
+    fn entry(&self, idx: usize) -> Box<dyn super::EntryRef + '_> {
+        Box::new(EntryRef {
+            entry: &self.content.inner[idx],
+            crypt: CryptStateRef {
+                key: self.key.as_deref(),
+            },
+        })
+    }
+
+    fn entry_mut(&mut self, idx: usize) -> Box<dyn super::EntryMut + '_> {
+        Box::new(EntryMut {
+            entry: &mut self.content.inner[idx],
+            crypt: CryptStateRef {
+                key: self.key.as_deref(),
+            },
+            unsaved: &mut self.unsaved,
+            global_update: &mut self.content.last_update,
+        })
+    }
+
+    fn add_empty_entry(&mut self, name: String) -> usize {
+        let idx = self.num_entries();
+        let now = SystemTime::now();
+        self.content.inner.push(Entry {
+            name,
+            tags: Vec::new(),
+            fields: Vec::new(),
+            first_added: now,
+            last_update: now,
+            meta: None,
+        });
+
+        self.content.last_update = now;
+        self.unsaved = true;
+        idx
+    }
+
+    fn remove_entry(&mut self, idx: usize) {
+        self.content.inner.remove(idx);
+        self.content.last_update = SystemTime::now();
+        self.unsaved = true;
+    }
+}
+
+#[derive(Copy, Clone)]
+struct CryptStateRef<'a> {
+    key: Option<&'a [u8]>,
+}
+
+struct EntryRef<'a> {
+    entry: &'a Entry,
+    crypt: CryptStateRef<'a>,
+}
+
+struct EntryMut<'a> {
+    entry: &'a mut Entry,
+    crypt: CryptStateRef<'a>,
+    unsaved: &'a mut bool,
+    global_update: &'a mut SystemTime,
+}
+
+macro_rules! impl_entry_ref {
+    ($ty:ident) => {
+        impl<'a> super::EntryRef for $ty<'a> {
+            fn name(&self) -> &str {
+                match self.entry.meta {
+                    Some(_) => LOCKED_NAME,
+                    None => &self.entry.name,
+                }
+            }
+
+            fn tags(&self) -> Vec<&str> {
+                self.entry.tags.iter().map(|s| s.as_str()).collect()
+            }
+
+            fn first_added(&self) -> SystemTime {
+                self.entry.first_added
+            }
+
+            fn last_update(&self) -> SystemTime {
+                self.entry.last_update
+            }
+
+            fn field(&self, idx: usize) -> Box<dyn super::FieldRef + '_> {
+                Box::new(FieldRef {
+                    field: &self.entry.fields[idx],
+                    crypt: self.crypt,
+                })
+            }
+
+            fn num_fields(&self) -> usize {
+                self.entry.fields.len()
+            }
+        }
+    };
+}
+
+impl_entry_ref!(EntryRef);
+impl_entry_ref!(EntryMut);
+
+impl<'a> EntryMut<'a> {
+    /// Internal method to mark the entry as updated
+    fn updated(&mut self) {
+        let now = SystemTime::now();
+        self.entry.last_update = now;
+        *self.global_update = now;
+        *self.unsaved = true;
+    }
+}
+
+impl<'a> super::EntryMut for EntryMut<'a> {
+    fn set_name(&mut self, name: String) {
+        self.entry.name = name;
+        self.updated();
+    }
+
+    fn set_tags(&mut self, tags: Vec<String>) {
+        self.entry.tags = tags;
+        self.updated();
+    }
+
+    fn field_mut(&mut self, idx: usize) -> Box<dyn super::FieldMut + '_> {
+        Box::new(FieldMut {
+            field: &mut self.entry.fields[idx],
+            crypt: self.crypt,
+            unsaved: self.unsaved,
+            entry_update: &mut self.entry.last_update,
+            global_update: self.global_update,
+        })
+    }
+
+    fn field_builder(&self) -> Box<dyn super::FieldBuilder> {
+        Box::new(FieldBuilder {
+            name: None,
+            value: None,
+        })
+    }
+
+    fn set_field(
+        &mut self,
+        idx: usize,
+        mut builder: Box<dyn super::FieldBuilder>,
+    ) -> Result<(), SetFieldError> {
+        let b = builder
+            .as_any_mut()
+            .downcast_mut::<FieldBuilder>()
+            .expect("wrong type given back to `set_field`");
+
+        let name = take(&mut b.name).expect("no name set in builder");
+        #[rustfmt::skip]
+        let value = match take(&mut b.value).expect("no value set in builder") {
+            PlaintextValue::Manual { value, protected: false } => {
+                Value::Basic(value.as_str().to_owned())
+            }
+            PlaintextValue::Manual { value, protected: true } => {
+                let k = self.crypt.key
+                    .ok_or(SetFieldError::ContentsNotUnlocked(ValueKind::Protected))?;
+
+                Value::Protected(Base64Vec(encrypt(value.as_bytes(), k)))
+            }
+            PlaintextValue::Totp { issuer, secret } => {
+                let k = self.crypt.key
+                    .ok_or(SetFieldError::ContentsNotUnlocked(ValueKind::Totp))?;
+
+                Value::Totp {
+                    issuer,
+                    secret: Base64Vec(encrypt(secret.as_bytes(), k)),
+                }
+            }
+        };
+
+        let field = Field { name, value };
+
+        if idx == self.entry.fields.len() {
+            self.entry.fields.push(field);
+        } else {
+            self.entry.fields[idx] = field;
+        }
+
+        self.updated();
+        Ok(())
+    }
+
+    fn remove_field(&mut self, idx: usize) {
+        self.entry.fields.remove(idx);
+        self.updated();
+    }
+}
+
+struct FieldRef<'a> {
+    field: &'a Field,
+    crypt: CryptStateRef<'a>,
+}
+
+struct FieldMut<'a> {
+    field: &'a mut Field,
+    crypt: CryptStateRef<'a>,
+    unsaved: &'a mut bool,
+    entry_update: &'a mut SystemTime,
+    global_update: &'a mut SystemTime,
+}
+
+#[rustfmt::skip]
+macro_rules! impl_field_ref {
+    ($ty:ident) => {
+        impl<'a> super::FieldRef for $ty<'a> {
+            fn name(&self) -> &str {
+                &self.field.name
+            }
+
+            fn value_kind(&self) -> ValueKind {
+                match &self.field.value {
+                    Value::Basic(_) => ValueKind::Basic,
+                    Value::Protected(_) => ValueKind::Protected,
+                    Value::Totp { .. } => ValueKind::Totp,
+                }
+            }
+
+            fn value(&self) -> Result<SecretString, GetValueError> {
+                match (&self.field.value, self.crypt.key) {
+                    (Value::Basic(s), _) => Ok(s.clone().into()),
+                    (Value::Protected(bs), Some(k)) => Ok(decrypt_string(bs.as_ref(), k)?),
+                    (Value::Totp { secret, .. }, Some(k)) => {
+                        let secret_plaintext = decrypt_string(secret.as_ref(), k)?;
+                        let unix_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        // TOTP works with 30-second time slices
+                        let time_slice = unix_time / 30;
+                        let code = GA_AUTH.get_code(&secret_plaintext, time_slice)
+                            .map_err(|_| GetValueError::BadTotpSecret)?;
+                        Ok(code.into())
+                    }
+                    (_, None) => Err(GetValueError::ContentsNotUnlocked),
+                }
+            }
+
+            fn plaintext_value(&self) -> Result<PlaintextValue, GetValueError> {
+                match (&self.field.value, self.crypt.key) {
+                    (Value::Basic(s), _) => {
+                        Ok(PlaintextValue::Manual { value: s.clone().into(), protected: false })
+                    }
+                    (Value::Protected(bs), Some(k)) => {
+                        let value = decrypt_string(bs.as_ref(), k)?;
+                        Ok(PlaintextValue::Manual { value, protected: true })
+                    }
+                    (Value::Totp { secret, issuer }, Some(k)) => {
+                        let secret = decrypt_string(secret.as_ref(), k)?;
+                        Ok(PlaintextValue::Totp { secret, issuer: issuer.clone() })
+                    }
+                    (_, None) => Err(GetValueError::ContentsNotUnlocked),
+                }
+            }
+        }
+    };
+}
+
+impl_field_ref!(FieldRef);
+impl_field_ref!(FieldMut);
+
+impl<'a> FieldMut<'a> {
+    /// Internal method to mark the entry as updated
+    fn updated(&mut self) {
+        let now = SystemTime::now();
+        *self.entry_update = now;
+        *self.global_update = now;
+        *self.unsaved = true;
+    }
+}
+
+impl<'a> super::FieldMut for FieldMut<'a> {
+    fn swap_encryption(&mut self) -> Result<(), SwapEncryptionError> {
+        let key = self
+            .crypt
+            .key
+            .ok_or(SwapEncryptionError::ContentsNotUnlocked)?;
+
+        let new_val = match &self.field.value {
+            Value::Basic(s) => Value::Protected(Base64Vec(encrypt(s.as_bytes(), key))),
+            Value::Protected(bs) => {
+                let value = decrypt_string(bs.as_ref(), key)?;
+                Value::Basic(value.as_str().to_owned())
+            }
+            Value::Totp { .. } => return Err(SwapEncryptionError::IsTotp),
+        };
+
+        self.field.value = new_val;
+        self.updated();
+        Ok(())
+    }
+}
+
+struct FieldBuilder {
+    name: Option<String>,
+    value: Option<PlaintextValue>,
+}
+
+impl super::FieldBuilder for FieldBuilder {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn make_manual(&mut self) {}
+
+    fn make_totp(&mut self) -> Result<(), UnsupportedFeature> {
+        Ok(())
+    }
+
+    fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    fn set_value(&mut self, value: PlaintextValue) {
+        self.value = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::LockedKey;
+
+    // 32 totally random bytes, used as the data key
+    const KEY: &[u8; 32] = b"not actually a random key, sorry";
+
+    // Small parameters for the key slots, so that the tests run quickly
+    const TEST_KDF: KdfParams = KdfParams {
+        algorithm: KdfAlgorithm::Argon2id,
+        t_cost: 1,
+        m_cost: 64,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn round_trip() {
+        let cases: &[&[u8]] = &[
+            b"",
+            b"short",
+            b"exactly thirty-one bytes long!!",
+            b"a much longer value, which shouldn't need any minimum padding at all",
+        ];
+
+        for &val in cases {
+            let encrypted = encrypt(val, KEY);
+            assert!(encrypted.len() >= NONCE_LENGTH + PADDED_MIN_LENGTH);
+            assert_eq!(
+                decrypt(&encrypted, KEY).as_deref().map(Vec::as_slice),
+                Some(val)
+            );
+        }
+    }
+
+    #[test]
+    fn nonces_are_unique() {
+        let fst = encrypt(b"foobarbaz", KEY);
+        let snd = encrypt(b"foobarbaz", KEY);
+        assert_ne!(fst[..NONCE_LENGTH], snd[..NONCE_LENGTH]);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let encrypted = encrypt(b"foobarbaz", KEY);
+
+        for i in 0..encrypted.len() {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(decrypt(&tampered, KEY), None, "flipped bit in byte {}", i);
+        }
+
+        let mut wrong_key = *KEY;
+        wrong_key[0] ^= 0x01;
+        assert_eq!(decrypt(&encrypted, &wrong_key), None);
+        assert_eq!(decrypt(&encrypted[..NONCE_LENGTH - 1], KEY), None);
+    }
+
+    fn sample_content() -> Keyed<FileContent> {
+        let now = SystemTime::now();
+        let mut content = Keyed::new(FileContent {
+            version: VERSION_STR.to_owned(),
+            slots: vec![KeySlot::new(String::new(), "password", None, TEST_KDF, KEY).unwrap()],
+            recipients: Vec::new(),
+            private_metadata: false,
+            last_update: now,
+            inner: vec![Entry {
+                name: "foo".to_owned(),
+                tags: vec!["bar".to_owned()],
+                fields: vec![Field {
+                    name: "username".to_owned(),
+                    value: Value::Basic("baz".to_owned()),
+                }],
+                first_added: now,
+                last_update: now,
+                meta: None,
+            }],
+            mac: Base64Vec(Vec::new()),
+            saved_bytes: None,
+            keyfile: None,
+            unlocked_slot: Some(0),
+            unlocked_secret: None,
+        });
+        content.key = Some(LockedKey::new(KEY));
+        content
+    }
+
+    #[test]
+    fn mac_round_trip() {
+        use super::super::FileContent;
+
+        let written = sample_content().write();
+        let parsed = parse(written.clone()).unwrap();
+        assert!(parsed.content.verify_mac(KEY));
+
+        // Changing any of the unencrypted metadata should cause the check to fail
+        let tampered = [
+            written.replace("name: foo", "name: qux"),
+            written.replace("- bar", "- qux"),
+            written.replace("basic: baz", "basic: qux"),
+        ];
+        for t in tampered {
+            assert_ne!(t, written);
+            assert!(!parse(t).unwrap().content.verify_mac(KEY));
+        }
+    }
+
+    #[test]
+    fn private_metadata() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content.content.private_metadata = true;
+
+        // Metadata is encrypted deterministically, so writing twice should give the same output
+        let written = content.write();
+        assert_eq!(written, content.write());
+        assert!(!written.contains("foo") && !written.contains("bar"));
+        // ... but field names and basic values are still public
+        assert!(written.contains("username") && written.contains("baz"));
+
+        let mut parsed = parse(written).unwrap();
+        assert!(parsed.metadata_locked());
+        assert_eq!(parsed.entry(0).name(), LOCKED_NAME);
+        assert!(parsed.entry(0).tags().is_empty());
+
+        assert!(parsed.content.verify_mac(KEY));
+        parsed.content.unseal_metadata(KEY).unwrap();
+        assert_eq!(parsed.entry(0).name(), "foo");
+        assert_eq!(parsed.entry(0).tags(), ["bar"]);
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content.content.private_metadata = true;
+        content.entry_mut(0).field_mut(0).swap_encryption().unwrap();
+        content.mark_saved();
+
+        // Changes made before locking are kept, and are still there once unlocked again
+        content.entry_mut(0).set_name("qux".to_owned());
+        content.lock();
+        assert!(!content.decrypted());
+        assert!(content.metadata_locked());
+        assert_eq!(content.entry(0).name(), LOCKED_NAME);
+        assert!(content.entry(0).field(0).value().is_err());
+
+        content.set_key("password".to_owned()).unwrap();
+        assert!(content.unsaved());
+        assert_eq!(content.entry(0).name(), "qux");
+        assert_eq!(content.entry(0).tags(), ["bar"]);
+        assert_eq!(content.entry(0).field(0).value().unwrap().as_str(), "baz");
+    }
+
+    #[test]
+    fn rotate_then_lock() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content.content.private_metadata = true;
+        content.entry_mut(0).field_mut(0).swap_encryption().unwrap();
+        content.mark_saved();
+        let secret = content.content.slots[0]
+            .derive_secret("password", None)
+            .unwrap();
+        let id = content.derived_key_ids()[0].to_owned();
+
+        // Rotating changes the data key without saving, which the MAC has to keep up with
+        content.rotate_data_key().unwrap();
+        content.lock();
+        content.set_derived_key(&id, &secret).unwrap();
+        assert_eq!(content.entry(0).name(), "foo");
+        assert_eq!(content.entry(0).field(0).value().unwrap().as_str(), "baz");
+
+        content.rotate_data_key().unwrap();
+        content.lock();
+        content.set_key("password".to_owned()).unwrap();
+        assert!(content.unsaved());
+        assert_eq!(content.entry(0).field(0).value().unwrap().as_str(), "baz");
+
+        // And the file written afterwards is still valid
+        let mut parsed = parse(content.write()).unwrap();
+        parsed.set_key("password".to_owned()).unwrap();
+        assert_eq!(parsed.entry(0).name(), "foo");
+    }
+
+    #[test]
+    fn rekey() {
+        use super::super::FileContent;
+
+        let kdf = KdfParams {
+            t_cost: 2,
+            ..TEST_KDF
+        };
+
+        let mut content = sample_content();
+        content.entry_mut(0).field_mut(0).swap_encryption().unwrap();
+        let first_added = content.content.inner[0].first_added;
+
+        content.rekey("new password", kdf).unwrap();
+        assert_ne!(content.key.as_deref(), Some(&KEY[..]));
+        assert_eq!(content.entry(0).field(0).value().unwrap().as_str(), "baz");
+        assert_eq!(content.content.inner[0].first_added, first_added);
+
+        let mut parsed = parse(content.write()).unwrap();
+        assert_eq!(parsed.content.slots[0].kdf, kdf);
+        assert!(parsed.set_key("password".to_owned()).is_err());
+        parsed.set_key("new password".to_owned()).unwrap();
+        assert_eq!(parsed.entry(0).field(0).value().unwrap().as_str(), "baz");
+    }
+
+    #[test]
+    fn rekey_requires_keyfile() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content.content.slots[0] =
+            KeySlot::new(String::new(), "password", Some(b"keyfile"), TEST_KDF, KEY).unwrap();
+        content.mark_saved();
+
+        // Unlocking with a derived key (as the agent does) doesn't give us the keyfile
+        let secret = (content.content.slots[0])
+            .derive_secret("password", Some(b"keyfile"))
+            .unwrap();
+        let id = content.derived_key_ids()[0].to_owned();
+        let mut parsed = parse(content.write()).unwrap();
+        parsed.set_derived_key(&id, &secret).unwrap();
+
+        assert!(matches!(
+            parsed.rekey("new password", TEST_KDF),
+            Err(ChangePasswordError::Decrypt(DecryptError::KeyfileRequired))
+        ));
+        assert!(parsed.content.slots[0].keyfile_required);
+
+        parsed.set_keyfile(b"keyfile".to_vec()).unwrap();
+        parsed.rekey("new password", TEST_KDF).unwrap();
+        assert!(parsed.content.slots[0].keyfile_required);
+    }
+
+    #[test]
+    fn kdf_params_bounded() {
+        use super::super::FileContent;
+
+        let written = sample_content().write();
+        assert!(written.contains("m_cost: 64\n"));
+        parse(written.clone()).unwrap();
+
+        for (from, to) in [
+            ("t_cost: 1\n", "t_cost: 4294967295\n"),
+            ("m_cost: 64\n", "m_cost: 4294967295\n"),
+            ("p_cost: 1\n", "p_cost: 17\n"),
+        ] {
+            let err = parse(written.replacen(from, to, 1)).err().unwrap();
+            assert!(err
+                .to_string()
+                .contains(&DecryptError::BadKdfParams.to_string()));
+        }
+
+        let too_large = KdfParams {
+            m_cost: KdfParams::MAX_M_COST + 1,
+            ..TEST_KDF
+        };
+        let salt = Salt::new("randomsaltstring").unwrap();
+        assert!(matches!(
+            too_large.hash_key(salt, "password", None),
+            Err(DecryptError::BadKdfParams)
+        ));
+    }
+
+    #[test]
+    fn keyfile_changes_key() {
+        let salt = Salt::new("randomsaltstring").unwrap();
+
+        let hash = |keyfile: Option<&[u8]>| TEST_KDF.hash_key(salt, "password", keyfile).unwrap();
+        let without = hash(None);
+        let with = hash(Some(b"keyfile contents"));
+
+        assert_eq!(with.len(), without.len());
+        assert_ne!(with, without);
+        assert_ne!(with, hash(Some(b"other keyfile contents")));
+        assert_eq!(with, hash(Some(b"keyfile contents")));
+    }
+
+    #[test]
+    fn key_slots() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content.entry_mut(0).field_mut(0).swap_encryption().unwrap();
+        assert_eq!(
+            content
+                .add_slot("bob".to_owned(), "hunter2", None, TEST_KDF)
+                .unwrap(),
+            1
+        );
+
+        // Either slot can unlock the file
+        for pwd in ["password", "hunter2"] {
+            let mut parsed = parse(content.write()).unwrap();
+            parsed.set_key(pwd.to_owned()).unwrap();
+            assert_eq!(parsed.entry(0).field(0).value().unwrap().as_str(), "baz");
+        }
+
+        // Removing the first slot should rotate the data key, and leave only the second
+        content.remove_slot(0).unwrap();
+        assert_ne!(content.key.as_deref(), Some(&KEY[..]));
+        assert_eq!(content.content.slots.len(), 1);
+        assert_eq!(content.content.unlocked_slot, None);
+
+        let mut parsed = parse(content.write()).unwrap();
+        assert!(parsed.set_key("password".to_owned()).is_err());
+        parsed.set_key("hunter2".to_owned()).unwrap();
+        assert_eq!(parsed.content.unlocked_slot, Some(0));
+        assert_eq!(parsed.entry(0).field(0).value().unwrap().as_str(), "baz");
+    }
+
+    #[test]
+    fn bad_slot_skipped() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content
+            .add_slot("bob".to_owned(), "hunter2", None, TEST_KDF)
+            .unwrap();
+        content.content.slots[0].salt = "not a valid salt!".to_owned();
+
+        // The first slot can't be used, but the second still unlocks the file
+        let mut parsed = parse(content.write()).unwrap();
+        parsed.set_key("hunter2".to_owned()).unwrap();
+        assert_eq!(parsed.content.unlocked_slot, Some(1));
+
+        let mut parsed = parse(content.write()).unwrap();
+        assert!(matches!(
+            parsed.set_key("password".to_owned()),
+            Err(DecryptError::BadCrypt)
+        ));
+
+        // With only the bad slot, its error is returned
+        content.remove_slot(1).unwrap();
+        let mut parsed = parse(content.write()).unwrap();
+        assert!(matches!(
+            parsed.set_key("password".to_owned()),
+            Err(DecryptError::BadKdfParams)
+        ));
+    }
+
+    #[test]
+    fn recipients() {
+        use super::super::FileContent;
+
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let identity_file = alice.to_file_string();
+        let alice = Identity::parse(&identity_file).unwrap();
+
+        let mut content = sample_content();
+        content.entry_mut(0).field_mut(0).swap_encryption().unwrap();
+        assert_eq!(
+            content.add_recipient(String::new(), alice.public_key()),
+            Some(0)
+        );
+        assert_eq!(
+            content.add_recipient("bob".to_owned(), bob.public_key()),
+            Some(1)
+        );
+
+        // Either identity can unlock the file, without a password
+        for identity in [&alice, &bob] {
+            let mut parsed = parse(content.write()).unwrap();
+            parsed.set_identity(identity).unwrap();
+            assert_eq!(parsed.content.unlocked_slot, None);
+            assert_eq!(parsed.entry(0).field(0).value().unwrap().as_str(), "baz");
+            assert!(matches!(
+                parsed.change_password("new"),
+                Err(ChangePasswordError::NoPassword)
+            ));
+        }
+
+        // Removing a recipient should rotate the data key, so that their identity no longer works
+        content.remove_recipient(1).unwrap();
+        assert_ne!(content.key.as_deref(), Some(&KEY[..]));
+
+        let mut parsed = parse(content.write()).unwrap();
+        assert!(matches!(
+            parsed.set_identity(&bob),
+            Err(DecryptError::NotARecipient)
+        ));
+        parsed.set_identity(&alice).unwrap();
+        assert_eq!(parsed.entry(0).field(0).value().unwrap().as_str(), "baz");
+
+        let mut parsed = parse(content.write()).unwrap();
+        parsed.set_key("password".to_owned()).unwrap();
+        assert_eq!(parsed.entry(0).field(0).value().unwrap().as_str(), "baz");
+    }
+
+    #[test]
+    fn derived_keys() {
+        use super::super::FileContent;
+
+        let mut content = sample_content();
+        content.entry_mut(0).field_mut(0).swap_encryption().unwrap();
+        content
+            .add_slot("bob".to_owned(), "hunter2", None, TEST_KDF)
+            .unwrap();
+        let written = content.write();
+
+        let mut parsed = parse(written.clone()).unwrap();
+        assert_eq!(parsed.derived_key(), None);
+        parsed.set_key("hunter2".to_owned()).unwrap();
+        let (id, key) = parsed.derived_key().unwrap();
+        assert_eq!(id, parsed.derived_key_ids()[1]);
+
+        // The derived key unlocks the file in the same way as the password
+        let mut cached = parse(written).unwrap();
+        assert!(cached.set_derived_key(id, b"not the right key").is_err());
+        cached.set_derived_key(id, key).unwrap();
+        assert_eq!(cached.content.unlocked_slot, Some(1));
+        assert_eq!(cached.entry(0).field(0).value().unwrap().as_str(), "baz");
+
+        // Changing the password gives the slot a new salt, so the old key is no longer used
+        cached.change_password("new").unwrap();
+        assert_eq!(cached.derived_key(), None);
+        let parsed = parse(cached.write()).unwrap();
+        assert!(!parsed.derived_key_ids().contains(&id));
+    }
+}